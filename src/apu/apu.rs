@@ -8,39 +8,59 @@ use crossbeam::channel::{unbounded, Sender};
 
 use crate::{apu::frame_sequencer::FrameSequencerMode, util::ClockDivider};
 
-use super::{ch_pulse::ChannelPulse, frame_sequencer::FrameSequencer};
+use super::{ch_pulse::ChannelPulse, expansion::ExpansionAudio, frame_sequencer::FrameSequencer};
+
+// Output of a pulse channel at full volume, leaving headroom for the expansion chips
+const PULSE_LEVEL: f32 = 0.15;
+
+// NTSC master clock divided by the audio clock divider
+pub const SAMPLE_RATE: u32 = 21_477_272 / 400;
 
 struct AudioBuffer {
-    buffer: Vec<u8>,
+    buffer: Vec<f32>,
     idx_write: usize,
-    sender: Sender<Vec<u8>>,
+    // None without an audio device
+    sender: Option<Sender<Vec<f32>>>,
+    // Samples are kept here instead of being played while recording
+    recording: Option<Vec<f32>>,
 }
 
 impl std::ops::Drop for AudioBuffer {
     fn drop(&mut self) {
         // Ensure the channel is closed when the AudioBuffer is dropped
-        self.sender
-            .send(vec![0])
-            .expect("Failed to flush the audio buffer");
+        if let Some(sender) = &self.sender {
+            sender
+                .send(vec![0.0])
+                .expect("Failed to flush the audio buffer");
+        }
     }
 }
 
 impl AudioBuffer {
-    pub fn new(size: usize, sender: Sender<Vec<u8>>) -> Self {
+    pub fn new(size: usize, sender: Option<Sender<Vec<f32>>>) -> Self {
         AudioBuffer {
-            buffer: vec![0; size],
+            buffer: vec![0.0; size],
             idx_write: 0,
             sender,
+            recording: None,
         }
     }
 
-    pub fn push(&mut self, value: u8) {
+    pub fn push(&mut self, value: f32) {
+        if let Some(recording) = &mut self.recording {
+            recording.push(value);
+            return;
+        }
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return,
+        };
         self.buffer[self.idx_write] = value;
         self.idx_write += 1;
         if self.idx_write == self.buffer.len() {
             self.idx_write = 0;
             // TODO: Use a circular buffer instead of copying
-            self.sender
+            sender
                 .send(self.buffer.clone())
                 .expect("Failed to send the audio buffer");
         }
@@ -50,8 +70,10 @@ impl AudioBuffer {
 #[allow(clippy::upper_case_acronyms)]
 pub struct APU {
     audio_buffer: AudioBuffer,
-    stream: Stream,
+    stream: Option<Stream>,
     audio_clock_divider: ClockDivider<400>, // TODO: fix this value
+    cpu_clock_divider: ClockDivider<12>,
+    volume: f32,
 
     // Functional units
     frame_sequencer: FrameSequencer,
@@ -105,16 +127,16 @@ impl APU {
             .with_max_sample_rate();
         let sample_format = main_config.sample_format();
         let config = main_config.into();
-        let (s, r) = unbounded::<Vec<u8>>();
+        let (s, r) = unbounded::<Vec<f32>>();
         let stream = match sample_format {
             SampleFormat::F32 => device.build_output_stream(
                 &config,
                 move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
                     let buf = r.recv().expect("Failed to receive the audio buffer");
                     for (i, frame) in data.chunks_mut(2).enumerate() {
-                        let x = buf[i % buf.len()] * 10;
+                        let x = buf[i % buf.len()];
                         for sample in frame.iter_mut() {
-                            *sample = x as f32 / 100.0;
+                            *sample = x;
                         }
                     }
                 },
@@ -124,11 +146,21 @@ impl APU {
             _ => panic!("Invalid sample format"),
         }
         .unwrap();
+        Self::with_output(Some(stream), AudioBuffer::new(480, Some(s)))
+    }
+
+    // Doesn't open an audio device, the samples are only kept while recording
+    pub fn new_headless() -> Self {
+        Self::with_output(None, AudioBuffer::new(480, None))
+    }
 
+    fn with_output(stream: Option<Stream>, audio_buffer: AudioBuffer) -> Self {
         APU {
             stream,
-            audio_buffer: AudioBuffer::new(480, s),
+            audio_buffer,
             audio_clock_divider: ClockDivider::new(),
+            cpu_clock_divider: ClockDivider::new(),
+            volume: 1.0,
             frame_sequencer: FrameSequencer::new(),
             channel_pulse1: ChannelPulse::new(),
             channel_pulse2: ChannelPulse::new(),
@@ -163,10 +195,36 @@ impl APU {
     }
 
     pub fn play(&mut self) {
-        self.stream.play().expect("Failed to start playing audio");
+        if let Some(stream) = &self.stream {
+            stream.play().expect("Failed to start playing audio");
+        }
     }
 
-    pub fn tick(&mut self) {
+    // Master volume, 1.0 being the normal level
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+    }
+
+    pub fn start_recording(&mut self) {
+        self.audio_buffer.recording = Some(vec![]);
+    }
+
+    // Returns the samples recorded at SAMPLE_RATE since the recording started
+    pub fn take_recording(&mut self) -> Vec<f32> {
+        self.audio_buffer
+            .recording
+            .replace(vec![])
+            .unwrap_or_default()
+    }
+
+    // The expansion audio of the cartridge is clocked along with the APU, once per CPU cycle
+    pub fn tick(&mut self, mut expansion: Option<&mut dyn ExpansionAudio>) {
+        if self.cpu_clock_divider.is_triggered() {
+            if let Some(audio) = &mut expansion {
+                audio.tick_audio();
+            }
+        }
+        self.cpu_clock_divider.tick();
         let triggers = self.frame_sequencer.tick();
         if triggers.frame_interrupt {
             dbg!("TODO: interrupt");
@@ -181,10 +239,20 @@ impl APU {
         self.channel_pulse2.tick_timer();
         self.audio_clock_divider.tick();
         if self.audio_clock_divider.is_triggered() {
-            self.audio_buffer.push(self.channel_pulse2.get_volume());
+            let sample = self.mix(expansion.as_deref());
+            self.audio_buffer.push(sample);
         }
     }
 
+    fn mix(&self, expansion: Option<&dyn ExpansionAudio>) -> f32 {
+        let pulse = self.channel_pulse2.get_volume() as f32 / 15.0;
+        let expansion = match expansion {
+            Some(audio) => audio.mixed_output(),
+            None => 0.0,
+        };
+        ((pulse + expansion) * PULSE_LEVEL * self.volume).clamp(-1.0, 1.0)
+    }
+
     pub fn read_pulse1_0(&self) -> u8 {
         self.channel_pulse1.read_reg_0()
     }
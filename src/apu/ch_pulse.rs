@@ -105,6 +105,10 @@ impl ChannelPulse {
         }
     }
 
+    pub fn is_length_counter_active(&self) -> bool {
+        !self.length_counter.value_is_zero()
+    }
+
     fn update_timer_period(&mut self) {
         self.timer_period = (((self.reg_3 as u16) & 0x7) | (self.reg_2 as u16)) + 1;
     }
@@ -0,0 +1,50 @@
+// Sound chips on the cartridge, clocked by the APU and mixed with its channels
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpansionChip {
+    VRC6,
+    VRC7,
+    FDS,
+    MMC5,
+    N163,
+    Sunsoft5B,
+}
+
+impl ExpansionChip {
+    // Largest output of the chip, and how loud it is compared to an APU pulse at full volume
+    fn peak(self) -> (i32, f32) {
+        match self {
+            // The channel steps are as loud as the APU pulse steps
+            ExpansionChip::VRC6 => (15 + 15 + 31, 61.0 / 15.0),
+            // PCM steps are a 16th of a pulse step, so the DAC spans about a pulse at full volume
+            ExpansionChip::MMC5 => (2 * 15 * 16 + 255, 3.0),
+            // A single channel, the others are multiplexed through the same DAC
+            ExpansionChip::N163 => (8 * 15, 3.0),
+            ExpansionChip::Sunsoft5B => (3 * 255, 4.5),
+            ExpansionChip::VRC7 => (6 * 4095, 6.0),
+            ExpansionChip::FDS => (63, 2.4),
+        }
+    }
+
+    // Output of a single unit, with 1.0 being an APU pulse at full volume
+    pub fn level(self) -> f32 {
+        let (peak, relative_level) = self.peak();
+        relative_level / peak as f32
+    }
+}
+
+pub trait ExpansionAudio {
+    fn chip(&self) -> ExpansionChip;
+
+    // Called once per CPU cycle
+    fn tick_audio(&mut self);
+
+    // In units of the chip, can be negative for chips that swing around silence
+    fn audio_output(&self) -> i32;
+
+    // Output of the chip mixed with the APU, with 1.0 being a pulse at full volume
+    fn mixed_output(&self) -> f32 {
+        self.audio_output() as f32 * self.chip().level()
+    }
+}
@@ -2,6 +2,9 @@
 mod apu;
 pub use apu::*;
 mod ch_pulse;
+pub use ch_pulse::ChannelPulse;
 mod envelope_generator;
+mod expansion;
+pub use expansion::{ExpansionAudio, ExpansionChip};
 mod frame_sequencer;
 mod length_counter;
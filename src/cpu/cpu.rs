@@ -801,6 +801,16 @@ impl CPU {
         7 // Interrupts take 7 cycles
     }
 
+    pub fn execute_irq<M: Memory>(&mut self, mem: &mut M) -> u8 {
+        self.push_addr(mem, self.pc);
+        // The B flag is only pushed as set by BRK
+        let p = self.pack_flags() & !flags::B;
+        self.push_byte(mem, p);
+        self.pc = mem.read_u16(0xfffe);
+        self.flag_interrupt_disable = true;
+        7
+    }
+
     fn update_zn_flags(&mut self, val: u8) {
         self.flag_zero = val == 0;
         self.flag_negative = (val & 0x80) != 0;
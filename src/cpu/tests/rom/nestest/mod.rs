@@ -6,7 +6,7 @@ static NESTEST_LOG: &'static str = include_str!("./nestest.log");
 #[test]
 fn test() {
     let rom = NESFile::load(&NESTEST).expect("Failed to load the nestest ROM");
-    let mut nes = NES::new(rom);
+    let mut nes = NES::new_headless(rom).expect("Failed to create the NES");
     nes.cpu.pc = 0xc000;
 
     for line in NESTEST_LOG.lines() {
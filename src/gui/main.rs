@@ -1,16 +1,202 @@
+use crate::nes::battery::BatterySave;
+use crate::nes::cheats::CheatManager;
 use crate::nes::trace::fceux::FceuxTrace;
 use crate::nes::NES;
-use crate::rom::nes::NESFile;
+use crate::rom::{archive, fds::FDSFile, nes::NESFile, nsf::NSFFile, patch, unif};
+
+use super::nsf_player;
 
 use raylib::prelude::*;
 
+const BATTERY_FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Removes an option and its value from the arguments
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    match args.iter().position(|arg| arg == name) {
+        Some(i) if i + 1 < args.len() => {
+            let value = args.remove(i + 1);
+            args.remove(i);
+            Some(value)
+        }
+        Some(_) => {
+            eprintln!("Expected a value after {}", name);
+            std::process::exit(1);
+        }
+        None => None,
+    }
+}
+
+// Sides are numbered as disk 1 side A, disk 1 side B, disk 2 side A...
+fn disk_side_name(side: usize) -> String {
+    format!(
+        "disk {} side {}",
+        side / 2 + 1,
+        (b'A' + (side & 0x01) as u8) as char
+    )
+}
+
+// Applies the given patch, or a patch with the same name as the ROM if there's one
+fn apply_patch(data: Vec<u8>, rom_path: &std::path::Path, patch_path: Option<String>) -> Vec<u8> {
+    let patch_path = match patch_path {
+        Some(patch_path) => std::path::PathBuf::from(patch_path),
+        None => match ["ips", "ups", "bps"]
+            .iter()
+            .map(|extension| rom_path.with_extension(extension))
+            .find(|path| path.exists())
+        {
+            Some(patch_path) => patch_path,
+            None => return data,
+        },
+    };
+    let patch_data = match std::fs::read(&patch_path) {
+        Ok(patch_data) => patch_data,
+        Err(err) => {
+            eprintln!("Failed to read the patch {}: {}", patch_path.display(), err);
+            std::process::exit(1);
+        }
+    };
+    match patch::apply(&data, &patch_data) {
+        Ok(data) => {
+            println!("Applied the patch {}", patch_path.display());
+            data
+        }
+        Err(err) => {
+            eprintln!(
+                "Failed to apply the patch {}: {}",
+                patch_path.display(),
+                err
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn load_cartridge(data: &[u8]) -> NES {
+    let rom = if unif::is_unif(data) {
+        unif::load(data)
+    } else {
+        NESFile::load(data)
+    };
+    let rom = match rom {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("Failed to load the ROM: {}", err);
+            std::process::exit(1);
+        }
+    };
+    for warning in &rom.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    match NES::new(rom) {
+        Ok(nes) => nes,
+        Err(err) => {
+            eprintln!("Failed to start the emulation: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn load_disk_system(data: &[u8], bios_path: &std::path::Path) -> NES {
+    let disk = match FDSFile::load(data) {
+        Ok(disk) => disk,
+        Err(err) => {
+            eprintln!("Failed to load the disk image: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let bios = match std::fs::read(bios_path) {
+        Ok(bios) if bios.len() == 0x2000 => bios,
+        Ok(_) => {
+            eprintln!("The FDS BIOS must be 8K");
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!(
+                "Failed to read the FDS BIOS {}: {}",
+                bios_path.display(),
+                err
+            );
+            std::process::exit(1);
+        }
+    };
+    NES::new_disk_system(disk, bios)
+}
+
+// Tracks are numbered from 1 on the command line
+fn play_nsf(data: &[u8], track: Option<String>, wav_path: Option<String>) {
+    let nsf = match NSFFile::load(data) {
+        Ok(nsf) => nsf,
+        Err(err) => {
+            eprintln!("Failed to load the NSF: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let track = match track.map(|track| track.parse::<usize>()) {
+        Some(Ok(track)) if (1..=nsf.tracks.len()).contains(&track) => track - 1,
+        Some(_) => {
+            eprintln!("The track must be between 1 and {}", nsf.tracks.len());
+            std::process::exit(1);
+        }
+        None => nsf.starting_track,
+    };
+    match wav_path {
+        Some(wav_path) => {
+            if let Err(err) = nsf_player::render_wav(&nsf, track, std::path::Path::new(&wav_path)) {
+                eprintln!("Failed to write {}: {}", wav_path, err);
+                std::process::exit(1);
+            }
+        }
+        None => nsf_player::play(&nsf, track),
+    }
+}
+
 pub fn gui_main() {
-    let path = std::env::args().nth(1).expect("Expected an argument");
-    let data = std::fs::read(path).expect("Failed to read the ROM file");
-    let rom = NESFile::load(&data).expect("Failed to load the ROM");
-    let mut nes = NES::new(rom);
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    // Card swiped through the Datach barcode reader when B is pressed
+    let barcode = take_option(&mut args, "--barcode");
+    if let Some(barcode) = &barcode {
+        if !matches!(barcode.len(), 8 | 13) || !barcode.bytes().all(|b| b.is_ascii_digit()) {
+            eprintln!("The barcode must have 8 or 13 digits");
+            std::process::exit(1);
+        }
+    }
+    let bios_path = take_option(&mut args, "--bios");
+    let patch_path = take_option(&mut args, "--patch");
+    // File to pick from a zip archive, the first ROM by default
+    let entry_name = take_option(&mut args, "--entry");
+    // NSF player options, the track to start with and a file to render it to instead of playing
+    let track = take_option(&mut args, "--track");
+    let wav_path = take_option(&mut args, "--wav");
+    // Cheats to add to the ones saved for the game, a code or an FCEUX .cht file
+    let cheat_code = take_option(&mut args, "--cheat");
+    let cheats_path = take_option(&mut args, "--cheats");
+    let path = args.first().cloned().expect("Expected an argument");
+    let data = std::fs::read(&path).expect("Failed to read the ROM file");
+    let data = match archive::extract(data, entry_name.as_deref()) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Failed to extract the ROM: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let data = apply_patch(data, std::path::Path::new(&path), patch_path);
+    if NSFFile::is_nsf(&data) {
+        play_nsf(&data, track, wav_path);
+        return;
+    }
+    let is_disk = FDSFile::is_disk_image(&data);
+    let mut nes = if is_disk {
+        // The BIOS is looked up next to the disk image by default
+        let bios_path = match bios_path {
+            Some(bios_path) => std::path::PathBuf::from(bios_path),
+            None => std::path::Path::new(&path).with_file_name("disksys.rom"),
+        };
+        load_disk_system(&data, &bios_path)
+    } else {
+        load_cartridge(&data)
+    };
 
-    if let Some(trace_path) = std::env::args().nth(2) {
+    if let Some(trace_path) = args.get(1) {
         let data = std::fs::read(trace_path).expect("Failed to read the trace file");
         let text = String::from_utf8_lossy(&data).into_owned();
         let trace = FceuxTrace::new(&text);
@@ -19,6 +205,41 @@ pub fn gui_main() {
         return;
     }
 
+    let mut battery_save = if is_disk {
+        BatterySave::new_diff(std::path::Path::new(&path), &nes)
+    } else {
+        BatterySave::new(std::path::Path::new(&path))
+    };
+    if let Err(err) = battery_save.load(&mut nes) {
+        eprintln!("Failed to load the save file: {}", err);
+    }
+
+    let mut cheats = CheatManager::new(std::path::Path::new(&path));
+    if let Err(err) = cheats.load() {
+        eprintln!("Failed to load the cheats: {}", err);
+    }
+    if let Some(cheats_path) = cheats_path {
+        match cheats.import(std::path::Path::new(&cheats_path)) {
+            Ok(count) => println!("Imported {} cheats", count),
+            Err(err) => eprintln!("Failed to import the cheats: {}", err),
+        }
+    }
+    if let Some(cheat_code) = cheat_code {
+        if let Err(err) = cheats.add(&cheat_code, &cheat_code) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+    if let Err(err) = cheats.save() {
+        eprintln!("Failed to save the cheats: {}", err);
+    }
+    cheats.apply(&mut nes);
+
+    let mut last_flush = std::time::Instant::now();
+    // E ejects and inserts the disk, S picks the side to insert
+    let mut disk_side = 0;
+    let mut is_disk_inserted = true;
+
     let (mut rl, thread) = raylib::init()
         .size(256 * 4, 240 * 4)
         .title("NESNESS v0.1")
@@ -40,6 +261,41 @@ pub fn gui_main() {
             }
         }
 
+        if let Some(barcode) = &barcode {
+            if d.is_key_pressed(KeyboardKey::KEY_B) && !nes.scan_barcode(barcode) {
+                eprintln!("The cartridge has no barcode reader");
+            }
+        }
+
+        if nes.disk_side_count() > 0 {
+            if d.is_key_pressed(KeyboardKey::KEY_E) {
+                is_disk_inserted = !is_disk_inserted;
+                nes.insert_disk(Some(disk_side).filter(|_| is_disk_inserted));
+                if is_disk_inserted {
+                    println!("Inserted {}", disk_side_name(disk_side));
+                } else {
+                    println!("Ejected the disk");
+                }
+            }
+            if d.is_key_pressed(KeyboardKey::KEY_S) && !is_disk_inserted {
+                disk_side = (disk_side + 1) % nes.disk_side_count();
+                println!("Selected {}", disk_side_name(disk_side));
+            }
+        }
+
+        // C turns all the cheats off, or back on
+        if d.is_key_pressed(KeyboardKey::KEY_C) && !cheats.cheats().is_empty() {
+            let is_enabled = !cheats.cheats().iter().any(|cheat| cheat.is_enabled);
+            for i in 0..cheats.cheats().len() {
+                cheats.set_enabled(i, is_enabled);
+            }
+            cheats.apply(&mut nes);
+            if let Err(err) = cheats.save() {
+                eprintln!("Failed to save the cheats: {}", err);
+            }
+            println!("Cheats {}", if is_enabled { "enabled" } else { "disabled" });
+        }
+
         // TODO: Make this number internal to the NES type
         // TODO: Account for skipped dots
         for _ in 0..89342 * 4 {
@@ -67,5 +323,17 @@ pub fn gui_main() {
         let fps = 1.0 / elapsed.as_secs_f32();
         last = now;
         rl.set_window_title(&thread, &format!("NESNESS v0.1 - FPS: {:.2}", fps));
+
+        // Don't lose the progress if the emulator crashes later
+        if now.duration_since(last_flush) >= BATTERY_FLUSH_INTERVAL {
+            last_flush = now;
+            if let Err(err) = battery_save.flush(&nes) {
+                eprintln!("Failed to write the save file: {}", err);
+            }
+        }
+    }
+
+    if let Err(err) = battery_save.flush(&nes) {
+        eprintln!("Failed to write the save file: {}", err);
     }
 }
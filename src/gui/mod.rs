@@ -1 +1,2 @@
 pub mod main;
+mod nsf_player;
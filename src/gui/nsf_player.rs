@@ -0,0 +1,165 @@
+use crate::apu::SAMPLE_RATE;
+use crate::nes::NES;
+use crate::rom::nsf::NSFFile;
+use crate::util::write_wav;
+
+use raylib::prelude::*;
+
+const MASTER_CLOCK_RATE: u64 = 21_477_272;
+const FRAME_TICKS: u64 = 89342 * 4;
+// Tracks without a length in the file are rendered for this long, then faded out
+const DEFAULT_LENGTH: u32 = 150_000;
+const DEFAULT_FADE: u32 = 5_000;
+// Lengths in NSFe files are not trusted when rendering, the output stops after this long
+const MAX_RENDER_LENGTH: u32 = 10 * 60_000;
+
+// Length and fade-out of a track in milliseconds, the length is None when the track loops forever
+fn track_timing(nsf: &NSFFile, track: usize) -> (Option<u32>, u32) {
+    let info = &nsf.tracks[track];
+    match info.length {
+        Some(length) => (Some(length), info.fade.unwrap_or(0)),
+        None => (None, info.fade.unwrap_or(DEFAULT_FADE)),
+    }
+}
+
+fn fade_volume(elapsed: u32, length: u32, fade: u32) -> f32 {
+    if elapsed <= length {
+        1.0
+    } else if fade == 0 {
+        0.0
+    } else {
+        1.0 - (elapsed - length).min(fade) as f32 / fade as f32
+    }
+}
+
+// Length and fade-out used for rendering, together they never exceed MAX_RENDER_LENGTH
+fn render_timing(length: Option<u32>, fade: u32) -> (u32, u32) {
+    let length = length.unwrap_or(DEFAULT_LENGTH).min(MAX_RENDER_LENGTH);
+    (length, fade.min(MAX_RENDER_LENGTH - length))
+}
+
+fn track_name(nsf: &NSFFile, track: usize) -> String {
+    match &nsf.tracks[track].title {
+        Some(title) => format!("Track {}/{}: {}", track + 1, nsf.tracks.len(), title),
+        None => format!("Track {}/{}", track + 1, nsf.tracks.len()),
+    }
+}
+
+fn format_time(ms: u32) -> String {
+    format!("{}:{:02}", ms / 60_000, ms / 1000 % 60)
+}
+
+// Renders a single track without opening a window
+pub fn render_wav(nsf: &NSFFile, track: usize, path: &std::path::Path) -> std::io::Result<()> {
+    let (length, fade) = track_timing(nsf, track);
+    let (length, fade) = render_timing(length, fade);
+    let mut nes = NES::new_nsf_headless(nsf);
+    nes.select_track(track);
+    nes.mmap.apu.start_recording();
+    for _ in 0..(length + fade) as u64 * MASTER_CLOCK_RATE / 1000 {
+        nes.tick();
+    }
+    let mut samples = nes.mmap.apu.take_recording();
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let elapsed = (i as u64 * 1000 / SAMPLE_RATE as u64) as u32;
+        *sample *= fade_volume(elapsed, length, fade);
+    }
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    write_wav(file, &samples, SAMPLE_RATE)
+}
+
+// Left and right pick the track, tracks with a known length move on to the next one by themselves
+pub fn play(nsf: &NSFFile, mut track: usize) {
+    let mut nes = NES::new_nsf(nsf);
+    nes.select_track(track);
+    let mut elapsed_ticks = 0;
+
+    let (mut rl, thread) = raylib::init()
+        .size(256 * 4, 240 * 2)
+        .title("NESNESS v0.1")
+        .build();
+
+    rl.set_target_fps(60);
+
+    while !rl.window_should_close() {
+        let mut d = rl.begin_drawing(&thread);
+
+        d.clear_background(Color::BLACK);
+
+        let (length, fade) = track_timing(nsf, track);
+        let elapsed = (elapsed_ticks * 1000 / MASTER_CLOCK_RATE) as u32;
+        let is_finished = length.is_some_and(|length| elapsed >= length.saturating_add(fade));
+        let next_track = if d.is_key_pressed(KeyboardKey::KEY_LEFT) {
+            Some((track + nsf.tracks.len() - 1) % nsf.tracks.len())
+        } else if d.is_key_pressed(KeyboardKey::KEY_RIGHT) || is_finished {
+            Some((track + 1) % nsf.tracks.len())
+        } else {
+            None
+        };
+        if let Some(next_track) = next_track {
+            track = next_track;
+            elapsed_ticks = 0;
+            nes.select_track(track);
+            continue;
+        }
+        if let Some(length) = length {
+            nes.mmap.apu.set_volume(fade_volume(elapsed, length, fade));
+        } else {
+            nes.mmap.apu.set_volume(1.0);
+        }
+
+        for _ in 0..FRAME_TICKS {
+            nes.tick();
+        }
+        elapsed_ticks += FRAME_TICKS;
+
+        let time = match length {
+            Some(length) => format!("{} / {}", format_time(elapsed), format_time(length)),
+            None => format_time(elapsed),
+        };
+        let lines = [
+            nsf.title.clone(),
+            nsf.artist.clone(),
+            nsf.copyright.clone(),
+            String::new(),
+            track_name(nsf, track),
+            time,
+        ];
+        for (i, line) in lines.iter().enumerate() {
+            d.draw_text(
+                line,
+                32,
+                32 + i as i32 * 48,
+                32,
+                Color::new(255, 255, 255, 255),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fade_volume() {
+        assert_eq!(1.0, fade_volume(1000, 1000, 2000));
+        assert_eq!(0.5, fade_volume(2000, 1000, 2000));
+        assert_eq!(0.0, fade_volume(4000, 1000, 2000));
+        assert_eq!(0.0, fade_volume(1001, 1000, 0));
+    }
+
+    #[test]
+    fn test_render_timing() {
+        assert_eq!((DEFAULT_LENGTH, 2000), render_timing(None, 2000));
+        assert_eq!((60_000, 0), render_timing(Some(60_000), 0));
+        assert_eq!(
+            (MAX_RENDER_LENGTH - 1000, 1000),
+            render_timing(Some(MAX_RENDER_LENGTH - 1000), 5000)
+        );
+        assert_eq!(
+            (MAX_RENDER_LENGTH, 0),
+            render_timing(Some(u32::MAX), u32::MAX)
+        );
+    }
+}
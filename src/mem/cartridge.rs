@@ -0,0 +1,95 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{apu::ExpansionAudio, rom::nes::Mirroring};
+
+use super::mappers::Mapper;
+
+// A single cartridge shared between the CPU and the PPU memory maps,
+// so that the mapper state written by the CPU is visible to the PPU
+#[derive(Clone)]
+pub struct Cartridge {
+    mapper: Rc<RefCell<Box<dyn Mapper>>>,
+}
+
+impl Cartridge {
+    pub fn new(mapper: Box<dyn Mapper>) -> Self {
+        Cartridge {
+            mapper: Rc::new(RefCell::new(mapper)),
+        }
+    }
+
+    pub fn read_prg(&self, addr: u16) -> u8 {
+        self.mapper.borrow().read_prg(addr)
+    }
+
+    pub fn write_prg(&self, addr: u16, value: u8) {
+        self.mapper.borrow_mut().write_prg(addr, value);
+    }
+
+    pub fn read_chr(&self, addr: u16) -> u8 {
+        self.mapper.borrow().read_chr(addr)
+    }
+
+    pub fn write_chr(&self, addr: u16, value: u8) {
+        self.mapper.borrow_mut().write_chr(addr, value);
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mapper.borrow().mirroring()
+    }
+
+    pub fn read_nametable(&self, addr: u16) -> Option<u8> {
+        self.mapper.borrow().read_nametable(addr)
+    }
+
+    pub fn write_nametable(&self, addr: u16, value: u8) -> bool {
+        self.mapper.borrow_mut().write_nametable(addr, value)
+    }
+
+    pub fn observe_ppu_addr(&self, addr: u16) {
+        self.mapper.borrow_mut().observe_ppu_addr(addr);
+    }
+
+    pub fn observe_cpu_write(&self, addr: u16, value: u8) {
+        self.mapper.borrow_mut().observe_cpu_write(addr, value);
+    }
+
+    pub fn is_irq_asserted(&self) -> bool {
+        self.mapper.borrow().is_irq_asserted()
+    }
+
+    pub fn tick(&self) {
+        self.mapper.borrow_mut().tick();
+    }
+
+    pub fn with_expansion_audio<T>(
+        &self,
+        f: impl FnOnce(Option<&mut dyn ExpansionAudio>) -> T,
+    ) -> T {
+        f(self.mapper.borrow_mut().expansion_audio())
+    }
+
+    pub fn scan_barcode(&self, barcode: &str) -> bool {
+        self.mapper.borrow_mut().scan_barcode(barcode)
+    }
+
+    pub fn disk_side_count(&self) -> usize {
+        self.mapper.borrow().disk_side_count()
+    }
+
+    pub fn insert_disk(&self, side: Option<usize>) {
+        self.mapper.borrow_mut().insert_disk(side);
+    }
+
+    pub fn select_track(&self, track: usize) {
+        self.mapper.borrow_mut().select_track(track);
+    }
+
+    pub fn battery_ram(&self) -> Option<Vec<u8>> {
+        self.mapper.borrow().battery_ram().map(|data| data.to_vec())
+    }
+
+    pub fn load_battery_ram(&self, data: &[u8]) {
+        self.mapper.borrow_mut().load_battery_ram(data);
+    }
+}
@@ -0,0 +1,417 @@
+use crate::rom::nes::{Mirroring, NESHeader};
+
+use super::{eeprom::SerialEeprom, Banks, ChrMemory, Mapper};
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BandaiBoard {
+    // Registers at 0x6000..0x7fff, the IRQ counter is written directly
+    FCG,
+    // Registers at 0x8000..0xffff, the IRQ counter is reloaded from a latch
+    LZ93D50,
+    // iNES mapper 16 doesn't tell them apart, both register ranges are decoded
+    FCGOrLZ93D50,
+    // 8K of battery-backed SRAM and a 256K outer PRG bank
+    LZ93D50WithSram,
+    // Datach Joint ROM System, with a barcode reader
+    Datach,
+}
+
+impl BandaiBoard {
+    pub fn detect(header: &NESHeader) -> Self {
+        match (header.mapper, header.submapper) {
+            (16, 4) => BandaiBoard::FCG,
+            (16, 5) => BandaiBoard::LZ93D50,
+            (16, _) => BandaiBoard::FCGOrLZ93D50,
+            (153, _) => BandaiBoard::LZ93D50WithSram,
+            (157, _) => BandaiBoard::Datach,
+            (_, _) => BandaiBoard::LZ93D50,
+        }
+    }
+
+    fn has_fcg_registers(&self) -> bool {
+        matches!(self, BandaiBoard::FCG | BandaiBoard::FCGOrLZ93D50)
+    }
+
+    fn has_lz93d50_registers(&self) -> bool {
+        *self != BandaiBoard::FCG
+    }
+}
+
+// Datach cards are read as a stream of bars, each lasting a fixed number of CPU cycles
+struct BarcodeReader {
+    bars: Vec<bool>,
+    cycle: usize,
+}
+
+impl BarcodeReader {
+    const CYCLES_PER_BAR: usize = 1000;
+
+    // Left and right encodings of the EAN digits, the right ones are the inverted left ones
+    const L_CODES: [u8; 10] = [0x0d, 0x19, 0x13, 0x3d, 0x23, 0x31, 0x2f, 0x3b, 0x37, 0x0b];
+    const G_CODES: [u8; 10] = [0x27, 0x33, 0x1b, 0x21, 0x1d, 0x39, 0x05, 0x11, 0x09, 0x17];
+    // EAN-13 encodes its first digit in the L/G pattern of the next six, G is a set bit
+    const PARITIES: [u8; 10] = [0x00, 0x0b, 0x0d, 0x0e, 0x13, 0x19, 0x1c, 0x15, 0x16, 0x1a];
+
+    fn new() -> Self {
+        BarcodeReader {
+            bars: Vec::new(),
+            cycle: 0,
+        }
+    }
+
+    // Only EAN-13 and EAN-8 codes are accepted
+    fn scan(&mut self, barcode: &str) -> bool {
+        let digits: Vec<u8> = barcode.bytes().map(|c| c.wrapping_sub(b'0')).collect();
+        if !(digits.len() == 13 || digits.len() == 8) || digits.iter().any(|&digit| digit > 9) {
+            return false;
+        }
+        let (parity, digits) = if digits.len() == 13 {
+            (Self::PARITIES[digits[0] as usize], &digits[1..])
+        } else {
+            (0, &digits[..])
+        };
+        let half = digits.len() / 2;
+
+        let mut modules = vec![false; 32];
+        let push = |modules: &mut Vec<bool>, code: u8, width: u8| {
+            modules.extend((0..width).rev().map(|bit| ((code >> bit) & 0x01) != 0));
+        };
+        push(&mut modules, 0x05, 3);
+        for (i, digit) in digits[..half].iter().enumerate() {
+            let is_g = ((parity >> (half - 1 - i)) & 0x01) != 0;
+            let codes = if is_g { &Self::G_CODES } else { &Self::L_CODES };
+            push(&mut modules, codes[*digit as usize], 7);
+        }
+        push(&mut modules, 0x0a, 5);
+        for digit in digits[half..].iter() {
+            push(&mut modules, !Self::L_CODES[*digit as usize] & 0x7f, 7);
+        }
+        push(&mut modules, 0x05, 3);
+        modules.extend([false; 32].iter());
+
+        self.bars = modules;
+        self.cycle = 0;
+        true
+    }
+
+    fn tick(&mut self) {
+        if self.cycle < self.bars.len() * Self::CYCLES_PER_BAR {
+            self.cycle += 1;
+        }
+    }
+
+    // Spaces read as 1, bars and the idle reader as 0
+    fn output(&self) -> bool {
+        match self.bars.get(self.cycle / Self::CYCLES_PER_BAR) {
+            Some(&is_bar) => !is_bar,
+            None => false,
+        }
+    }
+}
+
+// iNES mappers 16, 153, 157 and 159
+pub struct BandaiFCG {
+    board: BandaiBoard,
+    prg_rom: Banks,
+    prg_ram: Vec<u8>,
+    chr: Banks,
+    eeprom: Option<SerialEeprom>,
+    barcode_reader: BarcodeReader,
+
+    // Internal registers
+    reg_prg_bank: u8,
+    reg_outer_prg_bank: u8,
+    reg_mirroring: u8,
+    reg_control: u8,
+    is_irq_enabled: bool,
+    is_irq_pending: bool,
+    irq_counter: u16,
+    irq_latch: u16,
+}
+
+impl BandaiFCG {
+    pub fn new(
+        board: BandaiBoard,
+        prg_rom: Vec<u8>,
+        prg_ram_size: usize,
+        eeprom_size: usize,
+        chr: ChrMemory,
+    ) -> Self {
+        let mut mapper = BandaiFCG {
+            board,
+            prg_rom: Banks::new(prg_rom, 0x4000, 2),
+            prg_ram: vec![0x00; prg_ram_size],
+            chr: Banks::new_chr(chr, 0x0400, 8),
+            eeprom: if eeprom_size > 0 {
+                Some(SerialEeprom::new(eeprom_size))
+            } else {
+                None
+            },
+            barcode_reader: BarcodeReader::new(),
+            reg_prg_bank: 0,
+            reg_outer_prg_bank: 0,
+            reg_mirroring: 0,
+            reg_control: 0,
+            is_irq_enabled: false,
+            is_irq_pending: false,
+            irq_counter: 0,
+            irq_latch: 0,
+        };
+        mapper.update_prg_banks();
+        mapper
+    }
+
+    fn update_prg_banks(&mut self) {
+        let outer = (self.reg_outer_prg_bank as usize) << 4;
+        self.prg_rom
+            .set(0, outer | (self.reg_prg_bank & 0x0f) as usize);
+        // Boards without an outer bank just see the last bank of the ROM
+        let last = if self.board == BandaiBoard::LZ93D50WithSram {
+            outer | 0x0f
+        } else {
+            self.prg_rom.bank_count() - 1
+        };
+        self.prg_rom.set(1, last);
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        !self.prg_ram.is_empty() && (self.reg_control & 0x20) != 0
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        let is_fcg = addr < 0x8000;
+        match addr & 0x0f {
+            window @ 0x0..=0x7 => {
+                // Boards with CHR RAM don't bank it, 153 uses the registers for the outer PRG bank
+                match self.board {
+                    BandaiBoard::LZ93D50WithSram => {
+                        self.reg_outer_prg_bank = value & 0x01;
+                        self.update_prg_banks();
+                    }
+                    BandaiBoard::Datach => {}
+                    _ => self.chr.set(window as usize, value as usize),
+                }
+            }
+            0x8 => {
+                self.reg_prg_bank = value;
+                self.update_prg_banks();
+            }
+            0x9 => self.reg_mirroring = value,
+            // Enabling the IRQ acknowledges it, the LZ93D50 also reloads the counter
+            0xa => {
+                self.is_irq_enabled = (value & 0x01) != 0;
+                self.is_irq_pending = false;
+                if !is_fcg {
+                    self.irq_counter = self.irq_latch;
+                }
+            }
+            0xb if is_fcg => self.irq_counter = (self.irq_counter & 0xff00) | value as u16,
+            0xc if is_fcg => self.irq_counter = (self.irq_counter & 0x00ff) | ((value as u16) << 8),
+            0xb => self.irq_latch = (self.irq_latch & 0xff00) | value as u16,
+            0xc => self.irq_latch = (self.irq_latch & 0x00ff) | ((value as u16) << 8),
+            // SCL and SDA lines of the EEPROM, the SRAM enable on boards without one
+            0xd => {
+                self.reg_control = value;
+                if let Some(eeprom) = &mut self.eeprom {
+                    eeprom.write((value & 0x20) != 0, (value & 0x40) != 0);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for BandaiFCG {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let open_bus = (addr >> 8) as u8;
+        match addr >> 12 {
+            0x6 | 0x7 if self.is_prg_ram_enabled() => self.prg_ram[(addr & 0x1fff) as usize],
+            0x6 | 0x7 if self.board.has_lz93d50_registers() => {
+                let mut value = open_bus & 0xe7;
+                // The EEPROM data line is only readable with the direction bit set
+                if let Some(eeprom) = &self.eeprom {
+                    let is_readable = (self.reg_control & 0x80) != 0;
+                    value |= ((is_readable && eeprom.output()) as u8) << 4;
+                }
+                if self.board == BandaiBoard::Datach {
+                    value |= (self.barcode_reader.output() as u8) << 3;
+                }
+                value
+            }
+            0x4..=0x7 => open_bus,
+            0x8..=0xf => self.prg_rom.read((addr & 0x7fff) as usize),
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        match addr >> 12 {
+            0x6 | 0x7 if self.is_prg_ram_enabled() => {
+                self.prg_ram[(addr & 0x1fff) as usize] = value
+            }
+            0x6 | 0x7 if self.board.has_fcg_registers() => self.write_register(addr, value),
+            0x4..=0x7 => {}
+            0x8..=0xf if self.board.has_lz93d50_registers() => self.write_register(addr, value),
+            0x8..=0xf => {}
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.reg_mirroring & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenA,
+            _ => Mirroring::SingleScreenB,
+        }
+    }
+
+    fn is_irq_asserted(&self) -> bool {
+        self.is_irq_pending
+    }
+
+    fn tick(&mut self) {
+        if self.is_irq_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0 {
+                self.is_irq_pending = true;
+            }
+        }
+        self.barcode_reader.tick();
+    }
+
+    fn scan_barcode(&mut self, barcode: &str) -> bool {
+        self.board == BandaiBoard::Datach && self.barcode_reader.scan(barcode)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        match &self.eeprom {
+            Some(eeprom) => Some(eeprom.data()),
+            None => Some(&self.prg_ram),
+        }
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        match &mut self.eeprom {
+            Some(eeprom) => Some(eeprom.data_mut()),
+            None => Some(&mut self.prg_ram),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::mappers::numbered_banks;
+
+    fn test_mapper(board: BandaiBoard, prg_ram_size: usize, eeprom_size: usize) -> BandaiFCG {
+        let prg_rom = numbered_banks(0x80000, 0x4000);
+        let chr = numbered_banks(0x40000, 0x0400);
+        BandaiFCG::new(
+            board,
+            prg_rom,
+            prg_ram_size,
+            eeprom_size,
+            ChrMemory::rom(chr),
+        )
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut mapper = test_mapper(BandaiBoard::FCG, 0, 0);
+        mapper.write_prg(0x6008, 3);
+        mapper.write_prg(0x6007, 0x42);
+        mapper.write_prg(0x6009, 1);
+        assert_eq!(3, mapper.read_prg(0x8000));
+        assert_eq!(31, mapper.read_prg(0xc000));
+        assert_eq!(0x42, mapper.read_chr(0x1c00));
+        assert_eq!(Mirroring::Horizontal, mapper.mirroring());
+        // The FCG ignores the LZ93D50 range
+        mapper.write_prg(0x8008, 4);
+        assert_eq!(3, mapper.read_prg(0x8000));
+
+        // 153 uses the CHR registers for the outer PRG bank
+        let mut mapper = test_mapper(BandaiBoard::LZ93D50WithSram, 0x2000, 0);
+        mapper.write_prg(0x8000, 1);
+        mapper.write_prg(0x8008, 2);
+        assert_eq!(18, mapper.read_prg(0x8000));
+        assert_eq!(31, mapper.read_prg(0xc000));
+        mapper.write_prg(0x800d, 0x20);
+        mapper.write_prg(0x6000, 0x55);
+        assert_eq!(0x55, mapper.read_prg(0x6000));
+    }
+
+    #[test]
+    fn test_irq() {
+        // The FCG writes the counter directly
+        let mut mapper = test_mapper(BandaiBoard::FCGOrLZ93D50, 0, 0x100);
+        mapper.write_prg(0x600b, 2);
+        mapper.write_prg(0x600a, 1);
+        mapper.tick();
+        assert!(!mapper.is_irq_asserted());
+        mapper.tick();
+        assert!(mapper.is_irq_asserted());
+        mapper.write_prg(0x600a, 0);
+        assert!(!mapper.is_irq_asserted());
+
+        // The LZ93D50 copies the latch on enable
+        mapper.write_prg(0x800b, 1);
+        mapper.write_prg(0x800c, 0);
+        mapper.write_prg(0x800a, 1);
+        mapper.tick();
+        assert!(mapper.is_irq_asserted());
+    }
+
+    #[test]
+    fn test_eeprom() {
+        let mut mapper = test_mapper(BandaiBoard::LZ93D50, 0, 0x80);
+        let write = |mapper: &mut BandaiFCG, scl: bool, sda: bool| {
+            mapper.write_prg(0x800d, 0x80 | ((scl as u8) << 5) | ((sda as u8) << 6));
+        };
+        // Start, then address 0x01 for reading LSB first
+        write(&mut mapper, true, true);
+        write(&mut mapper, true, false);
+        for bit in [1, 0, 0, 0, 0, 0, 0, 1].iter().map(|&bit| bit != 0) {
+            write(&mut mapper, false, bit);
+            write(&mut mapper, true, bit);
+        }
+        write(&mut mapper, false, true);
+        // Acknowledged
+        assert_eq!(0x00, mapper.read_prg(0x6000) & 0x10);
+        mapper.load_battery_ram(&[0xff; 0x80]);
+        write(&mut mapper, true, true);
+        write(&mut mapper, false, true);
+        assert_eq!(0x10, mapper.read_prg(0x6000) & 0x10);
+    }
+
+    #[test]
+    fn test_barcode() {
+        let mut mapper = test_mapper(BandaiBoard::Datach, 0, 0x100);
+        assert!(!mapper.scan_barcode("123"));
+        assert!(mapper.scan_barcode("4901234567894"));
+        // 32 spaces, then the start guard
+        let output = |mapper: &BandaiFCG| mapper.read_prg(0x6000) & 0x08;
+        assert_eq!(0x08, output(&mapper));
+        for _ in 0..32 * 1000 {
+            mapper.tick();
+        }
+        assert_eq!(0x00, output(&mapper));
+        for _ in 0..1000 {
+            mapper.tick();
+        }
+        assert_eq!(0x08, output(&mapper));
+    }
+}
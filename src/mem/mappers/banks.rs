@@ -0,0 +1,93 @@
+// Pattern table memory of a board, boards without CHR ROM use CHR RAM instead
+pub struct ChrMemory {
+    data: Vec<u8>,
+    is_ram: bool,
+}
+
+impl ChrMemory {
+    pub fn rom(data: Vec<u8>) -> Self {
+        ChrMemory {
+            data,
+            is_ram: false,
+        }
+    }
+
+    pub fn ram(size: usize) -> Self {
+        ChrMemory {
+            data: vec![0x00; size],
+            is_ram: true,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+// A ROM or RAM chip seen through a set of equally sized switchable windows
+pub struct Banks {
+    data: Vec<u8>,
+    is_read_only: bool,
+    window_size: usize,
+    offsets: Vec<usize>,
+}
+
+impl Banks {
+    pub fn new(data: Vec<u8>, window_size: usize, windows: usize) -> Self {
+        let mut banks = Banks {
+            data,
+            is_read_only: false,
+            window_size,
+            offsets: vec![0; windows],
+        };
+        for window in 0..windows {
+            banks.set(window, window);
+        }
+        banks
+    }
+
+    pub fn new_chr(chr: ChrMemory, window_size: usize, windows: usize) -> Self {
+        let mut banks = Self::new(chr.data, window_size, windows);
+        banks.is_read_only = !chr.is_ram;
+        banks
+    }
+
+    pub fn bank_count(&self) -> usize {
+        (self.data.len() / self.window_size).max(1)
+    }
+
+    pub fn set(&mut self, window: usize, bank: usize) {
+        // Unconnected high address lines make the bank number wrap around
+        self.offsets[window] = (bank % self.bank_count()) * self.window_size;
+    }
+
+    pub fn read(&self, addr: usize) -> u8 {
+        if self.data.is_empty() {
+            return 0;
+        }
+        self.data[self.index(addr)]
+    }
+
+    // The whole chip regardless of the current banks
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    pub fn write(&mut self, addr: usize, value: u8) {
+        if self.data.is_empty() || self.is_read_only {
+            return;
+        }
+        let idx = self.index(addr);
+        self.data[idx] = value;
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn index(&self, addr: usize) -> usize {
+        let window = (addr / self.window_size) % self.offsets.len();
+        // Chips smaller than a window are mirrored inside it
+        (self.offsets[window] + addr % self.window_size) % self.data.len()
+    }
+}
@@ -0,0 +1,492 @@
+// Boards built from discrete logic chips with a single write-only latch
+
+use crate::rom::nes::Mirroring;
+
+use super::{Banks, ChrMemory, Mapper};
+
+// The latch is written with the CPU and the ROM driving the data bus at the same time,
+// so the value that ends up in the latch is the AND of both
+fn resolve_bus_conflict(prg_rom: &Banks, has_bus_conflicts: bool, addr: u16, value: u8) -> u8 {
+    if has_bus_conflicts {
+        value & prg_rom.read((addr & 0x7fff) as usize)
+    } else {
+        value
+    }
+}
+
+fn read_prg_rom(prg_rom: &Banks, addr: u16) -> u8 {
+    match addr >> 12 {
+        // Open bus
+        0x4..=0x7 => (addr >> 8) as u8,
+        0x8..=0xf => prg_rom.read((addr & 0x7fff) as usize),
+        _ => panic!("Unmapped space access"),
+    }
+}
+
+// iNES mapper 2
+#[allow(clippy::upper_case_acronyms)]
+pub struct UxROM {
+    prg_rom: Banks,
+    chr: Banks,
+    mirroring: Mirroring,
+    has_bus_conflicts: bool,
+}
+
+impl UxROM {
+    pub fn new(
+        prg_rom: Vec<u8>,
+        chr: ChrMemory,
+        mirroring: Mirroring,
+        has_bus_conflicts: bool,
+    ) -> Self {
+        let mut prg_rom = Banks::new(prg_rom, 0x4000, 2);
+        prg_rom.set(1, prg_rom.bank_count() - 1);
+        UxROM {
+            prg_rom,
+            chr: Banks::new_chr(chr, 0x2000, 1),
+            mirroring,
+            has_bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for UxROM {
+    fn read_prg(&self, addr: u16) -> u8 {
+        read_prg_rom(&self.prg_rom, addr)
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        if addr >= 0x8000 {
+            let value = resolve_bus_conflict(&self.prg_rom, self.has_bus_conflicts, addr, value);
+            self.prg_rom.set(0, value as usize);
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+// iNES mapper 3
+#[allow(clippy::upper_case_acronyms)]
+pub struct CNROM {
+    prg_rom: Banks,
+    chr: Banks,
+    mirroring: Mirroring,
+    has_bus_conflicts: bool,
+}
+
+impl CNROM {
+    pub fn new(
+        prg_rom: Vec<u8>,
+        chr: ChrMemory,
+        mirroring: Mirroring,
+        has_bus_conflicts: bool,
+    ) -> Self {
+        CNROM {
+            prg_rom: Banks::new(prg_rom, 0x8000, 1),
+            chr: Banks::new_chr(chr, 0x2000, 1),
+            mirroring,
+            has_bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for CNROM {
+    fn read_prg(&self, addr: u16) -> u8 {
+        read_prg_rom(&self.prg_rom, addr)
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        if addr >= 0x8000 {
+            let value = resolve_bus_conflict(&self.prg_rom, self.has_bus_conflicts, addr, value);
+            self.chr.set(0, value as usize);
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+// iNES mapper 7
+#[allow(clippy::upper_case_acronyms)]
+pub struct AxROM {
+    prg_rom: Banks,
+    chr: Banks,
+    mirroring: Mirroring,
+    has_bus_conflicts: bool,
+}
+
+impl AxROM {
+    pub fn new(prg_rom: Vec<u8>, chr: ChrMemory, has_bus_conflicts: bool) -> Self {
+        AxROM {
+            prg_rom: Banks::new(prg_rom, 0x8000, 1),
+            chr: Banks::new_chr(chr, 0x2000, 1),
+            mirroring: Mirroring::SingleScreenA,
+            has_bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for AxROM {
+    fn read_prg(&self, addr: u16) -> u8 {
+        read_prg_rom(&self.prg_rom, addr)
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        if addr >= 0x8000 {
+            let value = resolve_bus_conflict(&self.prg_rom, self.has_bus_conflicts, addr, value);
+            self.prg_rom.set(0, (value & 0x07) as usize);
+            self.mirroring = if (value & 0x10) == 0 {
+                Mirroring::SingleScreenA
+            } else {
+                Mirroring::SingleScreenB
+            };
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+// iNES mapper 11
+pub struct ColorDreams {
+    prg_rom: Banks,
+    chr: Banks,
+    mirroring: Mirroring,
+    has_bus_conflicts: bool,
+}
+
+impl ColorDreams {
+    pub fn new(
+        prg_rom: Vec<u8>,
+        chr: ChrMemory,
+        mirroring: Mirroring,
+        has_bus_conflicts: bool,
+    ) -> Self {
+        ColorDreams {
+            prg_rom: Banks::new(prg_rom, 0x8000, 1),
+            chr: Banks::new_chr(chr, 0x2000, 1),
+            mirroring,
+            has_bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for ColorDreams {
+    fn read_prg(&self, addr: u16) -> u8 {
+        read_prg_rom(&self.prg_rom, addr)
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        if addr >= 0x8000 {
+            let value = resolve_bus_conflict(&self.prg_rom, self.has_bus_conflicts, addr, value);
+            self.prg_rom.set(0, (value & 0x03) as usize);
+            self.chr.set(0, (value >> 4) as usize);
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+// iNES mapper 34 with up to 8K of CHR
+#[allow(clippy::upper_case_acronyms)]
+pub struct BNROM {
+    prg_rom: Banks,
+    chr: Banks,
+    mirroring: Mirroring,
+    has_bus_conflicts: bool,
+}
+
+impl BNROM {
+    pub fn new(
+        prg_rom: Vec<u8>,
+        chr: ChrMemory,
+        mirroring: Mirroring,
+        has_bus_conflicts: bool,
+    ) -> Self {
+        BNROM {
+            prg_rom: Banks::new(prg_rom, 0x8000, 1),
+            chr: Banks::new_chr(chr, 0x2000, 1),
+            mirroring,
+            has_bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for BNROM {
+    fn read_prg(&self, addr: u16) -> u8 {
+        read_prg_rom(&self.prg_rom, addr)
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        if addr >= 0x8000 {
+            let value = resolve_bus_conflict(&self.prg_rom, self.has_bus_conflicts, addr, value);
+            self.prg_rom.set(0, value as usize);
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+// iNES mapper 34 with more than 8K of CHR, the registers overlay the PRG RAM
+#[allow(clippy::upper_case_acronyms)]
+pub struct NINA001 {
+    prg_rom: Banks,
+    prg_ram: Vec<u8>,
+    chr: Banks,
+    mirroring: Mirroring,
+}
+
+impl NINA001 {
+    pub fn new(prg_rom: Vec<u8>, chr: ChrMemory, mirroring: Mirroring) -> Self {
+        NINA001 {
+            prg_rom: Banks::new(prg_rom, 0x8000, 1),
+            prg_ram: vec![0x00; 0x2000],
+            chr: Banks::new_chr(chr, 0x1000, 2),
+            mirroring,
+        }
+    }
+}
+
+impl Mapper for NINA001 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr >> 12 {
+            0x6 | 0x7 => self.prg_ram[(addr & 0x1fff) as usize],
+            _ => read_prg_rom(&self.prg_rom, addr),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        if (0x6000..=0x7fff).contains(&addr) {
+            self.prg_ram[(addr & 0x1fff) as usize] = value;
+        }
+        match addr {
+            0x7ffd => self.prg_rom.set(0, (value & 0x01) as usize),
+            0x7ffe => self.chr.set(0, (value & 0x0f) as usize),
+            0x7fff => self.chr.set(1, (value & 0x0f) as usize),
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+// iNES mapper 66
+#[allow(clippy::upper_case_acronyms)]
+pub struct GxROM {
+    prg_rom: Banks,
+    chr: Banks,
+    mirroring: Mirroring,
+    has_bus_conflicts: bool,
+}
+
+impl GxROM {
+    pub fn new(
+        prg_rom: Vec<u8>,
+        chr: ChrMemory,
+        mirroring: Mirroring,
+        has_bus_conflicts: bool,
+    ) -> Self {
+        GxROM {
+            prg_rom: Banks::new(prg_rom, 0x8000, 1),
+            chr: Banks::new_chr(chr, 0x2000, 1),
+            mirroring,
+            has_bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for GxROM {
+    fn read_prg(&self, addr: u16) -> u8 {
+        read_prg_rom(&self.prg_rom, addr)
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        if addr >= 0x8000 {
+            let value = resolve_bus_conflict(&self.prg_rom, self.has_bus_conflicts, addr, value);
+            self.prg_rom.set(0, ((value >> 4) & 0x03) as usize);
+            self.chr.set(0, (value & 0x03) as usize);
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::mappers::numbered_banks;
+
+    #[test]
+    fn test_uxrom_bus_conflicts() {
+        let mut prg_rom = numbered_banks(0x20000, 0x4000);
+        prg_rom[0x1234] = 0x05;
+        let mut mapper = UxROM::new(
+            prg_rom.clone(),
+            ChrMemory::ram(0x2000),
+            Mirroring::Vertical,
+            false,
+        );
+        mapper.write_prg(0x9234, 0x06);
+        assert_eq!(6, mapper.read_prg(0x8000));
+        assert_eq!(7, mapper.read_prg(0xc000));
+
+        let mut mapper = UxROM::new(prg_rom, ChrMemory::ram(0x2000), Mirroring::Vertical, true);
+        mapper.write_prg(0x9234, 0x06);
+        assert_eq!(4, mapper.read_prg(0x8000));
+    }
+
+    #[test]
+    fn test_axrom_single_screen() {
+        let mut mapper = AxROM::new(
+            numbered_banks(0x20000, 0x8000),
+            ChrMemory::ram(0x2000),
+            false,
+        );
+        mapper.write_prg(0x8000, 0x12);
+        assert_eq!(2, mapper.read_prg(0x8000));
+        assert_eq!(Mirroring::SingleScreenB, mapper.mirroring());
+        mapper.write_prg(0x8000, 0x02);
+        assert_eq!(Mirroring::SingleScreenA, mapper.mirroring());
+    }
+
+    #[test]
+    fn test_cnrom() {
+        let mut prg_rom = numbered_banks(0x8000, 0x8000);
+        prg_rom[0x0010] = 0x01;
+        let chr = ChrMemory::rom(numbered_banks(0x8000, 0x2000));
+        let mut mapper = CNROM::new(prg_rom.clone(), chr, Mirroring::Vertical, false);
+        mapper.write_prg(0x8000, 0x03);
+        assert_eq!(3, mapper.read_chr(0x0000));
+
+        let chr = ChrMemory::rom(numbered_banks(0x8000, 0x2000));
+        let mut mapper = CNROM::new(prg_rom, chr, Mirroring::Vertical, true);
+        mapper.write_prg(0x8010, 0x03);
+        assert_eq!(1, mapper.read_chr(0x1fff));
+    }
+
+    #[test]
+    fn test_color_dreams() {
+        let chr = ChrMemory::rom(numbered_banks(0x20000, 0x2000));
+        let mut prg_rom = numbered_banks(0x20000, 0x8000);
+        prg_rom[0x0010] = 0xff;
+        let mut mapper = ColorDreams::new(prg_rom, chr, Mirroring::Vertical, true);
+        mapper.write_prg(0x8010, 0x52);
+        assert_eq!(2, mapper.read_prg(0x8000));
+        assert_eq!(5, mapper.read_chr(0x0000));
+        // Bank 2 holds 0x02 at the same address, which masks out the value
+        mapper.write_prg(0x8010, 0x31);
+        assert_eq!(0, mapper.read_prg(0xffff));
+        assert_eq!(0, mapper.read_chr(0x0000));
+    }
+
+    #[test]
+    fn test_bnrom() {
+        let mut prg_rom = numbered_banks(0x20000, 0x8000);
+        prg_rom[0x0020] = 0x01;
+        let mut mapper = BNROM::new(prg_rom, ChrMemory::ram(0x2000), Mirroring::Vertical, true);
+        mapper.write_prg(0x8000, 0x03);
+        assert_eq!(0, mapper.read_prg(0x8000));
+        mapper.write_prg(0x8020, 0x03);
+        assert_eq!(1, mapper.read_prg(0xc000));
+    }
+
+    #[test]
+    fn test_nina001() {
+        let chr = ChrMemory::rom(numbered_banks(0x10000, 0x1000));
+        let mut mapper = NINA001::new(numbered_banks(0x10000, 0x8000), chr, Mirroring::Vertical);
+        mapper.write_prg(0x7ffd, 0x01);
+        mapper.write_prg(0x7ffe, 0x05);
+        mapper.write_prg(0x7fff, 0x0a);
+        assert_eq!(1, mapper.read_prg(0x8000));
+        assert_eq!(5, mapper.read_chr(0x0000));
+        assert_eq!(10, mapper.read_chr(0x1000));
+        // The registers are written to the RAM as well
+        assert_eq!(0x0a, mapper.read_prg(0x7fff));
+    }
+
+    #[test]
+    fn test_gxrom() {
+        let chr = ChrMemory::rom(numbered_banks(0x8000, 0x2000));
+        let mut mapper = GxROM::new(
+            numbered_banks(0x20000, 0x8000),
+            chr,
+            Mirroring::Vertical,
+            false,
+        );
+        mapper.write_prg(0x8000, 0x21);
+        assert_eq!(2, mapper.read_prg(0x8000));
+        assert_eq!(1, mapper.read_chr(0x0000));
+    }
+}
@@ -0,0 +1,275 @@
+// I2C serial EEPROMs driven bit by bit through a mapper register.
+// The 128 byte X24C01 uses a simplified protocol, sending a single address byte LSB first,
+// while the 256 byte 24C02 has a device address byte and sends everything MSB first.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Idle,
+    DeviceAddress,
+    WordAddress,
+    Write,
+    Read,
+}
+
+pub struct SerialEeprom {
+    data: Vec<u8>,
+    mode: Mode,
+    // Rising clock edges in the current byte, the 9th one is the acknowledge
+    bit: u8,
+    shift: u8,
+    address: u8,
+    is_reading_data: bool,
+    is_read_acked: bool,
+    // Data line driven by the EEPROM, high when released
+    output: bool,
+    scl: bool,
+    sda: bool,
+}
+
+impl SerialEeprom {
+    pub fn new(size: usize) -> Self {
+        SerialEeprom {
+            data: vec![0x00; size],
+            mode: Mode::Idle,
+            bit: 0,
+            shift: 0,
+            address: 0,
+            is_reading_data: false,
+            is_read_acked: false,
+            output: true,
+            scl: false,
+            sda: false,
+        }
+    }
+
+    fn is_24c01(&self) -> bool {
+        self.data.len() <= 0x80
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    pub fn output(&self) -> bool {
+        self.output
+    }
+
+    pub fn write(&mut self, scl: bool, sda: bool) {
+        if self.scl && scl && sda != self.sda {
+            // Data changes while the clock is high are start and stop conditions
+            if sda {
+                self.mode = Mode::Idle;
+                self.output = true;
+            } else {
+                self.start();
+            }
+        } else if scl && !self.scl {
+            self.clock_rise(sda);
+        } else if !scl && self.scl {
+            self.clock_fall();
+        }
+        self.scl = scl;
+        self.sda = sda;
+    }
+
+    fn start(&mut self) {
+        self.mode = if self.is_24c01() {
+            Mode::WordAddress
+        } else {
+            Mode::DeviceAddress
+        };
+        self.bit = 0;
+        self.output = true;
+    }
+
+    fn clock_rise(&mut self, sda: bool) {
+        if self.mode == Mode::Idle {
+            return;
+        }
+        self.bit += 1;
+        match self.mode {
+            Mode::Read if self.bit == 9 && self.is_reading_data => self.is_read_acked = !sda,
+            Mode::Read => {}
+            _ if self.bit <= 8 => {
+                self.shift = if self.is_24c01() {
+                    (self.shift >> 1) | ((sda as u8) << 7)
+                } else {
+                    (self.shift << 1) | sda as u8
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn clock_fall(&mut self) {
+        match (self.mode, self.bit) {
+            (Mode::Idle, _) => {}
+            (Mode::Read, 9) => {
+                self.bit = 0;
+                if self.is_reading_data && !self.is_read_acked {
+                    self.mode = Mode::Idle;
+                    self.output = true;
+                    return;
+                }
+                self.is_reading_data = true;
+                self.shift = self.data[self.address as usize];
+                self.address = self.address.wrapping_add(1) & (self.data.len() - 1) as u8;
+                self.output_bit();
+            }
+            // Released for the master to acknowledge
+            (Mode::Read, 8) => self.output = true,
+            (Mode::Read, _) => self.output_bit(),
+            (_, 8) => self.receive_byte(),
+            (_, 9) => {
+                self.bit = 0;
+                self.output = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn output_bit(&mut self) {
+        let bit = if self.is_24c01() {
+            self.bit
+        } else {
+            7 - self.bit
+        };
+        self.output = ((self.shift >> bit) & 0x01) != 0;
+    }
+
+    // Handles a complete byte from the master and acknowledges it
+    fn receive_byte(&mut self) {
+        self.output = false;
+        match self.mode {
+            Mode::DeviceAddress if (self.shift & 0xf0) != 0xa0 => {
+                // Addressed to another device
+                self.mode = Mode::Idle;
+                self.output = true;
+            }
+            Mode::DeviceAddress if (self.shift & 0x01) != 0 => self.start_read(),
+            Mode::DeviceAddress => self.mode = Mode::WordAddress,
+            Mode::WordAddress if self.is_24c01() => {
+                self.address = self.shift & 0x7f;
+                if (self.shift & 0x80) != 0 {
+                    self.start_read();
+                } else {
+                    self.mode = Mode::Write;
+                }
+            }
+            Mode::WordAddress => {
+                self.address = self.shift;
+                self.mode = Mode::Write;
+            }
+            Mode::Write => {
+                self.data[self.address as usize] = self.shift;
+                // Writes wrap around within a page, 4 bytes on the 24C01 and 8 on the 24C02
+                let page_mask = if self.is_24c01() { 0x03 } else { 0x07 };
+                self.address =
+                    (self.address & !page_mask) | (self.address.wrapping_add(1) & page_mask);
+            }
+            _ => {}
+        }
+    }
+
+    fn start_read(&mut self) {
+        self.mode = Mode::Read;
+        self.is_reading_data = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bit-bangs the I2C bus like the games do
+    struct Master<'a>(&'a mut SerialEeprom);
+
+    impl Master<'_> {
+        fn start(&mut self) {
+            self.0.write(false, true);
+            self.0.write(true, true);
+            self.0.write(true, false);
+            self.0.write(false, false);
+        }
+
+        fn stop(&mut self) {
+            self.0.write(false, false);
+            self.0.write(true, false);
+            self.0.write(true, true);
+        }
+
+        fn clock(&mut self, sda: bool) -> bool {
+            self.0.write(false, sda);
+            self.0.write(true, sda);
+            let output = self.0.output();
+            self.0.write(false, sda);
+            output
+        }
+
+        // Returns true if the EEPROM acknowledged
+        fn send(&mut self, value: u8, is_lsb_first: bool) -> bool {
+            for i in 0..8 {
+                let bit = if is_lsb_first { i } else { 7 - i };
+                self.clock(((value >> bit) & 0x01) != 0);
+            }
+            !self.clock(true)
+        }
+
+        fn receive(&mut self, is_lsb_first: bool, ack: bool) -> u8 {
+            let mut value = 0;
+            for i in 0..8 {
+                let bit = if is_lsb_first { i } else { 7 - i };
+                value |= (self.clock(true) as u8) << bit;
+            }
+            self.clock(!ack);
+            value
+        }
+    }
+
+    #[test]
+    fn test_24c02() {
+        let mut eeprom = SerialEeprom::new(0x100);
+        let mut master = Master(&mut eeprom);
+        master.start();
+        assert!(master.send(0xa0, false));
+        assert!(master.send(0x10, false));
+        assert!(master.send(0x12, false));
+        assert!(master.send(0x34, false));
+        master.stop();
+        assert_eq!(&[0x12, 0x34], &master.0.data()[0x10..0x12]);
+
+        // Random read through a dummy write
+        master.start();
+        assert!(master.send(0xa0, false));
+        assert!(master.send(0x10, false));
+        master.start();
+        assert!(master.send(0xa1, false));
+        assert_eq!(0x12, master.receive(false, true));
+        assert_eq!(0x34, master.receive(false, false));
+        master.stop();
+
+        // Other devices on the bus are ignored
+        master.start();
+        assert!(!master.send(0x50, false));
+    }
+
+    #[test]
+    fn test_24c01() {
+        let mut eeprom = SerialEeprom::new(0x80);
+        let mut master = Master(&mut eeprom);
+        master.start();
+        assert!(master.send(0x05, true));
+        assert!(master.send(0xa5, true));
+        master.stop();
+        assert_eq!(0xa5, master.0.data()[0x05]);
+
+        master.start();
+        assert!(master.send(0x85, true));
+        assert_eq!(0xa5, master.receive(true, false));
+        master.stop();
+    }
+}
@@ -0,0 +1,551 @@
+use std::{cell::Cell, ops::Range};
+
+use crate::{
+    apu::ExpansionAudio,
+    rom::{fds::DISK_SIDE_SIZE, nes::Mirroring},
+};
+
+use super::{fds_audio::FdsAudio, Mapper};
+
+// Gaps are written as zeroes, 28300 bits before the first block and 976 bits after each block
+const LEADING_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+// Sides are padded so that games can append files after the last block
+const SIDE_LENGTH: usize = LEADING_GAP + DISK_SIDE_SIZE;
+
+// CPU cycles for the head to get back to the start of the disk, and to pass over a byte
+const REWIND_CYCLES: u32 = 50000;
+const BYTE_CYCLES: u32 = 150;
+
+// CRC-16 with the reflected CCITT polynomial, the gap end mark is included.
+// Running the CRC over a block followed by its checksum gives 0.
+fn update_crc(crc: u16, value: u8) -> u16 {
+    let mut crc = crc ^ value as u16;
+    for _ in 0..8 {
+        crc = if (crc & 0x01) != 0 {
+            (crc >> 1) ^ 0x8408
+        } else {
+            crc >> 1
+        };
+    }
+    crc
+}
+
+// Lays out the blocks of an FDS image the way they are on the disk surface,
+// each one starts with the gap end mark and is followed by its CRC
+fn layout_disk_side(blocks: &[u8]) -> Vec<u8> {
+    let mut side = vec![0x00; LEADING_GAP];
+    let mut idx = 0;
+    let mut file_size = 0;
+    while idx < blocks.len() {
+        let length = match blocks[idx] {
+            // Disk info, file count, file header and file data
+            1 => 56,
+            2 => 2,
+            3 => 16,
+            4 => file_size + 1,
+            _ => break,
+        };
+        let block = match blocks.get(idx..idx + length) {
+            Some(block) => block,
+            None => break,
+        };
+        if block[0] == 3 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+        side.push(0x80);
+        side.extend_from_slice(block);
+        let crc = block
+            .iter()
+            .fold(update_crc(0, 0x80), |crc, &value| update_crc(crc, value));
+        side.extend_from_slice(&crc.to_le_bytes());
+        side.resize(side.len() + BLOCK_GAP, 0x00);
+        idx += length;
+    }
+    side.resize(side.len().max(SIDE_LENGTH), 0x00);
+    side
+}
+
+// Famicom Disk System RAM adapter with the disk drive plugged into it
+#[allow(clippy::upper_case_acronyms)]
+pub struct FDS {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+
+    // All the sides one after another, with gaps and CRCs
+    disk: Vec<u8>,
+    sides: Vec<Range<usize>>,
+
+    // Internal registers
+    reg_irq_reload: u16,
+    reg_write_data: u8,
+    reg_read_data: u8,
+    reg_control: u8,
+    reg_external: u8,
+    is_disk_io_enabled: bool,
+    is_sound_io_enabled: bool,
+
+    // Timer IRQ
+    irq_counter: u16,
+    is_irq_enabled: bool,
+    is_irq_repeated: bool,
+    // Acknowledged by reading the status
+    is_timer_irq_pending: Cell<bool>,
+    is_disk_irq_pending: Cell<bool>,
+    is_transfer_complete: Cell<bool>,
+
+    // Drive
+    inserted_side: Option<usize>,
+    is_motor_on: bool,
+    head: usize,
+    delay: u32,
+    is_scanning: bool,
+    is_end_of_head: bool,
+    is_gap_ended: bool,
+    crc: u16,
+    is_crc_error: bool,
+
+    audio: FdsAudio,
+}
+
+impl FDS {
+    pub fn new(bios: Vec<u8>, sides: Vec<Vec<u8>>) -> Self {
+        let mut disk = vec![];
+        let mut ranges = vec![];
+        for side in sides {
+            let start = disk.len();
+            disk.extend(layout_disk_side(&side));
+            ranges.push(start..disk.len());
+        }
+        FDS {
+            bios,
+            prg_ram: vec![0x00; 0x8000],
+            chr_ram: vec![0x00; 0x2000],
+            disk,
+            inserted_side: if ranges.is_empty() { None } else { Some(0) },
+            sides: ranges,
+            reg_irq_reload: 0,
+            reg_write_data: 0,
+            reg_read_data: 0,
+            reg_control: 0,
+            reg_external: 0,
+            is_disk_io_enabled: false,
+            is_sound_io_enabled: false,
+            irq_counter: 0,
+            is_irq_enabled: false,
+            is_irq_repeated: false,
+            is_timer_irq_pending: Cell::new(false),
+            is_disk_irq_pending: Cell::new(false),
+            is_transfer_complete: Cell::new(false),
+            is_motor_on: false,
+            head: 0,
+            delay: 0,
+            is_scanning: false,
+            is_end_of_head: true,
+            is_gap_ended: false,
+            crc: 0,
+            is_crc_error: false,
+            audio: FdsAudio::new(),
+        }
+    }
+
+    fn is_transfer_reset(&self) -> bool {
+        (self.reg_control & 0x02) != 0
+    }
+
+    fn is_read_mode(&self) -> bool {
+        (self.reg_control & 0x04) != 0
+    }
+
+    fn is_crc_control(&self) -> bool {
+        (self.reg_control & 0x10) != 0
+    }
+
+    // Cleared while the head is over a gap
+    fn is_transfer_enabled(&self) -> bool {
+        (self.reg_control & 0x40) != 0
+    }
+
+    fn is_disk_irq_enabled(&self) -> bool {
+        (self.reg_control & 0x80) != 0
+    }
+
+    fn read_status(&self) -> u8 {
+        let value = self.is_timer_irq_pending.get() as u8
+            | ((self.is_transfer_complete.get() as u8) << 1)
+            | ((self.is_crc_error as u8) << 4)
+            | ((self.is_end_of_head as u8) << 6);
+        self.is_timer_irq_pending.set(false);
+        self.is_disk_irq_pending.set(false);
+        self.is_transfer_complete.set(false);
+        value
+    }
+
+    fn read_drive_status(&self) -> u8 {
+        let is_ejected = self.inserted_side.is_none();
+        // Upper bits are open bus, writes are always allowed on an inserted disk
+        0x40 | is_ejected as u8
+            | (((is_ejected || !self.is_scanning) as u8) << 1)
+            | ((is_ejected as u8) << 2)
+    }
+
+    fn tick_timer(&mut self) {
+        if !self.is_irq_enabled {
+            return;
+        }
+        if self.irq_counter == 0 {
+            self.is_timer_irq_pending.set(true);
+            self.irq_counter = self.reg_irq_reload;
+            self.is_irq_enabled = self.is_irq_repeated;
+        } else {
+            self.irq_counter -= 1;
+        }
+    }
+
+    fn tick_drive(&mut self) {
+        let side = match self.inserted_side {
+            Some(side) if self.is_motor_on => side,
+            _ => {
+                self.is_end_of_head = true;
+                self.is_scanning = false;
+                return;
+            }
+        };
+        if self.is_transfer_reset() && !self.is_scanning {
+            return;
+        }
+        if self.is_end_of_head {
+            self.is_end_of_head = false;
+            self.head = 0;
+            self.delay = REWIND_CYCLES;
+            self.is_gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+        self.is_scanning = true;
+        let range = self.sides[side].clone();
+        let position = range.start + self.head;
+        if self.is_read_mode() {
+            self.read_byte(position);
+        } else {
+            self.write_byte(position);
+        }
+        self.head += 1;
+        if self.head >= range.len() {
+            // The drive stops at the end of the disk and rewinds when restarted
+            self.is_motor_on = false;
+        } else {
+            self.delay = BYTE_CYCLES - 1;
+        }
+    }
+
+    fn read_byte(&mut self, position: usize) {
+        let value = self.disk[position];
+        if !self.is_transfer_enabled() {
+            self.is_gap_ended = false;
+            self.crc = 0;
+            return;
+        }
+        self.crc = update_crc(self.crc, value);
+        if self.is_crc_control() {
+            self.is_crc_error = self.crc != 0;
+        }
+        if !self.is_gap_ended {
+            // The gap end mark itself is not transferred
+            self.is_gap_ended = value != 0x00;
+            return;
+        }
+        self.reg_read_data = value;
+        self.complete_transfer();
+    }
+
+    fn write_byte(&mut self, position: usize) {
+        self.is_gap_ended = false;
+        let value = if !self.is_transfer_enabled() {
+            self.crc = 0;
+            0x00
+        } else if self.is_crc_control() {
+            // The checksum is shifted out after the block
+            let value = self.crc as u8;
+            self.crc >>= 8;
+            value
+        } else {
+            self.crc = update_crc(self.crc, self.reg_write_data);
+            self.reg_write_data
+        };
+        if !self.is_crc_control() {
+            self.complete_transfer();
+        }
+        self.disk[position] = value;
+    }
+
+    fn complete_transfer(&mut self) {
+        self.is_transfer_complete.set(true);
+        if self.is_disk_irq_enabled() {
+            self.is_disk_irq_pending.set(true);
+        }
+    }
+
+    fn write_disk_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4020 => self.reg_irq_reload = (self.reg_irq_reload & 0xff00) | value as u16,
+            0x4021 => self.reg_irq_reload = (self.reg_irq_reload & 0x00ff) | ((value as u16) << 8),
+            0x4022 => {
+                self.is_irq_repeated = (value & 0x01) != 0;
+                self.is_irq_enabled = (value & 0x02) != 0;
+                if self.is_irq_enabled {
+                    self.irq_counter = self.reg_irq_reload;
+                } else {
+                    self.is_timer_irq_pending.set(false);
+                }
+            }
+            0x4024 => {
+                self.reg_write_data = value;
+                self.is_transfer_complete.set(false);
+                self.is_disk_irq_pending.set(false);
+            }
+            0x4025 => {
+                self.reg_control = value;
+                self.is_motor_on = (value & 0x01) != 0;
+                self.is_disk_irq_pending.set(false);
+            }
+            0x4026 => self.reg_external = value,
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for FDS {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x4030 if self.is_disk_io_enabled => self.read_status(),
+            0x4031 if self.is_disk_io_enabled => {
+                self.is_transfer_complete.set(false);
+                self.is_disk_irq_pending.set(false);
+                self.reg_read_data
+            }
+            0x4032 if self.is_disk_io_enabled => self.read_drive_status(),
+            // The battery is always good
+            0x4033 if self.is_disk_io_enabled => 0x80 | (self.reg_external & 0x7f),
+            0x4040..=0x4097 if self.is_sound_io_enabled => match self.audio.read(addr) {
+                Some(value) => value,
+                None => (addr >> 8) as u8,
+            },
+            // Open bus
+            0x4020..=0x5fff => (addr >> 8) as u8,
+            0x6000..=0xdfff => self.prg_ram[(addr - 0x6000) as usize],
+            0xe000..=0xffff => self.bios[(addr & 0x1fff) as usize],
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4023 => {
+                self.is_disk_io_enabled = (value & 0x01) != 0;
+                self.is_sound_io_enabled = (value & 0x02) != 0;
+                if !self.is_disk_io_enabled {
+                    self.is_irq_enabled = false;
+                    self.is_timer_irq_pending.set(false);
+                    self.is_disk_irq_pending.set(false);
+                }
+            }
+            0x4020..=0x4026 if self.is_disk_io_enabled => self.write_disk_register(addr, value),
+            0x4040..=0x4097 if self.is_sound_io_enabled => self.audio.write(addr, value),
+            0x4020..=0x5fff => {}
+            0x6000..=0xdfff => self.prg_ram[(addr - 0x6000) as usize] = value,
+            0xe000..=0xffff => {}
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr_ram[addr as usize]
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr_ram[addr as usize] = value;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if (self.reg_control & 0x08) != 0 {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn is_irq_asserted(&self) -> bool {
+        self.is_timer_irq_pending.get() || self.is_disk_irq_pending.get()
+    }
+
+    fn tick(&mut self) {
+        self.tick_timer();
+        self.tick_drive();
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    fn disk_side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    fn insert_disk(&mut self, side: Option<usize>) {
+        self.inserted_side = side.filter(|&side| side < self.sides.len());
+    }
+
+    // Disk writes, the original image is never modified
+    fn battery_ram(&self) -> Option<&[u8]> {
+        Some(&self.disk)
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.disk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_mapper() -> FDS {
+        let mut side = vec![0; DISK_SIDE_SIZE];
+        side[..15].copy_from_slice(b"\x01*NINTENDO-HVC*");
+        side[56..58].copy_from_slice(&[0x02, 0x01]);
+        // A 2 byte file
+        side[58] = 0x03;
+        side[58 + 13] = 0x02;
+        side[74..77].copy_from_slice(&[0x04, 0xaa, 0x55]);
+        let mut bios = vec![0; 0x2000];
+        bios[0x1ffc] = 0x24;
+        FDS::new(bios, vec![side.clone(), side])
+    }
+
+    // Waits for the next byte with the given control value, returns the byte read
+    fn transfer(mapper: &mut FDS, control: u8) -> u8 {
+        mapper.write_prg(0x4025, control);
+        while (mapper.read_prg(0x4030) & 0x02) == 0 {
+            mapper.tick();
+        }
+        mapper.read_prg(0x4031)
+    }
+
+    #[test]
+    fn test_layout() {
+        let mapper = test_mapper();
+        assert_eq!(2, mapper.disk_side_count());
+        let side = &mapper.disk[mapper.sides[0].clone()];
+        assert_eq!(SIDE_LENGTH, side.len());
+        assert!(side[..LEADING_GAP].iter().all(|&value| value == 0));
+        assert_eq!(&[0x80, 0x01], &side[LEADING_GAP..LEADING_GAP + 2]);
+        // The CRC bytes are followed by the gap and the file count block
+        let crc_end = LEADING_GAP + 1 + 56 + 2;
+        let crc = side[LEADING_GAP..crc_end]
+            .iter()
+            .fold(0, |crc, &value| update_crc(crc, value));
+        assert_eq!(0, crc);
+        assert_eq!(&[0x80, 0x02, 0x01], &side[crc_end + BLOCK_GAP..][..3]);
+        let file_data = side
+            .windows(4)
+            .position(|window| window == [0x80, 0x04, 0xaa, 0x55]);
+        assert!(file_data.is_some());
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut mapper = test_mapper();
+        assert_eq!(0x24, mapper.read_prg(0xfffc));
+        mapper.write_prg(0x6000, 0x42);
+        mapper.write_prg(0xdfff, 0x43);
+        assert_eq!(0x42, mapper.read_prg(0x6000));
+        assert_eq!(0x43, mapper.read_prg(0xdfff));
+
+        // Disk registers are ignored until enabled
+        mapper.write_prg(0x4025, 0x08);
+        assert_eq!(Mirroring::Vertical, mapper.mirroring());
+        mapper.write_prg(0x4023, 0x01);
+        mapper.write_prg(0x4025, 0x08);
+        assert_eq!(Mirroring::Horizontal, mapper.mirroring());
+        assert_eq!(0x42, mapper.read_prg(0x4032));
+        mapper.insert_disk(None);
+        assert_eq!(0x47, mapper.read_prg(0x4032));
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut mapper = test_mapper();
+        mapper.write_prg(0x4023, 0x01);
+        mapper.write_prg(0x4020, 0x02);
+        mapper.write_prg(0x4021, 0x00);
+        mapper.write_prg(0x4022, 0x03);
+        for _ in 0..2 {
+            mapper.tick();
+            assert!(!mapper.is_irq_asserted());
+        }
+        mapper.tick();
+        assert!(mapper.is_irq_asserted());
+        assert_eq!(0x01, mapper.read_prg(0x4030) & 0x01);
+        assert!(!mapper.is_irq_asserted());
+        // Repeats with the reload value
+        for _ in 0..3 {
+            mapper.tick();
+        }
+        assert!(mapper.is_irq_asserted());
+    }
+
+    #[test]
+    fn test_disk_read_write() {
+        let mut mapper = test_mapper();
+        mapper.write_prg(0x4023, 0x01);
+        // Motor on, read mode, wait for the first block
+        assert_eq!(0x01, transfer(&mut mapper, 0x45));
+        assert_eq!(0, mapper.read_prg(0x4032) & 0x02);
+        for &value in b"*NINTENDO-HVC*" {
+            assert_eq!(value, transfer(&mut mapper, 0x45));
+        }
+        for _ in 0..41 {
+            transfer(&mut mapper, 0x45);
+        }
+        transfer(&mut mapper, 0x45);
+        transfer(&mut mapper, 0x55);
+        assert_eq!(0, mapper.read_prg(0x4030) & 0x10);
+
+        // Rewrite the file count block after the gap
+        for _ in 0..BLOCK_GAP - 8 {
+            transfer(&mut mapper, 0x45);
+        }
+        mapper.write_prg(0x4024, 0x00);
+        for _ in 0..4 {
+            transfer(&mut mapper, 0x01);
+        }
+        for value in [0x80, 0x02, 0x07].iter().copied() {
+            mapper.write_prg(0x4024, value);
+            transfer(&mut mapper, 0x41);
+        }
+        // No transfers are signaled while the CRC is written
+        mapper.write_prg(0x4025, 0x51);
+        for _ in 0..BYTE_CYCLES * 2 {
+            mapper.tick();
+        }
+
+        let side = &mapper.disk[mapper.sides[0].clone()];
+        let start = side
+            .windows(3)
+            .position(|window| window == [0x80, 0x02, 0x07])
+            .expect("The block was not written");
+        let crc = side[start..start + 5]
+            .iter()
+            .fold(0, |crc, &value| update_crc(crc, value));
+        assert_eq!(0, crc);
+        // The other side is untouched
+        let other_side = &mapper.disk[mapper.sides[1].clone()];
+        assert_eq!(&side[..start - 8], &other_side[..start - 8]);
+        assert_ne!(&side[start..start + 3], &other_side[start..start + 3]);
+    }
+}
@@ -0,0 +1,314 @@
+// FDS expansion audio, a single 64 step wavetable channel
+// with its pitch bent by a modulation unit
+
+use crate::apu::{ExpansionAudio, ExpansionChip};
+
+// Mod table entries, 4 resets the counter
+const MOD_STEPS: [i32; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+// Master volume 2/2, 2/3, 2/4 and 2/5
+const WAVE_VOLUMES: [u32; 4] = [36, 24, 17, 14];
+
+// Shared by the volume and the mod depth
+struct Envelope {
+    speed: u8,
+    is_increasing: bool,
+    is_disabled: bool,
+    gain: u8,
+    timer: u32,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            speed: 0,
+            is_increasing: false,
+            is_disabled: true,
+            gain: 0,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, value: u8, master_speed: u8) {
+        self.speed = value & 0x3f;
+        self.is_increasing = (value & 0x40) != 0;
+        self.is_disabled = (value & 0x80) != 0;
+        if self.is_disabled {
+            self.gain = self.speed;
+        }
+        self.reset_timer(master_speed);
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    fn tick(&mut self, master_speed: u8) {
+        if self.is_disabled || master_speed == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.reset_timer(master_speed);
+            if self.is_increasing && self.gain < 32 {
+                self.gain += 1;
+            } else if !self.is_increasing && self.gain > 0 {
+                self.gain -= 1;
+            }
+        }
+    }
+}
+
+struct Modulator {
+    table: [u8; 64],
+    position: usize,
+    // 7 bit signed
+    counter: i32,
+    frequency: u16,
+    accumulator: u32,
+    is_halted: bool,
+    envelope: Envelope,
+}
+
+impl Modulator {
+    fn new() -> Self {
+        Modulator {
+            table: [0; 64],
+            position: 0,
+            counter: 0,
+            frequency: 0,
+            accumulator: 0,
+            is_halted: true,
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn set_counter(&mut self, value: i32) {
+        self.counter = ((value + 64) & 0x7f) - 64;
+    }
+
+    // Entries take two steps of the table and can only be written while halted
+    fn write_table(&mut self, value: u8) {
+        if self.is_halted {
+            self.table[self.position] = value & 0x07;
+            self.table[self.position + 1] = value & 0x07;
+            self.position = (self.position + 2) & 0x3f;
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.is_halted || self.frequency == 0 {
+            return;
+        }
+        self.accumulator += self.frequency as u32;
+        if self.accumulator >= 0x10000 {
+            self.accumulator &= 0xffff;
+            match self.table[self.position] {
+                4 => self.set_counter(0),
+                step => self.set_counter(self.counter + MOD_STEPS[step as usize]),
+            }
+            self.position = (self.position + 1) & 0x3f;
+        }
+    }
+
+    // Pitch offset for the wave frequency, with the odd rounding of the hardware
+    fn pitch_offset(&self, frequency: u16) -> i32 {
+        let product = self.counter * self.envelope.gain as i32;
+        let mut offset = product >> 4;
+        if (product & 0x0f) != 0 && (offset & 0x80) == 0 {
+            offset += if self.counter < 0 { -1 } else { 2 };
+        }
+        if offset >= 192 {
+            offset -= 256;
+        } else if offset < -64 {
+            offset += 256;
+        }
+        let product = frequency as i32 * offset;
+        (product >> 6) + ((product & 0x3f) >= 32) as i32
+    }
+}
+
+pub struct FdsAudio {
+    wave: [u8; 64],
+    wave_position: usize,
+    wave_accumulator: u32,
+    frequency: u16,
+    volume: Envelope,
+    modulator: Modulator,
+    master_volume: usize,
+    // $408a, scales the period of both envelopes
+    envelope_speed: u8,
+    is_wave_halted: bool,
+    // The output holds its last level while the wavetable is writable
+    is_wave_writable: bool,
+    are_envelopes_halted: bool,
+    output: u8,
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        FdsAudio {
+            wave: [0; 64],
+            wave_position: 0,
+            wave_accumulator: 0,
+            frequency: 0,
+            volume: Envelope::new(),
+            modulator: Modulator::new(),
+            master_volume: 0,
+            envelope_speed: 0xe8,
+            is_wave_halted: true,
+            is_wave_writable: false,
+            are_envelopes_halted: false,
+            output: 0,
+        }
+    }
+
+    // 0x4040..0x4097
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        // The upper bits are open bus
+        let open_bus = (addr >> 8) as u8 & 0xc0;
+        match addr {
+            0x4040..=0x407f if self.is_wave_writable => {
+                Some(open_bus | self.wave[(addr & 0x3f) as usize])
+            }
+            0x4040..=0x407f => Some(open_bus | self.wave[self.wave_position]),
+            0x4090 => Some(open_bus | self.volume.gain),
+            0x4092 => Some(open_bus | self.modulator.envelope.gain),
+            _ => None,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4040..=0x407f if self.is_wave_writable => {
+                self.wave[(addr & 0x3f) as usize] = value & 0x3f
+            }
+            0x4080 => self.volume.write(value, self.envelope_speed),
+            0x4082 => self.frequency = (self.frequency & 0x0f00) | value as u16,
+            0x4083 => {
+                self.frequency = (self.frequency & 0x00ff) | ((value as u16 & 0x0f) << 8);
+                self.is_wave_halted = (value & 0x80) != 0;
+                self.are_envelopes_halted = (value & 0x40) != 0;
+                if self.is_wave_halted {
+                    self.wave_position = 0;
+                    self.wave_accumulator = 0;
+                }
+                if self.are_envelopes_halted {
+                    self.volume.reset_timer(self.envelope_speed);
+                    self.modulator.envelope.reset_timer(self.envelope_speed);
+                }
+            }
+            0x4084 => self.modulator.envelope.write(value, self.envelope_speed),
+            0x4085 => self.modulator.set_counter((value & 0x7f) as i32),
+            0x4086 => self.modulator.frequency = (self.modulator.frequency & 0x0f00) | value as u16,
+            0x4087 => {
+                self.modulator.frequency =
+                    (self.modulator.frequency & 0x00ff) | ((value as u16 & 0x0f) << 8);
+                self.modulator.is_halted = (value & 0x80) != 0;
+                if self.modulator.is_halted {
+                    self.modulator.accumulator = 0;
+                }
+            }
+            0x4088 => self.modulator.write_table(value),
+            0x4089 => {
+                self.master_volume = (value & 0x03) as usize;
+                self.is_wave_writable = (value & 0x80) != 0;
+            }
+            0x408a => self.envelope_speed = value,
+            _ => {}
+        }
+    }
+}
+
+impl ExpansionAudio for FdsAudio {
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::FDS
+    }
+
+    fn tick_audio(&mut self) {
+        if !self.is_wave_halted && !self.are_envelopes_halted {
+            self.volume.tick(self.envelope_speed);
+            self.modulator.envelope.tick(self.envelope_speed);
+        }
+        self.modulator.tick();
+        if self.is_wave_writable {
+            return;
+        }
+        if !self.is_wave_halted {
+            let pitch = self.frequency as i32 + self.modulator.pitch_offset(self.frequency);
+            if pitch > 0 {
+                self.wave_accumulator += pitch as u32;
+                if self.wave_accumulator >= 0x10000 {
+                    self.wave_accumulator &= 0xffff;
+                    self.wave_position = (self.wave_position + 1) & 0x3f;
+                }
+            }
+        }
+        let level = self.volume.gain.min(32) as u32 * WAVE_VOLUMES[self.master_volume];
+        self.output = (self.wave[self.wave_position] as u32 * level / 1152) as u8;
+    }
+
+    // 0..=63
+    fn audio_output(&self) -> i32 {
+        self.output as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wave() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4089, 0x80);
+        for i in 0..64 {
+            audio.write(0x4040 + i, if i < 32 { 63 } else { 0 });
+        }
+        audio.write(0x4089, 0x00);
+        // Full volume without the envelope
+        audio.write(0x4080, 0xa0);
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x04);
+        assert_eq!(Some(0x40 | 32), audio.read(0x4090));
+
+        // 0x400 per cycle, 64 cycles per step
+        let outputs: Vec<i32> = (0..64 * 64)
+            .map(|_| {
+                audio.tick_audio();
+                audio.audio_output()
+            })
+            .collect();
+        assert_eq!(
+            32 * 64,
+            outputs.iter().filter(|&&output| output == 63).count()
+        );
+        assert_eq!(
+            32 * 64,
+            outputs.iter().filter(|&&output| output == 0).count()
+        );
+
+        audio.write(0x4083, 0x80);
+        audio.tick_audio();
+        assert_eq!(63, audio.audio_output());
+    }
+
+    #[test]
+    fn test_modulation() {
+        let mut audio = FdsAudio::new();
+        for _ in 0..32 {
+            audio.write(0x4088, 0x01);
+        }
+        audio.write(0x4084, 0x80 | 0x20);
+        audio.write(0x4086, 0x00);
+        audio.write(0x4087, 0x08);
+        // Each mod step raises the counter by 1
+        for _ in 0..32 {
+            audio.tick_audio();
+        }
+        assert_eq!(1, audio.modulator.counter);
+        assert_eq!(32, audio.modulator.pitch_offset(0x400));
+        audio.write(0x4085, 0x7f);
+        assert_eq!(-1, audio.modulator.counter);
+        assert_eq!(-32, audio.modulator.pitch_offset(0x400));
+    }
+}
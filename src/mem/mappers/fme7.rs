@@ -0,0 +1,476 @@
+use crate::{
+    apu::{ExpansionAudio, ExpansionChip},
+    rom::nes::Mirroring,
+};
+
+use super::{Banks, ChrMemory, Mapper};
+
+// Output of the 32 envelope levels, 1.5dB apart
+const VOLUMES: [u16; 32] = [
+    0, 1, 2, 2, 2, 3, 3, 4, 5, 6, 7, 8, 10, 11, 14, 16, 19, 23, 27, 32, 38, 45, 54, 64, 76, 90,
+    108, 128, 152, 181, 215, 255,
+];
+
+struct SquareChannel {
+    period: u16,
+    timer: u16,
+    is_high: bool,
+}
+
+impl SquareChannel {
+    fn new() -> Self {
+        SquareChannel {
+            period: 0,
+            timer: 0,
+            is_high: false,
+        }
+    }
+
+    fn tick(&mut self) {
+        self.timer += 1;
+        if self.timer >= self.period.max(1) {
+            self.timer = 0;
+            self.is_high = !self.is_high;
+        }
+    }
+}
+
+struct Envelope {
+    period: u16,
+    timer: u16,
+    shape: u8,
+    step: u8,
+    is_attack: bool,
+    is_holding: bool,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            period: 0,
+            timer: 0,
+            shape: 0,
+            step: 0,
+            is_attack: false,
+            is_holding: false,
+        }
+    }
+
+    // Writing the shape restarts the envelope
+    fn write_shape(&mut self, value: u8) {
+        self.shape = value & 0x0f;
+        self.timer = 0;
+        self.step = 0;
+        self.is_attack = (value & 0x04) != 0;
+        self.is_holding = false;
+    }
+
+    fn tick(&mut self) {
+        self.timer += 1;
+        if self.timer < self.period.max(1) {
+            return;
+        }
+        self.timer = 0;
+        if self.is_holding {
+            return;
+        }
+        self.step += 1;
+        if self.step < 32 {
+            return;
+        }
+        let is_continue = (self.shape & 0x08) != 0;
+        let is_alternate = (self.shape & 0x02) != 0;
+        let is_hold = (self.shape & 0x01) != 0;
+        if !is_continue {
+            // Drops to silence after a single ramp
+            self.is_holding = true;
+            self.is_attack = false;
+            self.step = 31;
+        } else if is_hold {
+            self.is_holding = true;
+            self.is_attack ^= is_alternate;
+            self.step = 31;
+        } else {
+            self.is_attack ^= is_alternate;
+            self.step = 0;
+        }
+    }
+
+    fn level(&self) -> u8 {
+        if self.is_attack {
+            self.step
+        } else {
+            31 - self.step
+        }
+    }
+}
+
+// The AY-3-8910 derived audio of the Sunsoft 5B
+struct Sunsoft5bAudio {
+    squares: [SquareChannel; 3],
+    envelope: Envelope,
+    noise_period: u8,
+    noise_timer: u8,
+    // 17-bit LFSR
+    noise_shift: u32,
+    reg_mixer: u8,
+    reg_volumes: [u8; 3],
+    cycle: u8,
+}
+
+impl Sunsoft5bAudio {
+    fn new() -> Self {
+        Sunsoft5bAudio {
+            squares: [
+                SquareChannel::new(),
+                SquareChannel::new(),
+                SquareChannel::new(),
+            ],
+            envelope: Envelope::new(),
+            noise_period: 0,
+            noise_timer: 0,
+            noise_shift: 1,
+            reg_mixer: 0,
+            reg_volumes: [0; 3],
+            cycle: 0,
+        }
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        match reg {
+            0x0..=0x5 => {
+                let square = &mut self.squares[(reg >> 1) as usize];
+                if (reg & 0x01) == 0 {
+                    square.period = (square.period & 0x0f00) | value as u16;
+                } else {
+                    square.period = (square.period & 0x00ff) | ((value as u16 & 0x0f) << 8);
+                }
+            }
+            0x6 => self.noise_period = value & 0x1f,
+            0x7 => self.reg_mixer = value,
+            0x8..=0xa => self.reg_volumes[(reg - 0x8) as usize] = value & 0x1f,
+            0xb => self.envelope.period = (self.envelope.period & 0xff00) | value as u16,
+            0xc => self.envelope.period = (self.envelope.period & 0x00ff) | ((value as u16) << 8),
+            0xd => self.envelope.write_shape(value),
+            _ => {}
+        }
+    }
+}
+
+impl ExpansionAudio for Sunsoft5bAudio {
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::Sunsoft5B
+    }
+
+    // The envelope is clocked every 8 CPU cycles, the squares every 16 and the noise every 32
+    fn tick_audio(&mut self) {
+        self.cycle = (self.cycle + 1) & 0x1f;
+        if (self.cycle & 0x07) != 0 {
+            return;
+        }
+        self.envelope.tick();
+        if (self.cycle & 0x0f) != 0 {
+            return;
+        }
+        self.squares.iter_mut().for_each(SquareChannel::tick);
+        if self.cycle != 0 {
+            return;
+        }
+        self.noise_timer += 1;
+        if self.noise_timer >= self.noise_period.max(1) {
+            self.noise_timer = 0;
+            let bit = (self.noise_shift ^ (self.noise_shift >> 3)) & 0x01;
+            self.noise_shift = (self.noise_shift >> 1) | (bit << 16);
+        }
+    }
+
+    fn audio_output(&self) -> i32 {
+        let is_noise_high = (self.noise_shift & 0x01) != 0;
+        let mut output = 0;
+        for (channel, square) in self.squares.iter().enumerate() {
+            // Disabled sources read as high, so a channel with both disabled outputs its volume
+            let is_tone_disabled = (self.reg_mixer & (0x01 << channel)) != 0;
+            let is_noise_disabled = (self.reg_mixer & (0x08 << channel)) != 0;
+            if !(square.is_high || is_tone_disabled) || !(is_noise_high || is_noise_disabled) {
+                continue;
+            }
+            let volume = self.reg_volumes[channel];
+            // The 4-bit volume register uses every other envelope level
+            let level = if (volume & 0x10) != 0 {
+                self.envelope.level()
+            } else if (volume & 0x0f) == 0 {
+                0
+            } else {
+                ((volume & 0x0f) << 1) | 0x01
+            };
+            output += VOLUMES[level as usize] as i32;
+        }
+        output
+    }
+}
+
+// iNES mapper 69 (Sunsoft FME-7 and 5B)
+#[allow(clippy::upper_case_acronyms)]
+pub struct FME7 {
+    // Window 0 is 0x6000..0x7fff, windows 1..4 are 0x8000..0xffff
+    prg_rom: Banks,
+    prg_ram: Vec<u8>,
+    chr: Banks,
+
+    // Internal registers
+    reg_command: u8,
+    reg_prg_ram: u8,
+    reg_mirroring: u8,
+    reg_audio: u8,
+    is_irq_enabled: bool,
+    is_irq_counter_enabled: bool,
+    is_irq_pending: bool,
+    irq_counter: u16,
+
+    audio: Sunsoft5bAudio,
+}
+
+impl FME7 {
+    pub fn new(prg_rom: Vec<u8>, prg_ram_size: usize, chr: ChrMemory) -> Self {
+        let mut prg_rom = Banks::new(prg_rom, 0x2000, 5);
+        prg_rom.set(4, prg_rom.bank_count() - 1);
+        FME7 {
+            prg_rom,
+            prg_ram: vec![0x00; prg_ram_size],
+            chr: Banks::new_chr(chr, 0x0400, 8),
+            reg_command: 0,
+            reg_prg_ram: 0,
+            reg_mirroring: 0,
+            reg_audio: 0,
+            is_irq_enabled: false,
+            is_irq_counter_enabled: false,
+            is_irq_pending: false,
+            irq_counter: 0,
+            audio: Sunsoft5bAudio::new(),
+        }
+    }
+
+    // Bit 6 selects RAM instead of ROM at 0x6000, bit 7 enables it
+    fn is_prg_ram_selected(&self) -> bool {
+        (self.reg_prg_ram & 0x40) != 0
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        self.is_prg_ram_selected() && (self.reg_prg_ram & 0x80) != 0 && !self.prg_ram.is_empty()
+    }
+
+    fn write_parameter(&mut self, value: u8) {
+        match self.reg_command & 0x0f {
+            window @ 0x0..=0x7 => self.chr.set(window as usize, value as usize),
+            0x8 => {
+                self.reg_prg_ram = value;
+                self.prg_rom.set(0, (value & 0x3f) as usize);
+            }
+            window @ 0x9..=0xb => self
+                .prg_rom
+                .set((window - 0x8) as usize, (value & 0x3f) as usize),
+            0xc => self.reg_mirroring = value,
+            // Writing the control acknowledges the IRQ
+            0xd => {
+                self.is_irq_enabled = (value & 0x01) != 0;
+                self.is_irq_counter_enabled = (value & 0x80) != 0;
+                self.is_irq_pending = false;
+            }
+            0xe => self.irq_counter = (self.irq_counter & 0xff00) | value as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00ff) | ((value as u16) << 8),
+        }
+    }
+}
+
+impl Mapper for FME7 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr >> 12 {
+            0x6 | 0x7 if self.is_prg_ram_enabled() => {
+                self.prg_ram[(addr as usize & 0x1fff) % self.prg_ram.len()]
+            }
+            0x6 | 0x7 if !self.is_prg_ram_selected() => self.prg_rom.read((addr - 0x6000) as usize),
+            // Open bus
+            0x4..=0x7 => (addr >> 8) as u8,
+            0x8..=0xf => self.prg_rom.read((addr - 0x6000) as usize),
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        match addr >> 12 {
+            0x6 | 0x7 if self.is_prg_ram_enabled() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize & 0x1fff) % len] = value;
+            }
+            0x4..=0x7 => {}
+            0x8 | 0x9 => self.reg_command = value,
+            0xa | 0xb => self.write_parameter(value),
+            0xc | 0xd => self.reg_audio = value,
+            0xe | 0xf => self.audio.write(self.reg_audio & 0x0f, value),
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.reg_mirroring & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenA,
+            _ => Mirroring::SingleScreenB,
+        }
+    }
+
+    fn is_irq_asserted(&self) -> bool {
+        self.is_irq_pending
+    }
+
+    fn tick(&mut self) {
+        // The IRQ fires when the counter wraps from 0 to 0xffff
+        if self.is_irq_counter_enabled {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xffff && self.is_irq_enabled {
+                self.is_irq_pending = true;
+            }
+        }
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mem::mappers::{get_mapper, numbered_banks},
+        rom::nes::NESFile,
+    };
+
+    fn test_mapper() -> FME7 {
+        let prg_rom = numbered_banks(0x40000, 0x2000);
+        let chr = numbered_banks(0x40000, 0x0400);
+        FME7::new(prg_rom, 0x2000, ChrMemory::rom(chr))
+    }
+
+    fn write_registers(mapper: &mut FME7, port: u16, writes: &[(u8, u8)]) {
+        for (reg, value) in writes.iter().copied() {
+            mapper.write_prg(port, reg);
+            mapper.write_prg(port + 0x2000, value);
+        }
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut mapper = test_mapper();
+        write_registers(
+            &mut mapper,
+            0x8000,
+            &[
+                (0x8, 0x02),
+                (0x9, 0x03),
+                (0xa, 0x04),
+                (0xb, 0x05),
+                (0x7, 0x42),
+            ],
+        );
+        assert_eq!(2, mapper.read_prg(0x6000));
+        assert_eq!(3, mapper.read_prg(0x8000));
+        assert_eq!(4, mapper.read_prg(0xa000));
+        assert_eq!(5, mapper.read_prg(0xc000));
+        assert_eq!(31, mapper.read_prg(0xe000));
+        assert_eq!(0x42, mapper.read_chr(0x1c00));
+
+        // RAM selected but disabled is open bus
+        write_registers(&mut mapper, 0x8000, &[(0x8, 0x40), (0xc, 0x03)]);
+        assert_eq!(0x60, mapper.read_prg(0x6000));
+        write_registers(&mut mapper, 0x8000, &[(0x8, 0xc0)]);
+        mapper.write_prg(0x6000, 0x55);
+        assert_eq!(0x55, mapper.read_prg(0x6000));
+        assert_eq!(Mirroring::SingleScreenB, mapper.mirroring());
+    }
+
+    #[test]
+    fn test_trainer() {
+        let mut data = vec![0; 16 + 0x200 + 0x8000 + 0x2000];
+        data[0..4].copy_from_slice(b"NES\x1a");
+        data[4] = 0x02;
+        data[5] = 0x01;
+        data[6] = 0x54; // Mapper 69, trainer
+        data[7] = 0x40;
+        data[16 + 0x1ff] = 0x42;
+        let rom = NESFile::load(&data).expect("Failed to load the ROM");
+        let mut mapper = get_mapper(rom).expect("Failed to create the mapper");
+        // The RAM is disabled at power-on, the trainer is loaded anyway
+        mapper.write_prg(0x8000, 0x8);
+        mapper.write_prg(0xa000, 0xc0);
+        assert_eq!(0x42, mapper.read_prg(0x71ff));
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut mapper = test_mapper();
+        write_registers(
+            &mut mapper,
+            0x8000,
+            &[(0xe, 0x01), (0xf, 0x00), (0xd, 0x81)],
+        );
+        mapper.tick();
+        assert!(!mapper.is_irq_asserted());
+        mapper.tick();
+        assert!(mapper.is_irq_asserted());
+        write_registers(&mut mapper, 0x8000, &[(0xd, 0x80)]);
+        assert!(!mapper.is_irq_asserted());
+    }
+
+    #[test]
+    fn test_audio() {
+        let collect = |mapper: &mut FME7, cycles: usize| {
+            (0..cycles)
+                .map(|_| {
+                    mapper.audio.tick_audio();
+                    mapper.audio.audio_output()
+                })
+                .collect::<Vec<i32>>()
+        };
+        // Channel A at full volume toggles every 2 * 16 cycles
+        let mut mapper = test_mapper();
+        write_registers(
+            &mut mapper,
+            0xc000,
+            &[(0x0, 0x02), (0x7, 0x3e), (0x8, 0x0f)],
+        );
+        let outputs = collect(&mut mapper, 64);
+        assert_eq!(32, outputs.iter().filter(|&&output| output == 255).count());
+
+        // A decaying envelope without continue ends silent
+        write_registers(
+            &mut mapper,
+            0xc000,
+            &[(0x7, 0x3f), (0x8, 0x10), (0xb, 0x01), (0xd, 0x00)],
+        );
+        assert_eq!(255, mapper.audio.audio_output());
+        collect(&mut mapper, 8 * 32);
+        assert_eq!(0, mapper.audio.audio_output());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::rom::nes::{Mirroring, NESHeader};
+
+use super::{Banks, ChrMemory, Mapper};
+
+// Boards differ in how the unused high bits of the CHR bank registers are wired
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MMC1Board {
+    // SKROM, SLROM and other boards without extra wiring
+    Generic,
+    // CHR bit 4 disables the PRG RAM
+    SNROM,
+    // CHR bit 3 selects the 8K PRG RAM bank
+    SOROM,
+    // CHR bit 4 selects the 256K PRG ROM bank
+    SUROM,
+    // CHR bit 4 selects the 256K PRG ROM bank, bits 2..3 select the 8K PRG RAM bank
+    SXROM,
+}
+
+impl MMC1Board {
+    pub fn detect(header: &NESHeader) -> Self {
+        if header.prg_rom_size > 0x40000 {
+            if header.total_prg_ram_size() >= 0x8000 {
+                MMC1Board::SXROM
+            } else {
+                MMC1Board::SUROM
+            }
+        } else if header.total_prg_ram_size() == 0x4000 {
+            MMC1Board::SOROM
+        } else if header.chr_rom_size == 0 {
+            MMC1Board::SNROM
+        } else {
+            MMC1Board::Generic
+        }
+    }
+
+    pub fn prg_ram_size(&self) -> usize {
+        match self {
+            MMC1Board::SOROM => 0x4000,
+            MMC1Board::SXROM => 0x8000,
+            _ => 0x2000,
+        }
+    }
+}
+
+// iNES mapper 1
+#[allow(clippy::upper_case_acronyms)]
+pub struct MMC1 {
+    board: MMC1Board,
+    prg_rom: Banks,
+    prg_ram: Banks,
+    chr: Banks,
+
+    // Serial port, filled LSB first. The marker bit reaching bit 0 means the fifth write.
+    shift: u8,
+
+    // Internal registers
+    reg_control: u8,
+    reg_chr_bank0: u8,
+    reg_chr_bank1: u8,
+    reg_prg_bank: u8,
+}
+
+impl MMC1 {
+    const SHIFT_RESET: u8 = 0x10;
+
+    pub fn new(board: MMC1Board, prg_rom: Vec<u8>, chr: ChrMemory) -> Self {
+        let mut mapper = MMC1 {
+            board,
+            prg_rom: Banks::new(prg_rom, 0x4000, 2),
+            prg_ram: Banks::new(vec![0x00; board.prg_ram_size()], 0x2000, 1),
+            chr: Banks::new_chr(chr, 0x1000, 2),
+            shift: Self::SHIFT_RESET,
+            reg_control: 0x0c,
+            reg_chr_bank0: 0,
+            reg_chr_bank1: 0,
+            reg_prg_bank: 0,
+        };
+        mapper.update_banks();
+        mapper
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        if self.board == MMC1Board::SNROM && (self.reg_chr_bank0 & 0x10) != 0 {
+            return false;
+        }
+        (self.reg_prg_bank & 0x10) == 0
+    }
+
+    fn current_mirroring(&self) -> Mirroring {
+        match self.reg_control & 0x03 {
+            0 => Mirroring::SingleScreenA,
+            1 => Mirroring::SingleScreenB,
+            2 => Mirroring::Vertical,
+            3 => Mirroring::Horizontal,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match (addr >> 13) & 0x03 {
+            0 => self.reg_control = value,
+            1 => self.reg_chr_bank0 = value,
+            2 => self.reg_chr_bank1 = value,
+            3 => self.reg_prg_bank = value,
+            _ => unreachable!(),
+        }
+        self.update_banks();
+    }
+
+    fn update_banks(&mut self) {
+        // NOTE: in the 4K CHR mode the board wiring follows whichever CHR
+        // register the PPU used last, this is approximated with the first one
+        let outer = self.reg_chr_bank0;
+        let prg_base = match self.board {
+            MMC1Board::SUROM | MMC1Board::SXROM => (outer & 0x10) as usize,
+            _ => 0,
+        };
+        let prg_bank = (self.reg_prg_bank & 0x0f) as usize;
+        match (self.reg_control >> 2) & 0x03 {
+            0 | 1 => {
+                // 32K mode, the lowest bit of the bank number is ignored
+                self.prg_rom.set(0, prg_base | (prg_bank & !1));
+                self.prg_rom.set(1, prg_base | (prg_bank | 1));
+            }
+            2 => {
+                self.prg_rom.set(0, prg_base);
+                self.prg_rom.set(1, prg_base | prg_bank);
+            }
+            3 => {
+                self.prg_rom.set(0, prg_base | prg_bank);
+                self.prg_rom.set(1, prg_base | 0x0f);
+            }
+            _ => unreachable!(),
+        }
+
+        let ram_bank = match self.board {
+            MMC1Board::SOROM => (outer >> 3) & 0x01,
+            MMC1Board::SXROM => (outer >> 2) & 0x03,
+            _ => 0,
+        };
+        self.prg_ram.set(0, ram_bank as usize);
+
+        if (self.reg_control & 0x10) == 0 {
+            // 8K mode, the lowest bit of the bank number is ignored
+            let chr_bank = (self.reg_chr_bank0 & 0x1e) as usize;
+            self.chr.set(0, chr_bank);
+            self.chr.set(1, chr_bank | 1);
+        } else {
+            self.chr.set(0, (self.reg_chr_bank0 & 0x1f) as usize);
+            self.chr.set(1, (self.reg_chr_bank1 & 0x1f) as usize);
+        }
+    }
+}
+
+impl Mapper for MMC1 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr >> 12 {
+            0x6 | 0x7 if self.is_prg_ram_enabled() => self.prg_ram.read((addr & 0x1fff) as usize),
+            // Open bus
+            0x4..=0x7 => (addr >> 8) as u8,
+            0x8..=0xf => self.prg_rom.read((addr & 0x7fff) as usize),
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        match addr >> 12 {
+            0x6 | 0x7 if self.is_prg_ram_enabled() => {
+                self.prg_ram.write((addr & 0x1fff) as usize, value)
+            }
+            0x4..=0x7 => {}
+            0x8..=0xf => {
+                if (value & 0x80) != 0 {
+                    self.shift = Self::SHIFT_RESET;
+                    self.reg_control |= 0x0c;
+                    self.update_banks();
+                    return;
+                }
+                let is_complete = (self.shift & 0x01) != 0;
+                self.shift = (self.shift >> 1) | ((value & 0x01) << 4);
+                if is_complete {
+                    let value = self.shift;
+                    self.shift = Self::SHIFT_RESET;
+                    self.write_register(addr, value);
+                }
+            }
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.current_mirroring()
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(self.prg_ram.data_mut())
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        Some(self.prg_ram.data())
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(self.prg_ram.data_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::mappers::numbered_banks;
+
+    fn write_serial(mapper: &mut MMC1, addr: u16, value: u8) {
+        for i in 0..5 {
+            mapper.write_prg(addr, (value >> i) & 0x01);
+        }
+    }
+
+    fn test_mapper(board: MMC1Board, prg_banks: usize) -> MMC1 {
+        let prg_rom = numbered_banks(prg_banks * 0x4000, 0x4000);
+        let chr = numbered_banks(0x20000, 0x1000);
+        MMC1::new(board, prg_rom, ChrMemory::rom(chr))
+    }
+
+    #[test]
+    fn test_prg_modes() {
+        let mut mapper = test_mapper(MMC1Board::Generic, 8);
+        // Power-on state fixes the last bank at 0xc000
+        assert_eq!(0, mapper.read_prg(0x8000));
+        assert_eq!(7, mapper.read_prg(0xc000));
+        write_serial(&mut mapper, 0xe000, 3);
+        assert_eq!(3, mapper.read_prg(0x8000));
+        assert_eq!(7, mapper.read_prg(0xffff));
+        // Fix the first bank at 0x8000
+        write_serial(&mut mapper, 0x8000, 0x08);
+        assert_eq!(0, mapper.read_prg(0x8000));
+        assert_eq!(3, mapper.read_prg(0xc000));
+        // 32K mode ignores the lowest bit
+        write_serial(&mut mapper, 0x8000, 0x00);
+        assert_eq!(2, mapper.read_prg(0x8000));
+        assert_eq!(3, mapper.read_prg(0xc000));
+    }
+
+    #[test]
+    fn test_shift_reset() {
+        let mut mapper = test_mapper(MMC1Board::Generic, 8);
+        write_serial(&mut mapper, 0x8000, 0x08);
+        mapper.write_prg(0xe000, 0x01);
+        mapper.write_prg(0xe000, 0x01);
+        // Resetting discards the partial value and restores the PRG mode 3
+        mapper.write_prg(0x8000, 0x80);
+        write_serial(&mut mapper, 0xe000, 0x02);
+        assert_eq!(2, mapper.read_prg(0x8000));
+        assert_eq!(7, mapper.read_prg(0xc000));
+    }
+
+    #[test]
+    fn test_chr_modes() {
+        let mut mapper = test_mapper(MMC1Board::Generic, 2);
+        write_serial(&mut mapper, 0xa000, 5);
+        write_serial(&mut mapper, 0xc000, 9);
+        assert_eq!(4, mapper.read_chr(0x0000));
+        assert_eq!(5, mapper.read_chr(0x1000));
+        write_serial(&mut mapper, 0x8000, 0x1c);
+        assert_eq!(5, mapper.read_chr(0x0000));
+        assert_eq!(9, mapper.read_chr(0x1000));
+    }
+
+    #[test]
+    fn test_surom_outer_bank() {
+        let mut mapper = test_mapper(MMC1Board::SUROM, 32);
+        write_serial(&mut mapper, 0xe000, 1);
+        assert_eq!(1, mapper.read_prg(0x8000));
+        assert_eq!(15, mapper.read_prg(0xc000));
+        write_serial(&mut mapper, 0xa000, 0x10);
+        assert_eq!(17, mapper.read_prg(0x8000));
+        assert_eq!(31, mapper.read_prg(0xc000));
+    }
+}
@@ -0,0 +1,234 @@
+// MMC2 and MMC4 switch the CHR banks by themselves when the PPU fetches tiles 0xfd or 0xfe
+
+use crate::rom::nes::Mirroring;
+
+use super::{Banks, ChrMemory, Mapper};
+
+// CHR banking and mirroring, identical on both chips except for the latch 0 trigger
+struct ChrLatches {
+    chr: Banks,
+    mirroring: Mirroring,
+    // Latch 0 reacts to a single address instead of a range on the MMC2
+    is_latch0_exact: bool,
+
+    reg_chr_banks: [[u8; 2]; 2],
+    latches: [u8; 2],
+}
+
+impl ChrLatches {
+    fn new(chr: ChrMemory, is_latch0_exact: bool) -> Self {
+        let mut latches = ChrLatches {
+            chr: Banks::new_chr(chr, 0x1000, 2),
+            mirroring: Mirroring::Vertical,
+            is_latch0_exact,
+            reg_chr_banks: [[0; 2]; 2],
+            latches: [0xfe; 2],
+        };
+        latches.update_banks();
+        latches
+    }
+
+    fn update_banks(&mut self) {
+        for window in 0..2 {
+            let reg = (self.latches[window] == 0xfe) as usize;
+            let bank = self.reg_chr_banks[window][reg];
+            self.chr.set(window, bank as usize);
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr >> 12 {
+            0xb => self.reg_chr_banks[0][0] = value & 0x1f,
+            0xc => self.reg_chr_banks[0][1] = value & 0x1f,
+            0xd => self.reg_chr_banks[1][0] = value & 0x1f,
+            0xe => self.reg_chr_banks[1][1] = value & 0x1f,
+            0xf => {
+                self.mirroring = if (value & 0x01) == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                };
+            }
+            _ => {}
+        }
+        self.update_banks();
+    }
+
+    // The latch switches after the fetch, so the trigger tile itself uses the old bank
+    fn observe_ppu_addr(&mut self, addr: u16) {
+        let (window, latch) = match addr {
+            0x0fd8 => (0, 0xfd),
+            0x0fe8 => (0, 0xfe),
+            0x0fd9..=0x0fdf if !self.is_latch0_exact => (0, 0xfd),
+            0x0fe9..=0x0fef if !self.is_latch0_exact => (0, 0xfe),
+            0x1fd8..=0x1fdf => (1, 0xfd),
+            0x1fe8..=0x1fef => (1, 0xfe),
+            _ => return,
+        };
+        if self.latches[window] != latch {
+            self.latches[window] = latch;
+            self.update_banks();
+        }
+    }
+}
+
+// iNES mapper 9 (PxROM)
+#[allow(clippy::upper_case_acronyms)]
+pub struct MMC2 {
+    prg_rom: Banks,
+    chr: ChrLatches,
+}
+
+impl MMC2 {
+    pub fn new(prg_rom: Vec<u8>, chr: ChrMemory) -> Self {
+        let mut prg_rom = Banks::new(prg_rom, 0x2000, 4);
+        // The last three 8K banks are fixed
+        let last = prg_rom.bank_count() - 1;
+        for window in 1..4 {
+            prg_rom.set(window, (last + window).saturating_sub(3));
+        }
+        MMC2 {
+            prg_rom,
+            chr: ChrLatches::new(chr, true),
+        }
+    }
+}
+
+impl Mapper for MMC2 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr >> 12 {
+            // Open bus
+            0x4..=0x7 => (addr >> 8) as u8,
+            0x8..=0xf => self.prg_rom.read((addr & 0x7fff) as usize),
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        match addr >> 12 {
+            0xa => self.prg_rom.set(0, (value & 0x0f) as usize),
+            _ => self.chr.write_register(addr, value),
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.chr.mirroring
+    }
+
+    fn observe_ppu_addr(&mut self, addr: u16) {
+        self.chr.observe_ppu_addr(addr);
+    }
+}
+
+// iNES mapper 10 (FxROM)
+#[allow(clippy::upper_case_acronyms)]
+pub struct MMC4 {
+    prg_rom: Banks,
+    prg_ram: Vec<u8>,
+    chr: ChrLatches,
+}
+
+impl MMC4 {
+    pub fn new(prg_rom: Vec<u8>, chr: ChrMemory) -> Self {
+        let mut prg_rom = Banks::new(prg_rom, 0x4000, 2);
+        prg_rom.set(1, prg_rom.bank_count() - 1);
+        MMC4 {
+            prg_rom,
+            prg_ram: vec![0x00; 0x2000],
+            chr: ChrLatches::new(chr, false),
+        }
+    }
+}
+
+impl Mapper for MMC4 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr >> 12 {
+            0x6 | 0x7 => self.prg_ram[(addr & 0x1fff) as usize],
+            // Open bus
+            0x4 | 0x5 => (addr >> 8) as u8,
+            0x8..=0xf => self.prg_rom.read((addr & 0x7fff) as usize),
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        match addr >> 12 {
+            0x6 | 0x7 => self.prg_ram[(addr & 0x1fff) as usize] = value,
+            0xa => self.prg_rom.set(0, (value & 0x0f) as usize),
+            _ => self.chr.write_register(addr, value),
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.chr.mirroring
+    }
+
+    fn observe_ppu_addr(&mut self, addr: u16) {
+        self.chr.observe_ppu_addr(addr);
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::mappers::numbered_banks;
+
+    fn test_chr() -> ChrMemory {
+        ChrMemory::rom(numbered_banks(0x20000, 0x1000))
+    }
+
+    #[test]
+    fn test_chr_latches() {
+        let mut mapper = MMC2::new(vec![0; 0x20000], test_chr());
+        mapper.write_prg(0xb000, 1);
+        mapper.write_prg(0xc000, 2);
+        mapper.write_prg(0xd000, 3);
+        mapper.write_prg(0xe000, 4);
+        assert_eq!(2, mapper.read_chr(0x0000));
+        assert_eq!(4, mapper.read_chr(0x1000));
+
+        mapper.observe_ppu_addr(0x0fd8);
+        assert_eq!(1, mapper.read_chr(0x0000));
+        // Only the exact address triggers latch 0 on the MMC2
+        mapper.observe_ppu_addr(0x0fe9);
+        assert_eq!(1, mapper.read_chr(0x0000));
+        mapper.observe_ppu_addr(0x1fdf);
+        assert_eq!(3, mapper.read_chr(0x1000));
+
+        let mut mapper = MMC4::new(vec![0; 0x20000], test_chr());
+        mapper.write_prg(0xb000, 1);
+        mapper.write_prg(0xc000, 2);
+        mapper.observe_ppu_addr(0x0fdd);
+        assert_eq!(1, mapper.read_chr(0x0000));
+        mapper.observe_ppu_addr(0x0fef);
+        assert_eq!(2, mapper.read_chr(0x0000));
+    }
+}
@@ -0,0 +1,265 @@
+use crate::rom::nes::Mirroring;
+
+use super::{Banks, ChrMemory, Mapper};
+
+// iNES mapper 4 (TxROM)
+#[allow(clippy::upper_case_acronyms)]
+pub struct MMC3 {
+    prg_rom: Banks,
+    prg_ram: Vec<u8>,
+    chr: Banks,
+    mirroring: Mirroring,
+
+    // Internal registers
+    reg_bank_select: u8,
+    reg_banks: [u8; 8],
+    reg_prg_ram_protect: u8,
+
+    // Scanline counter
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    // PPU A12 edge detection
+    is_a12_high: bool,
+    a12_low_cycles: u8,
+}
+
+impl MMC3 {
+    // A12 has to stay low for this many CPU cycles for its rising edge to be counted
+    const A12_FILTER_CYCLES: u8 = 3;
+
+    pub fn new(prg_rom: Vec<u8>, chr: ChrMemory, mirroring: Mirroring) -> Self {
+        let mut mapper = MMC3 {
+            prg_rom: Banks::new(prg_rom, 0x2000, 4),
+            prg_ram: vec![0x00; 0x2000],
+            chr: Banks::new_chr(chr, 0x0400, 8),
+            mirroring,
+            reg_bank_select: 0,
+            reg_banks: [0, 2, 4, 5, 6, 7, 0, 1],
+            reg_prg_ram_protect: 0x80,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            is_a12_high: false,
+            a12_low_cycles: 0,
+        };
+        mapper.update_banks();
+        mapper
+    }
+
+    fn update_banks(&mut self) {
+        let second_last = self.prg_rom.bank_count().saturating_sub(2);
+        let r6 = self.reg_banks[6] as usize & 0x3f;
+        let r7 = self.reg_banks[7] as usize & 0x3f;
+        if (self.reg_bank_select & 0x40) == 0 {
+            self.prg_rom.set(0, r6);
+            self.prg_rom.set(2, second_last);
+        } else {
+            self.prg_rom.set(0, second_last);
+            self.prg_rom.set(2, r6);
+        }
+        self.prg_rom.set(1, r7);
+        self.prg_rom.set(3, second_last + 1);
+
+        // With the inversion bit set the 2K banks are mapped at 0x1000 instead of 0x0000
+        let base = if (self.reg_bank_select & 0x80) == 0 {
+            0
+        } else {
+            4
+        };
+        let r0 = self.reg_banks[0] as usize & 0xfe;
+        let r1 = self.reg_banks[1] as usize & 0xfe;
+        self.chr.set(base, r0);
+        self.chr.set(base + 1, r0 | 1);
+        self.chr.set(base + 2, r1);
+        self.chr.set(base + 3, r1 | 1);
+        for i in 0..4 {
+            self.chr.set((base ^ 4) | i, self.reg_banks[2 + i] as usize);
+        }
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        (self.reg_prg_ram_protect & 0x80) != 0
+    }
+
+    fn is_prg_ram_writable(&self) -> bool {
+        self.is_prg_ram_enabled() && (self.reg_prg_ram_protect & 0x40) == 0
+    }
+}
+
+impl Mapper for MMC3 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr >> 12 {
+            0x6 | 0x7 if self.is_prg_ram_enabled() => self.prg_ram[(addr & 0x1fff) as usize],
+            // Open bus
+            0x4..=0x7 => (addr >> 8) as u8,
+            0x8..=0xf => self.prg_rom.read((addr & 0x7fff) as usize),
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        match (addr >> 12, addr & 0x01) {
+            (0x6 | 0x7, _) if self.is_prg_ram_writable() => {
+                self.prg_ram[(addr & 0x1fff) as usize] = value
+            }
+            (0x4..=0x7, _) => {}
+            (0x8 | 0x9, 0) => {
+                self.reg_bank_select = value;
+                self.update_banks();
+            }
+            (0x8 | 0x9, 1) => {
+                self.reg_banks[(self.reg_bank_select & 0x07) as usize] = value;
+                self.update_banks();
+            }
+            // Four-screen boards have the mirroring control disconnected
+            (0xa | 0xb, 0) if self.mirroring == Mirroring::FourScreen => {}
+            (0xa | 0xb, 0) => {
+                self.mirroring = if (value & 0x01) == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                };
+            }
+            (0xa | 0xb, 1) => self.reg_prg_ram_protect = value,
+            (0xc | 0xd, 0) => self.irq_latch = value,
+            (0xc | 0xd, 1) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xe | 0xf, 0) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (0xe | 0xf, 1) => self.irq_enabled = true,
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn observe_ppu_addr(&mut self, addr: u16) {
+        let is_high = (addr & 0x1000) != 0;
+        if is_high && !self.is_a12_high && self.a12_low_cycles >= Self::A12_FILTER_CYCLES {
+            self.clock_irq_counter();
+        }
+        if !is_high && self.is_a12_high {
+            self.a12_low_cycles = 0;
+        }
+        self.is_a12_high = is_high;
+    }
+
+    fn is_irq_asserted(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn tick(&mut self) {
+        if !self.is_a12_high {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
+        }
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::mappers::numbered_banks;
+
+    fn test_mapper() -> MMC3 {
+        let prg_rom = numbered_banks(0x20000, 0x2000);
+        let chr = numbered_banks(0x20000, 0x0400);
+        MMC3::new(prg_rom, ChrMemory::rom(chr), Mirroring::Vertical)
+    }
+
+    #[test]
+    fn test_bank_modes() {
+        let mut mapper = test_mapper();
+        mapper.write_prg(0x8000, 0x06);
+        mapper.write_prg(0x8001, 3);
+        mapper.write_prg(0x8000, 0x02);
+        mapper.write_prg(0x8001, 9);
+        assert_eq!(3, mapper.read_prg(0x8000));
+        assert_eq!(14, mapper.read_prg(0xc000));
+        assert_eq!(15, mapper.read_prg(0xe000));
+        assert_eq!(9, mapper.read_chr(0x1000));
+
+        // Swap the PRG and CHR halves
+        mapper.write_prg(0x8000, 0xc0);
+        assert_eq!(14, mapper.read_prg(0x8000));
+        assert_eq!(3, mapper.read_prg(0xc000));
+        assert_eq!(9, mapper.read_chr(0x0000));
+        assert_eq!(0, mapper.read_chr(0x1000));
+        assert_eq!(1, mapper.read_chr(0x1400));
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mapper = test_mapper();
+        mapper.write_prg(0xc000, 2);
+        mapper.write_prg(0xc001, 0);
+        mapper.write_prg(0xe001, 0);
+        let scanline = |mapper: &mut MMC3| {
+            mapper.observe_ppu_addr(0x0000);
+            for _ in 0..100 {
+                mapper.tick();
+            }
+            // Consecutive fetches only produce one rising edge
+            mapper.observe_ppu_addr(0x1000);
+            mapper.observe_ppu_addr(0x1008);
+        };
+        scanline(&mut mapper);
+        scanline(&mut mapper);
+        assert!(!mapper.is_irq_asserted());
+        scanline(&mut mapper);
+        assert!(mapper.is_irq_asserted());
+        mapper.write_prg(0xe000, 0);
+        assert!(!mapper.is_irq_asserted());
+
+        // Short low pulses are filtered out
+        mapper.write_prg(0xe001, 0);
+        for _ in 0..8 {
+            mapper.observe_ppu_addr(0x2000);
+            mapper.observe_ppu_addr(0x1000);
+        }
+        assert!(!mapper.is_irq_asserted());
+    }
+}
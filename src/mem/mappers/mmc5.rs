@@ -0,0 +1,730 @@
+use std::cell::Cell;
+
+use crate::{
+    apu::{ChannelPulse, ExpansionAudio, ExpansionChip},
+    rom::nes::Mirroring,
+    util::ClockDivider,
+};
+
+use super::{Banks, ChrMemory, Mapper};
+
+// CHR windows, the set is selected by adding the base to the pattern table address
+const CHR_SET_A: usize = 0x0000;
+const CHR_SET_B: usize = 0x2000;
+const CHR_EXTENDED: usize = 0x4000;
+const CHR_SPLIT: usize = 0x5000;
+
+// iNES mapper 5 (ExROM)
+#[allow(clippy::upper_case_acronyms)]
+pub struct MMC5 {
+    prg_rom: Banks,
+    // Window 0 is 0x6000..0x7fff, windows 1..4 are 0x8000..0xffff
+    prg_ram: Banks,
+    is_prg_ram_window: [bool; 4],
+    chr: Banks,
+    exram: [u8; 0x400],
+
+    // Internal registers
+    reg_prg_mode: u8,
+    reg_chr_mode: u8,
+    reg_prg_ram_protect: [u8; 2],
+    reg_exram_mode: u8,
+    reg_nametables: u8,
+    reg_fill_tile: u8,
+    reg_fill_attr: u8,
+    reg_prg_banks: [u8; 5],
+    // Set A is 0..7, set B is 8..11, with the upper bits already applied
+    reg_chr_banks: [u16; 12],
+    reg_chr_upper: u8,
+    is_chr_set_b_last: bool,
+    reg_split_control: u8,
+    reg_split_scroll: u8,
+    reg_split_bank: u8,
+    reg_irq_compare: u8,
+    is_irq_enabled: bool,
+    reg_multiplicand: u8,
+    reg_multiplier: u8,
+
+    // Snooped PPU state
+    is_sprite_8x16: bool,
+    is_rendering_enabled: bool,
+
+    // Scanline detection
+    is_in_frame: bool,
+    scanline: u8,
+    irq_pending: Cell<bool>,
+    last_ppu_addr: Option<u16>,
+    ppu_addr_repeats: u8,
+    ppu_idle_cycles: u8,
+
+    // Tile fetch tracking
+    tile_column: u8,
+    pattern_reads: u8,
+    is_split_tile: bool,
+    split_tile: u8,
+    split_attr: u8,
+    split_fine_y: u8,
+    extended_attr: u8,
+
+    // Audio
+    pulse1: ChannelPulse,
+    pulse2: ChannelPulse,
+    frame_clock_divider: ClockDivider<7457>,
+    is_pcm_read_mode: bool,
+    is_pcm_irq_enabled: bool,
+    pcm_irq_pending: Cell<bool>,
+    pcm_output: Cell<u8>,
+}
+
+impl MMC5 {
+    // The real chip leaves the frame after 3 CPU cycles without PPU reads. Our PPU fetches the
+    // sprites all at once on dot 257 and reads nothing else until dot 321, so that 64 dot gap
+    // (21 and a third CPU cycles) has to be allowed for on top of the 3 cycles.
+    const IDLE_CYCLES: u8 = ((321 - 257) / 3 + 1 + 3) as u8;
+
+    pub fn new(prg_rom: Vec<u8>, prg_ram_size: usize, chr: ChrMemory) -> Self {
+        let mut mapper = MMC5 {
+            prg_rom: Banks::new(prg_rom, 0x2000, 4),
+            prg_ram: Banks::new(vec![0x00; prg_ram_size], 0x2000, 5),
+            is_prg_ram_window: [false; 4],
+            chr: Banks::new_chr(chr, 0x0400, 24),
+            exram: [0x00; 0x400],
+            reg_prg_mode: 3,
+            reg_chr_mode: 0,
+            reg_prg_ram_protect: [0; 2],
+            reg_exram_mode: 0,
+            reg_nametables: 0,
+            reg_fill_tile: 0,
+            reg_fill_attr: 0,
+            reg_prg_banks: [0, 0, 0, 0, 0xff],
+            reg_chr_banks: [0; 12],
+            reg_chr_upper: 0,
+            is_chr_set_b_last: false,
+            reg_split_control: 0,
+            reg_split_scroll: 0,
+            reg_split_bank: 0,
+            reg_irq_compare: 0,
+            is_irq_enabled: false,
+            reg_multiplicand: 0xff,
+            reg_multiplier: 0xff,
+            is_sprite_8x16: false,
+            is_rendering_enabled: false,
+            is_in_frame: false,
+            scanline: 0,
+            irq_pending: Cell::new(false),
+            last_ppu_addr: None,
+            ppu_addr_repeats: 0,
+            ppu_idle_cycles: 0,
+            tile_column: 0,
+            pattern_reads: 0,
+            is_split_tile: false,
+            split_tile: 0,
+            split_attr: 0,
+            split_fine_y: 0,
+            extended_attr: 0,
+            pulse1: ChannelPulse::new(),
+            pulse2: ChannelPulse::new(),
+            frame_clock_divider: ClockDivider::new(),
+            is_pcm_read_mode: false,
+            is_pcm_irq_enabled: false,
+            pcm_irq_pending: Cell::new(false),
+            pcm_output: Cell::new(0),
+        };
+        mapper.update_prg_banks();
+        mapper.update_chr_banks();
+        mapper
+    }
+
+    fn update_prg_banks(&mut self) {
+        self.prg_ram.set(0, (self.reg_prg_banks[0] & 0x07) as usize);
+        for window in 0..4 {
+            // Register index and the bank size in 8K units
+            let (reg, size) = match (self.reg_prg_mode & 0x03, window) {
+                (0, _) => (4, 4),
+                (1, 0 | 1) => (2, 2),
+                (1, _) => (4, 2),
+                (2, 0 | 1) => (2, 2),
+                (2, _) => (window + 1, 1),
+                (_, _) => (window + 1, 1),
+            };
+            let value = self.reg_prg_banks[reg];
+            let bank = ((value & 0x7f) as usize & !(size - 1)) | (window % size);
+            // 0xe000..0xffff is always ROM
+            let is_ram = reg != 4 && (value & 0x80) == 0;
+            if is_ram {
+                self.prg_ram.set(window + 1, bank & 0x07);
+            } else {
+                self.prg_rom.set(window, bank);
+            }
+            self.is_prg_ram_window[window] = is_ram;
+        }
+    }
+
+    fn update_chr_banks(&mut self) {
+        // Bank size in 1K units
+        let size = 8 >> (self.reg_chr_mode & 0x03);
+        for window in 0..8 {
+            let reg = (window / size) * size + size - 1;
+            let bank = self.reg_chr_banks[reg] as usize * size + window % size;
+            self.chr.set(window, bank);
+
+            // Set B only has four registers, repeated for both pattern tables
+            let size_b = size.min(4);
+            let reg = 8 + ((window % 4) / size_b) * size_b + size_b - 1;
+            let bank = self.reg_chr_banks[reg] as usize * size + window % size;
+            self.chr.set(8 + window, bank);
+        }
+        for window in 0..4 {
+            self.chr
+                .set(20 + window, self.reg_split_bank as usize * 4 + window);
+        }
+    }
+
+    fn is_prg_ram_writable(&self) -> bool {
+        self.reg_prg_ram_protect == [0x02, 0x01]
+    }
+
+    fn nametable_source(&self, addr: u16) -> u8 {
+        (self.reg_nametables >> (((addr >> 10) & 0x03) * 2)) & 0x03
+    }
+
+    fn is_split_enabled(&self) -> bool {
+        (self.reg_split_control & 0x80) != 0 && self.reg_exram_mode <= 1
+    }
+
+    // The split region is checked for the tile that is about to be fetched
+    fn is_split_column(&self, column: u8) -> bool {
+        if !self.is_in_frame || !self.is_split_enabled() {
+            return false;
+        }
+        let threshold = self.reg_split_control & 0x1f;
+        let x = column % 34;
+        if (self.reg_split_control & 0x40) == 0 {
+            x < threshold
+        } else {
+            x >= threshold
+        }
+    }
+
+    // Tile coordinates inside the split nametable
+    fn split_position(&self, column: u8) -> (usize, usize) {
+        // Tiles past the end of the line are prefetched for the next one
+        let (x, line) = if column >= 34 {
+            ((column - 34) & 0x1f, self.scanline as usize + 1)
+        } else {
+            (column & 0x1f, self.scanline as usize)
+        };
+        (x as usize, (self.reg_split_scroll as usize + line) % 240)
+    }
+
+    fn detect_scanline(&mut self) {
+        if !self.is_in_frame {
+            self.is_in_frame = true;
+            self.scanline = 0;
+            self.irq_pending.set(false);
+        } else {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.reg_irq_compare && self.reg_irq_compare != 0 {
+                self.irq_pending.set(true);
+            }
+        }
+        // The first three tiles of the line were fetched at the end of the previous one,
+        // the third by the first of the repeated reads
+        self.tile_column = 3;
+    }
+
+    // Column of the tile being read, repeated reads of an address are the same fetch
+    fn read_column(&self, addr: u16) -> u8 {
+        if self.last_ppu_addr == Some(addr) {
+            self.tile_column.wrapping_sub(1)
+        } else {
+            self.tile_column
+        }
+    }
+
+    fn leave_frame(&mut self) {
+        self.is_in_frame = false;
+        self.last_ppu_addr = None;
+        self.ppu_addr_repeats = 0;
+    }
+
+    fn fetch_tile(&mut self, addr: u16) {
+        let column = self.tile_column;
+        self.tile_column = self.tile_column.saturating_add(1);
+        self.pattern_reads = 0;
+        self.is_split_tile = self.is_split_column(column);
+        if self.is_split_tile {
+            let (x, y) = self.split_position(column);
+            self.split_tile = self.exram[(y / 8) * 32 + x];
+            let attr = self.exram[0x3c0 + (y / 32) * 8 + x / 4];
+            let shift = ((y >> 2) & 0x04) | (x & 0x02);
+            self.split_attr = (attr >> shift) & 0x03;
+            self.split_fine_y = (y & 0x07) as u8;
+        } else if self.reg_exram_mode == 1 {
+            let value = self.exram[(addr & 0x03ff) as usize];
+            self.extended_attr = value >> 6;
+            let bank = (value & 0x3f) as usize | ((self.reg_chr_upper as usize & 0x03) << 6);
+            for window in 0..4 {
+                self.chr.set(16 + window, bank * 4 + window);
+            }
+        }
+    }
+
+    fn chr_set(&self) -> usize {
+        let is_sprite_fetch = self.pattern_reads >= 2;
+        if self.is_in_frame && !is_sprite_fetch {
+            if self.is_split_tile {
+                return CHR_SPLIT;
+            }
+            if self.reg_exram_mode == 1 {
+                return CHR_EXTENDED;
+            }
+        }
+        // 8x16 sprites use set A and the background uses set B, only while rendering
+        if self.is_sprite_8x16 && self.is_in_frame {
+            if is_sprite_fetch {
+                CHR_SET_A
+            } else {
+                CHR_SET_B
+            }
+        } else if self.is_chr_set_b_last {
+            CHR_SET_B
+        } else {
+            CHR_SET_A
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x5000..=0x5003 => {
+                let pulse = &mut self.pulse1;
+                match addr & 0x03 {
+                    0 => pulse.write_reg_0(value),
+                    1 => pulse.write_reg_1(value),
+                    2 => pulse.write_reg_2(value),
+                    _ => pulse.write_reg_3(value),
+                }
+            }
+            0x5004..=0x5007 => {
+                let pulse = &mut self.pulse2;
+                match addr & 0x03 {
+                    0 => pulse.write_reg_0(value),
+                    1 => pulse.write_reg_1(value),
+                    2 => pulse.write_reg_2(value),
+                    _ => pulse.write_reg_3(value),
+                }
+            }
+            0x5010 => {
+                self.is_pcm_read_mode = (value & 0x01) != 0;
+                self.is_pcm_irq_enabled = (value & 0x80) != 0;
+            }
+            // Zero can't be written, it triggers the IRQ in the read mode instead
+            0x5011 if !self.is_pcm_read_mode && value != 0 => self.pcm_output.set(value),
+            0x5015 => {
+                self.pulse1.set_enabled((value & 0x01) != 0);
+                self.pulse2.set_enabled((value & 0x02) != 0);
+            }
+            0x5100 => {
+                self.reg_prg_mode = value & 0x03;
+                self.update_prg_banks();
+            }
+            0x5101 => {
+                self.reg_chr_mode = value & 0x03;
+                self.update_chr_banks();
+            }
+            0x5102 => self.reg_prg_ram_protect[0] = value & 0x03,
+            0x5103 => self.reg_prg_ram_protect[1] = value & 0x03,
+            0x5104 => self.reg_exram_mode = value & 0x03,
+            0x5105 => self.reg_nametables = value,
+            0x5106 => self.reg_fill_tile = value,
+            0x5107 => self.reg_fill_attr = value & 0x03,
+            0x5113..=0x5117 => {
+                self.reg_prg_banks[(addr - 0x5113) as usize] = value;
+                self.update_prg_banks();
+            }
+            0x5120..=0x512b => {
+                let reg = (addr - 0x5120) as usize;
+                self.reg_chr_banks[reg] = value as u16 | ((self.reg_chr_upper as u16) << 8);
+                self.is_chr_set_b_last = reg >= 8;
+                self.update_chr_banks();
+            }
+            0x5130 => self.reg_chr_upper = value & 0x03,
+            0x5200 => self.reg_split_control = value,
+            0x5201 => self.reg_split_scroll = value,
+            0x5202 => {
+                self.reg_split_bank = value;
+                self.update_chr_banks();
+            }
+            0x5203 => self.reg_irq_compare = value,
+            0x5204 => self.is_irq_enabled = (value & 0x80) != 0,
+            0x5205 => self.reg_multiplicand = value,
+            0x5206 => self.reg_multiplier = value,
+            0x5c00..=0x5fff => {
+                let idx = (addr & 0x03ff) as usize;
+                match self.reg_exram_mode {
+                    // Only writable while rendering when used for the nametables
+                    0 | 1 => self.exram[idx] = if self.is_in_frame { value } else { 0 },
+                    2 => self.exram[idx] = value,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_register(&self, addr: u16) -> u8 {
+        match addr {
+            0x5010 => {
+                let value = ((self.pcm_irq_pending.get() as u8) << 7) | self.is_pcm_read_mode as u8;
+                self.pcm_irq_pending.set(false);
+                value
+            }
+            0x5015 => {
+                (self.pulse1.is_length_counter_active() as u8)
+                    | ((self.pulse2.is_length_counter_active() as u8) << 1)
+            }
+            0x5204 => {
+                let value = ((self.irq_pending.get() as u8) << 7) | ((self.is_in_frame as u8) << 6);
+                self.irq_pending.set(false);
+                value
+            }
+            0x5205 => (self.reg_multiplicand as u16 * self.reg_multiplier as u16) as u8,
+            0x5206 => ((self.reg_multiplicand as u16 * self.reg_multiplier as u16) >> 8) as u8,
+            0x5c00..=0x5fff if self.reg_exram_mode >= 2 => self.exram[(addr & 0x03ff) as usize],
+            // Open bus
+            _ => (addr >> 8) as u8,
+        }
+    }
+
+    fn read_prg_window(&self, addr: u16) -> u8 {
+        let window = ((addr >> 13) & 0x03) as usize;
+        if self.is_prg_ram_window[window] {
+            self.prg_ram.read(0x2000 + (addr & 0x7fff) as usize)
+        } else {
+            self.prg_rom.read((addr & 0x7fff) as usize)
+        }
+    }
+}
+
+impl Mapper for MMC5 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr >> 12 {
+            0x4 | 0x5 => self.read_register(addr),
+            0x6 | 0x7 => self.prg_ram.read((addr & 0x1fff) as usize),
+            0x8..=0xf => {
+                let value = self.read_prg_window(addr);
+                // The PCM channel can capture reads from 0x8000..0xbfff
+                if self.is_pcm_read_mode && addr < 0xc000 {
+                    if value == 0 {
+                        self.pcm_irq_pending.set(true);
+                    } else {
+                        self.pcm_output.set(value);
+                    }
+                }
+                value
+            }
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        match addr >> 12 {
+            0x4 | 0x5 => self.write_register(addr, value),
+            0x6 | 0x7 if self.is_prg_ram_writable() => {
+                self.prg_ram.write((addr & 0x1fff) as usize, value)
+            }
+            0x8..=0xd if self.is_prg_ram_writable() => {
+                let window = ((addr >> 13) & 0x03) as usize;
+                if self.is_prg_ram_window[window] {
+                    self.prg_ram.write(0x2000 + (addr & 0x7fff) as usize, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        let addr = addr as usize;
+        match self.chr_set() {
+            CHR_SPLIT => {
+                let row = (addr & 0x08) | self.split_fine_y as usize;
+                self.chr
+                    .read(CHR_SPLIT + ((self.split_tile as usize) << 4) + row)
+            }
+            CHR_EXTENDED => self.chr.read(CHR_EXTENDED + (addr & 0x0fff)),
+            set => self.chr.read(set + addr),
+        }
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        let set = if self.is_chr_set_b_last {
+            CHR_SET_B
+        } else {
+            CHR_SET_A
+        };
+        self.chr.write(set + addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        let mut pages = [0; 4];
+        for (table, page) in pages.iter_mut().enumerate() {
+            *page = (self.reg_nametables >> (table * 2)) & 0x01;
+        }
+        Mirroring::Custom(pages)
+    }
+
+    fn read_nametable(&self, addr: u16) -> Option<u8> {
+        let offset = (addr & 0x03ff) as usize;
+        let is_attr = offset >= 0x3c0;
+        // Attributes are repeated for all four quadrants of the byte
+        let spread = |attr: u8| attr * 0x55;
+        if self.is_in_frame && self.is_split_enabled() {
+            let column = self.read_column(addr);
+            if !is_attr && self.is_split_column(column) {
+                let (x, y) = self.split_position(column);
+                return Some(self.exram[(y / 8) * 32 + x]);
+            }
+            if is_attr && self.is_split_tile {
+                return Some(spread(self.split_attr));
+            }
+        }
+        if self.is_in_frame && is_attr && self.reg_exram_mode == 1 {
+            return Some(spread(self.extended_attr));
+        }
+        match self.nametable_source(addr) {
+            0 | 1 => None,
+            2 if self.reg_exram_mode <= 1 => Some(self.exram[offset]),
+            2 => Some(0),
+            _ if is_attr => Some(spread(self.reg_fill_attr)),
+            _ => Some(self.reg_fill_tile),
+        }
+    }
+
+    fn write_nametable(&mut self, addr: u16, value: u8) -> bool {
+        match self.nametable_source(addr) {
+            0 | 1 => false,
+            2 => {
+                if self.reg_exram_mode <= 1 {
+                    self.exram[(addr & 0x03ff) as usize] = value;
+                }
+                true
+            }
+            _ => true,
+        }
+    }
+
+    fn observe_ppu_addr(&mut self, addr: u16) {
+        self.ppu_idle_cycles = 0;
+        // Three reads of the same nametable address in a row only happen at the end of a line
+        if self.last_ppu_addr == Some(addr) {
+            self.ppu_addr_repeats += 1;
+            if self.ppu_addr_repeats == 2 {
+                self.detect_scanline();
+            }
+            return;
+        }
+        self.last_ppu_addr = Some(addr);
+        self.ppu_addr_repeats = 0;
+        match addr {
+            0x0000..=0x1fff => self.pattern_reads = self.pattern_reads.saturating_add(1),
+            _ if (addr & 0x03ff) < 0x3c0 => self.fetch_tile(addr),
+            _ => {}
+        }
+    }
+
+    fn observe_cpu_write(&mut self, addr: u16, value: u8) {
+        match addr & 0x07 {
+            0 => self.is_sprite_8x16 = (value & 0x20) != 0,
+            1 => {
+                self.is_rendering_enabled = (value & 0x18) != 0;
+                if !self.is_rendering_enabled {
+                    self.leave_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn is_irq_asserted(&self) -> bool {
+        (self.irq_pending.get() && self.is_irq_enabled)
+            || (self.pcm_irq_pending.get() && self.is_pcm_irq_enabled)
+    }
+
+    fn tick(&mut self) {
+        self.ppu_idle_cycles = self.ppu_idle_cycles.saturating_add(1);
+        if self.ppu_idle_cycles >= Self::IDLE_CYCLES && self.is_in_frame {
+            self.leave_frame();
+        }
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(self)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(self.prg_ram.data_mut())
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        Some(self.prg_ram.data())
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(self.prg_ram.data_mut())
+    }
+}
+
+impl ExpansionAudio for MMC5 {
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::MMC5
+    }
+
+    fn tick_audio(&mut self) {
+        self.pulse1.tick_timer();
+        self.pulse2.tick_timer();
+        // Envelopes and length counters are clocked at a fixed 240Hz
+        self.frame_clock_divider.tick();
+        if self.frame_clock_divider.is_triggered() {
+            for pulse in [&mut self.pulse1, &mut self.pulse2].iter_mut() {
+                pulse.tick_length_counter();
+                pulse.tick_envelope_generator();
+            }
+        }
+    }
+
+    fn audio_output(&self) -> i32 {
+        (self.pulse1.get_volume() as i32 + self.pulse2.get_volume() as i32) * 16
+            + self.pcm_output.get() as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::mappers::numbered_banks;
+
+    fn test_mapper() -> MMC5 {
+        let prg_rom = numbered_banks(0x40000, 0x2000);
+        let chr = numbered_banks(0x40000, 0x0400);
+        MMC5::new(prg_rom, 0x10000, ChrMemory::rom(chr))
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut mapper = test_mapper();
+        assert_eq!(31, mapper.read_prg(0xe000));
+        mapper.write_prg(0x5100, 1);
+        mapper.write_prg(0x5115, 0x85);
+        assert_eq!(4, mapper.read_prg(0x8000));
+        assert_eq!(5, mapper.read_prg(0xa000));
+        assert_eq!(30, mapper.read_prg(0xc000));
+
+        // RAM mapped into 0x8000..0xbfff is only writable when unlocked
+        mapper.write_prg(0x5115, 0x02);
+        mapper.write_prg(0x8000, 0x42);
+        assert_eq!(0, mapper.read_prg(0x8000));
+        mapper.write_prg(0x5102, 0x02);
+        mapper.write_prg(0x5103, 0x01);
+        mapper.write_prg(0x8000, 0x42);
+        assert_eq!(0x42, mapper.read_prg(0x8000));
+        mapper.write_prg(0x5113, 0x02);
+        assert_eq!(0x42, mapper.read_prg(0x6000));
+
+        mapper.write_prg(0x5101, 3);
+        mapper.write_prg(0x5130, 1);
+        mapper.write_prg(0x5123, 0x02);
+        assert_eq!(2, mapper.read_chr(0x0c00));
+        mapper.write_prg(0x5101, 1);
+        mapper.write_prg(0x512b, 0x03);
+        // The upper bits are latched on the bank register write
+        assert_eq!(0x0c, mapper.read_chr(0x0000));
+        assert_eq!(0x0f, mapper.read_chr(0x1c00));
+
+        mapper.write_prg(0x5205, 200);
+        mapper.write_prg(0x5206, 100);
+        assert_eq!(
+            20000,
+            mapper.read_prg(0x5205) as u16 | (mapper.read_prg(0x5206) as u16) << 8
+        );
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mapper = test_mapper();
+        mapper.write_prg(0x5203, 2);
+        mapper.write_prg(0x5204, 0x80);
+        let scanline = |mapper: &mut MMC5| {
+            for _ in 0..3 {
+                mapper.read_nametable(0x2000);
+                mapper.observe_ppu_addr(0x2000);
+            }
+            mapper.observe_ppu_addr(0x23c0);
+        };
+        scanline(&mut mapper);
+        assert_eq!(0x40, mapper.read_prg(0x5204));
+        scanline(&mut mapper);
+        assert!(!mapper.is_irq_asserted());
+        scanline(&mut mapper);
+        assert!(mapper.is_irq_asserted());
+        assert_eq!(0xc0, mapper.read_prg(0x5204));
+        assert!(!mapper.is_irq_asserted());
+
+        // The frame ends once the PPU stops fetching
+        for _ in 0..MMC5::IDLE_CYCLES {
+            mapper.tick();
+        }
+        assert_eq!(0x00, mapper.read_prg(0x5204));
+    }
+
+    #[test]
+    fn test_split_columns() {
+        let mut mapper = test_mapper();
+        let fetch = |mapper: &mut MMC5, addr: u16| {
+            let value = mapper.read_nametable(addr);
+            mapper.observe_ppu_addr(addr);
+            value
+        };
+        // Fetches from dot 1 to 340, the tiles of the line from the third one on,
+        // then the first two of the next line
+        let line = |mapper: &mut MMC5| {
+            let mut tiles = vec![];
+            for column in 2..36 {
+                let x = column % 34;
+                tiles.push(fetch(mapper, 0x2000 | x));
+                fetch(mapper, 0x23c0 | (x >> 2));
+                mapper.observe_ppu_addr(x * 0x10);
+                mapper.observe_ppu_addr(x * 0x10 + 8);
+            }
+            fetch(mapper, 0x2002);
+            fetch(mapper, 0x2002);
+            tiles
+        };
+        fetch(&mut mapper, 0x2002);
+        fetch(&mut mapper, 0x2002);
+        line(&mut mapper);
+        // Split on the left four tiles, read from the ExRAM
+        mapper.write_prg(0x5200, 0x84);
+        for x in 0..32 {
+            mapper.write_prg(0x5c00 + x, 0x10 + x as u8);
+        }
+        let tiles = line(&mut mapper);
+        assert_eq!(vec![Some(0x12), Some(0x13)], tiles[..2]);
+        assert!(tiles[2..32].iter().all(|tile| tile.is_none()));
+        assert_eq!(vec![Some(0x10), Some(0x11)], tiles[32..]);
+        assert_eq!(Some(0x12), line(&mut mapper)[0]);
+    }
+
+    #[test]
+    fn test_nametables() {
+        let mut mapper = test_mapper();
+        mapper.write_prg(0x5105, 0xe4);
+        mapper.write_prg(0x5106, 0x12);
+        mapper.write_prg(0x5107, 0x02);
+        assert_eq!(Mirroring::Custom([0, 1, 0, 1]), mapper.mirroring());
+        assert_eq!(None, mapper.read_nametable(0x2400));
+        assert!(mapper.write_nametable(0x2805, 0x34));
+        assert_eq!(Some(0x34), mapper.read_nametable(0x2805));
+        assert_eq!(Some(0x12), mapper.read_nametable(0x2c00));
+        assert_eq!(Some(0xaa), mapper.read_nametable(0x2fc0));
+    }
+}
@@ -0,0 +1,247 @@
+mod bandai;
+pub use bandai::*;
+mod banks;
+pub use banks::ChrMemory;
+use banks::*;
+mod discrete;
+mod eeprom;
+pub use discrete::*;
+mod fds;
+mod fds_audio;
+pub use fds::*;
+mod fme7;
+pub use fme7::*;
+mod mmc1;
+pub use mmc1::*;
+mod mmc2;
+pub use mmc2::*;
+mod mmc3;
+pub use mmc3::*;
+mod mmc5;
+pub use mmc5::*;
+mod namco163;
+pub use namco163::*;
+mod nrom;
+pub use nrom::*;
+mod nsf;
+pub use nsf::*;
+mod opll;
+mod vrc4;
+pub use vrc4::*;
+mod vrc6;
+pub use vrc6::*;
+mod vrc7;
+pub use vrc7::*;
+mod vrc_irq;
+
+use crate::{
+    apu::ExpansionAudio,
+    rom::nes::{LoadError, Mirroring, NESFile},
+};
+
+// Cartridge hardware as seen from both the CPU and the PPU buses
+pub trait Mapper {
+    // CPU side - 0x4020..0xffff
+    fn read_prg(&self, addr: u16) -> u8;
+    // Register writes and PRG RAM, ROM contents are never modified
+    fn write_prg(&mut self, addr: u16, value: u8);
+
+    // PPU side - 0x0000..0x1fff
+    fn read_chr(&self, addr: u16) -> u8;
+    fn write_chr(&mut self, addr: u16, value: u8);
+
+    // Nametable layout, can be changed by the mapper at any time
+    fn mirroring(&self) -> Mirroring;
+
+    // Boards that put their own memory into the nametables (0x2000..0x3eff)
+    // take over the access, otherwise it goes to CIRAM
+    fn read_nametable(&self, _addr: u16) -> Option<u8> {
+        None
+    }
+    fn write_nametable(&mut self, _addr: u16, _value: u8) -> bool {
+        false
+    }
+
+    // Called after every access the PPU makes on its bus (0x0000..0x3eff),
+    // including the pattern fetches done while rendering
+    fn observe_ppu_addr(&mut self, _addr: u16) {}
+
+    // Called for CPU writes to the PPU registers (0x2000..0x3fff)
+    fn observe_cpu_write(&mut self, _addr: u16, _value: u8) {}
+
+    // IRQ output, the line stays asserted until the mapper acknowledges it
+    fn is_irq_asserted(&self) -> bool {
+        false
+    }
+
+    // Called once per CPU cycle
+    fn tick(&mut self) {}
+
+    // Boards with a sound chip hand it to the APU
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        None
+    }
+
+    // Boards with a barcode reader take a code to scan, returns false if it was rejected
+    fn scan_barcode(&mut self, _barcode: &str) -> bool {
+        false
+    }
+
+    // Disk drives, sides are numbered from 0 and None ejects the disk
+    fn disk_side_count(&self) -> usize {
+        0
+    }
+    fn insert_disk(&mut self, _side: Option<usize>) {}
+
+    // Music players start over with the given track, numbered from 0
+    fn select_track(&mut self, _track: usize) {}
+
+    // PRG RAM seen at 0x6000..0x7fff after power-on, trainers are copied into it
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    // Memory that is kept between sessions when the board has a battery
+    fn battery_ram(&self) -> Option<&[u8]> {
+        None
+    }
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    // Files of a different size only overwrite the common part
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        if let Some(ram) = self.battery_ram_mut() {
+            let len = data.len().min(ram.len());
+            ram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+}
+
+pub fn get_mapper(rom: NESFile) -> Result<Box<dyn Mapper>, LoadError> {
+    let header = rom.header;
+    let prg_rom = rom.prg_rom;
+    let chr = if rom.chr_rom.is_empty() {
+        // Fall back to 8K for NES 2.0 headers that don't declare any CHR RAM
+        ChrMemory::ram(match header.total_chr_ram_size() {
+            0 => 0x2000,
+            size => size,
+        })
+    } else {
+        ChrMemory::rom(rom.chr_rom)
+    };
+    let mirroring = header.nametable_mirroring();
+    // NES 2.0 submapper 1 means no bus conflicts and 2 means bus conflicts
+    let has_bus_conflicts = |default: bool| match header.submapper {
+        1 => false,
+        2 => true,
+        _ => default,
+    };
+    let mut mapper: Box<dyn Mapper> = match header.mapper {
+        0 => Box::new(NROM::new(prg_rom, chr, mirroring)),
+        1 => Box::new(MMC1::new(MMC1Board::detect(&header), prg_rom, chr)),
+        2 => Box::new(UxROM::new(prg_rom, chr, mirroring, has_bus_conflicts(true))),
+        3 => Box::new(CNROM::new(prg_rom, chr, mirroring, has_bus_conflicts(true))),
+        4 => Box::new(MMC3::new(prg_rom, chr, mirroring)),
+        // iNES headers don't say how much RAM ExROM boards have, 64K covers all of them
+        5 if header.is_nes2_format => {
+            Box::new(MMC5::new(prg_rom, header.total_prg_ram_size(), chr))
+        }
+        5 => Box::new(MMC5::new(prg_rom, 0x10000, chr)),
+        // Only some AxROM boards have bus conflicts
+        7 => Box::new(AxROM::new(
+            prg_rom,
+            chr,
+            has_bus_conflicts(header.has_bus_conflicts),
+        )),
+        9 => Box::new(MMC2::new(prg_rom, chr)),
+        10 => Box::new(MMC4::new(prg_rom, chr)),
+        11 => Box::new(ColorDreams::new(prg_rom, chr, mirroring, true)),
+        16 | 153 | 157 | 159 => {
+            let board = BandaiBoard::detect(&header);
+            // NES 2.0 headers give the EEPROM size as PRG NVRAM
+            let eeprom_size = match header.mapper {
+                16 if header.is_nes2_format => header.prg_nvram_size,
+                16 | 157 => 0x100,
+                159 => 0x80,
+                _ => 0,
+            };
+            let prg_ram_size = match header.mapper {
+                153 if header.is_nes2_format => header.total_prg_ram_size(),
+                153 => 0x2000,
+                _ => 0,
+            };
+            Box::new(BandaiFCG::new(
+                board,
+                prg_rom,
+                prg_ram_size,
+                eeprom_size,
+                chr,
+            ))
+        }
+        // NES 2.0 headers count the 128 bytes of internal RAM as PRG RAM
+        19 if header.is_nes2_format => Box::new(Namco163::new(
+            prg_rom,
+            header.total_prg_ram_size() & !0xff,
+            chr,
+        )),
+        19 => Box::new(Namco163::new(prg_rom, 0x2000, chr)),
+        21 | 22 | 23 | 25 => {
+            let board = VRC4Board::detect(&header);
+            // iNES headers can't express RAM size, VRC2 boards without RAM have the microwire
+            // latch and boards that may be either chip only get RAM along with a battery
+            let prg_ram_size = if header.is_nes2_format {
+                header.total_prg_ram_size()
+            } else if board.is_vrc2()
+                || (board.has_microwire_latch() && !header.has_persistent_memory)
+            {
+                0
+            } else {
+                0x2000
+            };
+            Box::new(VRC4::new(board, prg_rom, prg_ram_size, chr))
+        }
+        24 => Box::new(VRC6::new(prg_rom, chr, false)),
+        26 => Box::new(VRC6::new(prg_rom, chr, true)),
+        // NES 2.0 submapper 1 is the NINA-001 and 2 the BNROM, otherwise the CHR size tells them apart
+        34 => match header.submapper {
+            1 => Box::new(NINA001::new(prg_rom, chr, mirroring)),
+            2 => Box::new(BNROM::new(prg_rom, chr, mirroring, true)),
+            _ if chr.len() > 0x2000 => Box::new(NINA001::new(prg_rom, chr, mirroring)),
+            _ => Box::new(BNROM::new(prg_rom, chr, mirroring, true)),
+        },
+        66 => Box::new(GxROM::new(prg_rom, chr, mirroring, true)),
+        69 if header.is_nes2_format => {
+            Box::new(FME7::new(prg_rom, header.total_prg_ram_size(), chr))
+        }
+        69 => Box::new(FME7::new(prg_rom, 0x2000, chr)),
+        // Submapper 1 is the VRC7b (A3) and 2 the VRC7a (A4), decode both when unknown
+        85 => {
+            let register_lines = match header.submapper {
+                1 => 0x08,
+                2 => 0x10,
+                _ => 0x18,
+            };
+            Box::new(VRC7::new(prg_rom, chr, register_lines))
+        }
+        _ => return Err(LoadError::UnsupportedMapper(header.mapper)),
+    };
+    // Some boards keep their RAM locked until the game enables it, so this
+    // bypasses the registers
+    if let Some(trainer) = rom.trainer {
+        if let Some(ram) = mapper.prg_ram_mut().filter(|ram| ram.len() >= 0x1200) {
+            ram[0x1000..0x1200].copy_from_slice(&trainer);
+        }
+    }
+    Ok(mapper)
+}
+
+// Fills each bank with its own number, so that tests can tell which bank is mapped
+#[cfg(test)]
+pub(crate) fn numbered_banks(size: usize, bank_size: usize) -> Vec<u8> {
+    let mut data = vec![0; size];
+    for (i, bank) in data.chunks_mut(bank_size).enumerate() {
+        bank.fill(i as u8);
+    }
+    data
+}
@@ -0,0 +1,373 @@
+use std::cell::Cell;
+
+use crate::{
+    apu::{ExpansionAudio, ExpansionChip},
+    rom::nes::Mirroring,
+};
+
+use super::{Banks, ChrMemory, Mapper};
+
+const INTERNAL_RAM_SIZE: usize = 0x80;
+
+// Wavetable synthesizer, the channel registers live in the internal RAM at 0x40..0x7f.
+// A single DAC is shared by all channels, each one is updated and output for 15 CPU cycles
+// in turn, so enabling more channels lowers the rate of every channel.
+struct WavetableAudio {
+    cycle: u8,
+    channel: usize,
+    output: i16,
+}
+
+impl WavetableAudio {
+    const CHANNEL_CYCLES: u8 = 15;
+
+    fn new() -> Self {
+        WavetableAudio {
+            cycle: 0,
+            channel: 7,
+            output: 0,
+        }
+    }
+
+    fn tick(&mut self, ram: &mut [u8]) {
+        self.cycle += 1;
+        if self.cycle < Self::CHANNEL_CYCLES {
+            return;
+        }
+        self.cycle = 0;
+        // Enabled channels go from 7 down to 8 - count
+        let channel_count = ((ram[0x7f] >> 4) & 0x07) as usize + 1;
+        self.channel = if self.channel <= 8 - channel_count {
+            7
+        } else {
+            self.channel - 1
+        };
+        self.output = Self::update_channel(ram, self.channel);
+    }
+
+    // Advances the phase of a channel and returns its output
+    fn update_channel(ram: &mut [u8], channel: usize) -> i16 {
+        let regs = &mut ram[0x40 + channel * 8..0x48 + channel * 8];
+        let frequency =
+            regs[0] as u32 | ((regs[2] as u32) << 8) | (((regs[4] & 0x03) as u32) << 16);
+        let length = 0x100 - (regs[4] & 0xfc) as u32;
+        let mut phase = regs[1] as u32 | ((regs[3] as u32) << 8) | ((regs[5] as u32) << 16);
+        phase = (phase + frequency) % (length << 16);
+        regs[1] = phase as u8;
+        regs[3] = (phase >> 8) as u8;
+        regs[5] = (phase >> 16) as u8;
+
+        // Samples are 4 bits, packed low nibble first
+        let sample_addr = ((phase >> 16) + regs[6] as u32) & 0xff;
+        let volume = (regs[7] & 0x0f) as i16;
+        let byte = ram[(sample_addr >> 1) as usize];
+        let sample = if (sample_addr & 0x01) == 0 {
+            byte & 0x0f
+        } else {
+            byte >> 4
+        };
+        (sample as i16 - 8) * volume
+    }
+}
+
+// iNES mapper 19
+pub struct Namco163 {
+    prg_rom: Banks,
+    // PRG RAM followed by the internal RAM, the battery keeps both
+    ram: Vec<u8>,
+    prg_ram_size: usize,
+    // Windows 0..7 are the pattern tables, 8..11 the nametables
+    chr: Banks,
+
+    // Internal registers
+    reg_chr_banks: [u8; 12],
+    reg_sound_disable: bool,
+    reg_write_protect: u8,
+    internal_ram_addr: Cell<u8>,
+    irq_counter: u16,
+    is_irq_enabled: bool,
+    is_irq_pending: bool,
+
+    audio: WavetableAudio,
+}
+
+impl Namco163 {
+    pub fn new(prg_rom: Vec<u8>, prg_ram_size: usize, chr: ChrMemory) -> Self {
+        let mut prg_rom = Banks::new(prg_rom, 0x2000, 4);
+        prg_rom.set(3, prg_rom.bank_count() - 1);
+        Namco163 {
+            prg_rom,
+            ram: vec![0x00; prg_ram_size + INTERNAL_RAM_SIZE],
+            prg_ram_size,
+            chr: Banks::new_chr(chr, 0x0400, 12),
+            reg_chr_banks: [0; 12],
+            reg_sound_disable: false,
+            reg_write_protect: 0,
+            internal_ram_addr: Cell::new(0),
+            irq_counter: 0,
+            is_irq_enabled: false,
+            is_irq_pending: false,
+            audio: WavetableAudio::new(),
+        }
+    }
+
+    // Values from 0xe0 select CIRAM instead of CHR ROM for the nametables
+    fn is_ciram(&self, window: usize) -> bool {
+        window >= 8 && self.reg_chr_banks[window] >= 0xe0
+    }
+
+    // Returns the internal RAM address and increments it when enabled
+    fn next_internal_ram_addr(&self) -> usize {
+        let addr = self.internal_ram_addr.get();
+        if (addr & 0x80) != 0 {
+            self.internal_ram_addr
+                .set((addr & 0x80) | (addr.wrapping_add(1) & 0x7f));
+        }
+        self.prg_ram_size + (addr & 0x7f) as usize
+    }
+
+    fn is_prg_ram_writable(&self, addr: u16) -> bool {
+        let section = (addr - 0x6000) >> 11;
+        (self.reg_write_protect & 0xf0) == 0x40
+            && (self.reg_write_protect & (1 << section)) == 0
+            && self.prg_ram_size > 0
+    }
+}
+
+impl Mapper for Namco163 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x4fff => self.ram[self.next_internal_ram_addr()],
+            0x5000..=0x57ff => self.irq_counter as u8,
+            0x5800..=0x5fff => ((self.is_irq_enabled as u8) << 7) | (self.irq_counter >> 8) as u8,
+            0x6000..=0x7fff if self.prg_ram_size > 0 => {
+                self.ram[(addr as usize - 0x6000) % self.prg_ram_size]
+            }
+            // Open bus
+            0x4020..=0x7fff => (addr >> 8) as u8,
+            0x8000..=0xffff => self.prg_rom.read((addr & 0x7fff) as usize),
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4800..=0x4fff => {
+                let addr = self.next_internal_ram_addr();
+                self.ram[addr] = value;
+            }
+            // Writing either half of the counter acknowledges the IRQ
+            0x5000..=0x57ff => {
+                self.irq_counter = (self.irq_counter & 0x7f00) | value as u16;
+                self.is_irq_pending = false;
+            }
+            0x5800..=0x5fff => {
+                self.irq_counter = (self.irq_counter & 0x00ff) | ((value as u16 & 0x7f) << 8);
+                self.is_irq_enabled = (value & 0x80) != 0;
+                self.is_irq_pending = false;
+            }
+            0x6000..=0x7fff if self.is_prg_ram_writable(addr) => {
+                self.ram[(addr as usize - 0x6000) % self.prg_ram_size] = value;
+            }
+            0x4020..=0x7fff => {}
+            0x8000..=0xdfff => {
+                let window = ((addr - 0x8000) >> 11) as usize;
+                self.reg_chr_banks[window] = value;
+                self.chr.set(window, value as usize);
+            }
+            0xe000..=0xe7ff => {
+                self.reg_sound_disable = (value & 0x40) != 0;
+                self.prg_rom.set(0, (value & 0x3f) as usize);
+            }
+            0xe800..=0xefff => self.prg_rom.set(1, (value & 0x3f) as usize),
+            0xf000..=0xf7ff => self.prg_rom.set(2, (value & 0x3f) as usize),
+            0xf800..=0xffff => {
+                self.reg_write_protect = value;
+                self.internal_ram_addr.set(value);
+            }
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    // CIRAM in the pattern tables is not supported, no released game uses it
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        let mut pages = [0; 4];
+        for (table, page) in pages.iter_mut().enumerate() {
+            *page = self.reg_chr_banks[8 + table] & 0x01;
+        }
+        Mirroring::Custom(pages)
+    }
+
+    fn read_nametable(&self, addr: u16) -> Option<u8> {
+        let window = 8 + ((addr >> 10) & 0x03) as usize;
+        if self.is_ciram(window) {
+            None
+        } else {
+            Some(self.chr.read(0x2000 + (addr & 0x0fff) as usize))
+        }
+    }
+
+    fn write_nametable(&mut self, addr: u16, value: u8) -> bool {
+        let window = 8 + ((addr >> 10) & 0x03) as usize;
+        if self.is_ciram(window) {
+            return false;
+        }
+        self.chr.write(0x2000 + (addr & 0x0fff) as usize, value);
+        true
+    }
+
+    fn is_irq_asserted(&self) -> bool {
+        self.is_irq_pending
+    }
+
+    fn tick(&mut self) {
+        // The counter stops once it reaches 0x7fff
+        if self.is_irq_enabled && self.irq_counter < 0x7fff {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7fff {
+                self.is_irq_pending = true;
+            }
+        }
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(self)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.ram[..self.prg_ram_size])
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        Some(&self.ram)
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.ram)
+    }
+}
+
+// The wavetables and the channel registers are in the internal RAM
+impl ExpansionAudio for Namco163 {
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::N163
+    }
+
+    fn tick_audio(&mut self) {
+        let prg_ram_size = self.prg_ram_size;
+        self.audio.tick(&mut self.ram[prg_ram_size..]);
+    }
+
+    fn audio_output(&self) -> i32 {
+        if self.reg_sound_disable {
+            0
+        } else {
+            self.audio.output as i32
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::mappers::numbered_banks;
+
+    fn test_mapper() -> Namco163 {
+        let prg_rom = numbered_banks(0x40000, 0x2000);
+        let chr = numbered_banks(0x40000, 0x0400);
+        Namco163::new(prg_rom, 0x2000, ChrMemory::rom(chr))
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut mapper = test_mapper();
+        mapper.write_prg(0xe000, 3);
+        mapper.write_prg(0xe800, 4);
+        mapper.write_prg(0xf000, 5);
+        assert_eq!(3, mapper.read_prg(0x8000));
+        assert_eq!(4, mapper.read_prg(0xa000));
+        assert_eq!(5, mapper.read_prg(0xc000));
+        assert_eq!(31, mapper.read_prg(0xe000));
+        mapper.write_prg(0xb800, 0x42);
+        assert_eq!(0x42, mapper.read_chr(0x1c00));
+
+        // Nametables from CHR ROM or CIRAM
+        mapper.write_prg(0xc000, 0x21);
+        mapper.write_prg(0xc800, 0xe1);
+        assert_eq!(Some(0x21), mapper.read_nametable(0x2000));
+        assert_eq!(None, mapper.read_nametable(0x2400));
+        assert_eq!(Mirroring::Custom([1, 1, 0, 0]), mapper.mirroring());
+
+        // PRG RAM is only writable with the right protection value
+        mapper.write_prg(0x6000, 0x55);
+        assert_eq!(0x00, mapper.read_prg(0x6000));
+        mapper.write_prg(0xf800, 0x40);
+        mapper.write_prg(0x6000, 0x55);
+        assert_eq!(0x55, mapper.read_prg(0x6000));
+    }
+
+    #[test]
+    fn test_internal_ram_and_irq() {
+        let mut mapper = test_mapper();
+        mapper.write_prg(0xf800, 0x90);
+        for value in 1..=3 {
+            mapper.write_prg(0x4800, value);
+        }
+        mapper.write_prg(0xf800, 0x10);
+        assert_eq!(1, mapper.read_prg(0x4800));
+        assert_eq!(1, mapper.read_prg(0x4800));
+        assert_eq!(&[1, 2, 3], &mapper.battery_ram().unwrap()[0x2010..0x2013]);
+
+        mapper.write_prg(0x5000, 0xfe);
+        mapper.write_prg(0x5800, 0xff);
+        assert_eq!(0xff, mapper.read_prg(0x5800));
+        mapper.tick();
+        assert!(mapper.is_irq_asserted());
+        mapper.tick();
+        assert_eq!(0xff, mapper.read_prg(0x5000));
+        mapper.write_prg(0x5000, 0x00);
+        assert!(!mapper.is_irq_asserted());
+    }
+
+    #[test]
+    fn test_audio() {
+        let mut mapper = test_mapper();
+        // A square wave of 8 samples at 0x00, played by channel 7 one sample per update
+        mapper.write_prg(0xf800, 0x80);
+        for value in [0xff, 0xff, 0x00, 0x00].iter().copied() {
+            mapper.write_prg(0x4800, value);
+        }
+        mapper.write_prg(0xf800, 0xf8);
+        for value in [0x00, 0x00, 0x00, 0x00, 0xf9, 0x00, 0x00, 0x0f]
+            .iter()
+            .copied()
+        {
+            mapper.write_prg(0x4800, value);
+        }
+        let outputs = |mapper: &mut Namco163| {
+            (0..15 * 64)
+                .map(|_| {
+                    mapper.tick_audio();
+                    mapper.audio_output()
+                })
+                .collect::<Vec<i32>>()
+        };
+        let single = outputs(&mut mapper);
+        assert!(single.contains(&105) && single.contains(&-120));
+
+        // With 8 channels, channel 7 is only heard for one slot out of eight
+        mapper.write_prg(0xf800, 0xff);
+        mapper.write_prg(0x4800, 0x7f);
+        let multiplexed = outputs(&mut mapper);
+        let silent = multiplexed.iter().filter(|&&output| output == 0).count();
+        assert!(silent >= multiplexed.len() * 7 / 8);
+    }
+}
@@ -0,0 +1,68 @@
+use crate::rom::nes::Mirroring;
+
+use super::{Banks, ChrMemory, Mapper};
+
+// iNES mapper 0
+#[allow(clippy::upper_case_acronyms)]
+pub struct NROM {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Banks,
+    mirroring: Mirroring,
+}
+
+impl NROM {
+    pub fn new(prg_rom: Vec<u8>, chr: ChrMemory, mirroring: Mirroring) -> Self {
+        NROM {
+            prg_rom,
+            prg_ram: vec![0xcc; 0x2000],
+            chr: Banks::new_chr(chr, 0x2000, 1),
+            mirroring,
+        }
+    }
+}
+
+impl Mapper for NROM {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr >> 12 {
+            0x6 | 0x7 => self.prg_ram[(addr & 0x1fff) as usize],
+            // 16K images are mirrored into 0xc000..0xffff
+            0x8..=0xf => self.prg_rom[(addr & 0x7fff) as usize % self.prg_rom.len()],
+            // Open bus
+            0x4 | 0x5 => (addr >> 8) as u8,
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        match addr >> 12 {
+            0x6 | 0x7 => self.prg_ram[(addr & 0x1fff) as usize] = value,
+            0x4 | 0x5 | 0x8..=0xf => {} // No registers
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
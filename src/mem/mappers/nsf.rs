@@ -0,0 +1,413 @@
+use crate::{
+    apu::{ExpansionAudio, ExpansionChip},
+    rom::{
+        nes::Mirroring,
+        nsf::{self, NSFFile},
+    },
+};
+
+use super::{fds_audio::FdsAudio, ChrMemory, Mapper, Namco163, FME7, MMC5, VRC6, VRC7};
+
+const DRIVER_ADDR: u16 = 0x4100;
+const RESET_ADDR: u16 = 0x4100;
+const IRQ_ADDR: u16 = 0x4120;
+const NMI_ADDR: u16 = 0x4140;
+// Variables read by the driver
+const TRACK_ADDR: u16 = 0x41f0;
+const REGION_ADDR: u16 = 0x41f1;
+const INIT_JUMP_ADDR: u16 = 0x41f2;
+const PLAY_JUMP_ADDR: u16 = 0x41f5;
+// Written by the driver
+const ACKNOWLEDGE_ADDR: u16 = 0x41f8;
+const INIT_DONE_ADDR: u16 = 0x41f9;
+
+// Calls INIT once with the track and the region, then PLAY from the IRQ of the play timer
+#[rustfmt::skip]
+const DRIVER: [(u16, &[u8]); 3] = [
+    (RESET_ADDR, &[
+        0x78,             // SEI
+        0xd8,             // CLD
+        0xa2, 0xff,       // LDX #$ff
+        0x9a,             // TXS
+        0xad, 0xf0, 0x41, // LDA TRACK_ADDR
+        0xae, 0xf1, 0x41, // LDX REGION_ADDR
+        0x20, 0xf2, 0x41, // JSR INIT_JUMP_ADDR
+        0x8d, 0xf9, 0x41, // STA INIT_DONE_ADDR
+        0x58,             // CLI
+        0x4c, 0x12, 0x41, // JMP * (0x4112)
+    ]),
+    (IRQ_ADDR, &[
+        0x48,             // PHA
+        0x8a,             // TXA
+        0x48,             // PHA
+        0x98,             // TYA
+        0x48,             // PHA
+        0x8d, 0xf8, 0x41, // STA ACKNOWLEDGE_ADDR
+        0x20, 0xf5, 0x41, // JSR PLAY_JUMP_ADDR
+        0x68,             // PLA
+        0xa8,             // TAY
+        0x68,             // PLA
+        0xaa,             // TAX
+        0x68,             // PLA
+        0x40,             // RTI
+    ]),
+    (NMI_ADDR, &[
+        0x40,             // RTI
+    ]),
+];
+
+// NTSC CPU clock, the play period is given in microseconds
+const CPU_CLOCK: u64 = 1_789_773;
+
+// Expansion chips live on the same boards as in the games, with only their audio ports used
+fn chip_ports(chip: ExpansionChip, addr: u16) -> bool {
+    match chip {
+        ExpansionChip::VRC6 => matches!(addr, 0x9000..=0x9003 | 0xa000..=0xa002 | 0xb000..=0xb002),
+        ExpansionChip::VRC7 => matches!(addr, 0x9010 | 0x9030),
+        ExpansionChip::MMC5 => matches!(addr, 0x5000..=0x5015 | 0x5205..=0x5206),
+        ExpansionChip::N163 => matches!(addr, 0x4800..=0x4fff | 0xf800..=0xffff),
+        ExpansionChip::Sunsoft5B => matches!(addr, 0xc000 | 0xe000),
+        ExpansionChip::FDS => false,
+    }
+}
+
+// Header flag of each chip, in the order of the flags
+const CHIPS: [(u8, ExpansionChip); 6] = [
+    (nsf::EXPANSION_VRC6, ExpansionChip::VRC6),
+    (nsf::EXPANSION_VRC7, ExpansionChip::VRC7),
+    (nsf::EXPANSION_FDS, ExpansionChip::FDS),
+    (nsf::EXPANSION_MMC5, ExpansionChip::MMC5),
+    (nsf::EXPANSION_N163, ExpansionChip::N163),
+    (nsf::EXPANSION_SUNSOFT5B, ExpansionChip::Sunsoft5B),
+];
+
+// FDS audio isn't tied to a mapper, see NSF::fds
+fn new_chip(chip: ExpansionChip) -> Option<Box<dyn Mapper>> {
+    let chr = ChrMemory::ram(0x2000);
+    let mapper: Box<dyn Mapper> = match chip {
+        ExpansionChip::VRC6 => Box::new(VRC6::new(vec![], chr, false)),
+        ExpansionChip::VRC7 => Box::new(VRC7::new(vec![], chr, 0x18)),
+        ExpansionChip::MMC5 => Box::new(MMC5::new(vec![], 0, chr)),
+        ExpansionChip::N163 => Box::new(Namco163::new(vec![], 0, chr)),
+        ExpansionChip::Sunsoft5B => Box::new(FME7::new(vec![], 0, chr)),
+        ExpansionChip::FDS => return None,
+    };
+    Some(mapper)
+}
+
+// Music player for NSF files, with the driver in the unused space at 0x4100
+#[allow(clippy::upper_case_acronyms)]
+pub struct NSF {
+    // Program data padded to start at a 4K boundary
+    image: Vec<u8>,
+    // Copy of the image that FDS tunes can write to, they run from RAM
+    prg: Vec<u8>,
+    bank_init: [usize; 10],
+    // 4K windows for 0x6000..0xffff, the first two are only used by FDS tunes
+    banks: [usize; 10],
+    prg_ram: Vec<u8>,
+    exram: Vec<u8>,
+    chr: Vec<u8>,
+
+    track: u8,
+    is_pal: bool,
+    init_addr: u16,
+    play_addr: u16,
+    play_period: u64,
+    play_timer: u64,
+    is_playing: bool,
+    is_irq_pending: bool,
+
+    expansion_chips: u8,
+    chips: Vec<(ExpansionChip, Box<dyn Mapper>)>,
+    fds: Option<FdsAudio>,
+    // Mix of all the chips, updated every cycle
+    output: f32,
+}
+
+impl NSF {
+    pub fn new(nsf: &NSFFile) -> Self {
+        let has_fds = (nsf.expansion_chips & nsf::EXPANSION_FDS) != 0;
+        let mut bank_init = [0; 10];
+        let padding = if nsf.uses_bankswitching() {
+            for (i, &bank) in nsf.bank_init.iter().enumerate() {
+                bank_init[2 + i] = bank as usize;
+            }
+            // FDS tunes start with the banks of 0xe000 and 0xf000 at 0x6000 and 0x7000
+            bank_init[0] = bank_init[8];
+            bank_init[1] = bank_init[9];
+            (nsf.load_addr & 0x0fff) as usize
+        } else if has_fds {
+            for (i, bank) in bank_init.iter_mut().enumerate() {
+                *bank = i;
+            }
+            nsf.load_addr.saturating_sub(0x6000) as usize
+        } else {
+            for (i, bank) in bank_init.iter_mut().enumerate().skip(2) {
+                *bank = i - 2;
+            }
+            nsf.load_addr.saturating_sub(0x8000) as usize
+        };
+        let mut image = vec![0x00; padding];
+        image.extend_from_slice(&nsf.data);
+        image.resize(((image.len() + 0x0fff) & !0x0fff).max(0x1000), 0x00);
+        let mut mapper = NSF {
+            prg: image.clone(),
+            image,
+            bank_init,
+            banks: bank_init,
+            prg_ram: vec![0x00; 0x2000],
+            exram: vec![0x00; 0x400],
+            chr: vec![0x00; 0x2000],
+            track: 0,
+            is_pal: nsf.is_pal,
+            init_addr: nsf.init_addr,
+            play_addr: nsf.play_addr,
+            play_period: (nsf.play_period as u64 * CPU_CLOCK / 1_000_000).max(1),
+            play_timer: 0,
+            is_playing: false,
+            is_irq_pending: false,
+            expansion_chips: nsf.expansion_chips,
+            chips: vec![],
+            fds: None,
+            output: 0.0,
+        };
+        mapper.select_track(nsf.starting_track);
+        mapper
+    }
+
+    fn has_fds(&self) -> bool {
+        self.fds.is_some()
+    }
+
+    fn has_chip(&self, flag: u8) -> bool {
+        (self.expansion_chips & flag) != 0
+    }
+
+    fn prg_index(&self, addr: u16) -> usize {
+        let window = ((addr - 0x6000) >> 12) as usize;
+        let bank_count = self.prg.len() / 0x1000;
+        (self.banks[window] % bank_count) * 0x1000 + (addr & 0x0fff) as usize
+    }
+
+    fn read_driver(&self, addr: u16) -> u8 {
+        let [init_lo, init_hi] = self.init_addr.to_le_bytes();
+        let [play_lo, play_hi] = self.play_addr.to_le_bytes();
+        match addr {
+            TRACK_ADDR => self.track,
+            REGION_ADDR => self.is_pal as u8,
+            // JMP INIT and JMP PLAY
+            INIT_JUMP_ADDR | PLAY_JUMP_ADDR => 0x4c,
+            0x41f3 => init_lo,
+            0x41f4 => init_hi,
+            0x41f6 => play_lo,
+            0x41f7 => play_hi,
+            _ => DRIVER
+                .iter()
+                .find_map(|(start, code)| code.get(addr.wrapping_sub(*start) as usize))
+                .copied()
+                .unwrap_or(0x00),
+        }
+    }
+}
+
+impl Mapper for NSF {
+    fn read_prg(&self, addr: u16) -> u8 {
+        // The vectors point to the driver
+        let vector = match addr & !0x01 {
+            0xfffa => Some(NMI_ADDR),
+            0xfffc => Some(RESET_ADDR),
+            0xfffe => Some(IRQ_ADDR),
+            _ => None,
+        };
+        if let Some(vector) = vector {
+            return vector.to_le_bytes()[(addr & 0x01) as usize];
+        }
+        if let Some((_, chip)) = self
+            .chips
+            .iter()
+            .find(|(chip, _)| addr < 0x6000 && chip_ports(*chip, addr))
+        {
+            return chip.read_prg(addr);
+        }
+        match addr {
+            0x4040..=0x4097 if self.has_fds() => self
+                .fds
+                .as_ref()
+                .and_then(|fds| fds.read(addr))
+                .unwrap_or((addr >> 8) as u8),
+            DRIVER_ADDR..=0x41ff => self.read_driver(addr),
+            0x5c00..=0x5ff5 if self.has_chip(nsf::EXPANSION_MMC5) => {
+                self.exram[(addr & 0x03ff) as usize]
+            }
+            0x6000..=0x7fff if self.has_fds() => self.prg[self.prg_index(addr)],
+            0x6000..=0x7fff => self.prg_ram[(addr & 0x1fff) as usize],
+            0x8000..=0xffff => self.prg[self.prg_index(addr)],
+            // Open bus
+            _ => (addr >> 8) as u8,
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        for (chip, mapper) in &mut self.chips {
+            if chip_ports(*chip, addr) {
+                mapper.write_prg(addr, value);
+            }
+        }
+        match addr {
+            0x4040..=0x4097 => {
+                if let Some(fds) = &mut self.fds {
+                    fds.write(addr, value);
+                }
+            }
+            ACKNOWLEDGE_ADDR => self.is_irq_pending = false,
+            INIT_DONE_ADDR => {
+                self.is_playing = true;
+                self.play_timer = 0;
+            }
+            0x5c00..=0x5ff5 => self.exram[(addr & 0x03ff) as usize] = value,
+            0x5ff6..=0x5ff7 if !self.has_fds() => {}
+            0x5ff6..=0x5fff => self.banks[(addr - 0x5ff6) as usize] = value as usize,
+            // FDS tunes can write anywhere in their RAM
+            0x6000..=0xdfff if self.has_fds() => {
+                let idx = self.prg_index(addr);
+                self.prg[idx] = value;
+            }
+            0x6000..=0x7fff => self.prg_ram[(addr & 0x1fff) as usize] = value,
+            _ => {}
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr[addr as usize & 0x1fff]
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr[addr as usize & 0x1fff] = value;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+
+    fn is_irq_asserted(&self) -> bool {
+        self.is_irq_pending
+    }
+
+    fn tick(&mut self) {
+        if !self.is_playing {
+            return;
+        }
+        self.play_timer += 1;
+        if self.play_timer >= self.play_period {
+            self.play_timer = 0;
+            self.is_irq_pending = true;
+        }
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        if self.expansion_chips & 0x3f != 0 {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    // Everything but the program data starts over, the driver calls INIT again after the reset
+    fn select_track(&mut self, track: usize) {
+        self.track = track as u8;
+        self.prg.copy_from_slice(&self.image);
+        self.banks = self.bank_init;
+        self.prg_ram.fill(0x00);
+        self.exram.fill(0x00);
+        self.is_playing = false;
+        self.play_timer = 0;
+        self.is_irq_pending = false;
+        self.chips = CHIPS
+            .iter()
+            .filter(|(flag, _)| self.has_chip(*flag))
+            .filter_map(|&(_, chip)| Some((chip, new_chip(chip)?)))
+            .collect();
+        self.fds = Some(FdsAudio::new()).filter(|_| self.has_chip(nsf::EXPANSION_FDS));
+        self.output = 0.0;
+    }
+}
+
+impl ExpansionAudio for NSF {
+    // Tunes can use several chips, they are mixed here instead of by the APU
+    fn chip(&self) -> ExpansionChip {
+        CHIPS
+            .iter()
+            .find(|(flag, _)| self.has_chip(*flag))
+            .map_or(ExpansionChip::FDS, |&(_, chip)| chip)
+    }
+
+    fn tick_audio(&mut self) {
+        self.output = 0.0;
+        for (_, chip) in &mut self.chips {
+            if let Some(audio) = chip.expansion_audio() {
+                audio.tick_audio();
+                self.output += audio.mixed_output();
+            }
+        }
+        if let Some(fds) = &mut self.fds {
+            fds.tick_audio();
+            self.output += fds.mixed_output();
+        }
+    }
+
+    // In units of the first chip
+    fn audio_output(&self) -> i32 {
+        (self.output / self.chip().level()) as i32
+    }
+
+    fn mixed_output(&self) -> f32 {
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nes::NES;
+
+    #[test]
+    fn test_driver() {
+        #[rustfmt::skip]
+        let data = vec![
+            0x85, 0x10, // INIT: STA $10
+            0x60,       // RTS
+            0xe6, 0x11, // PLAY: INC $11
+            0x60,       // RTS
+        ];
+        let nsf = NSFFile {
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            tracks: vec![Default::default(); 3],
+            starting_track: 1,
+            load_addr: 0x8000,
+            init_addr: 0x8000,
+            play_addr: 0x8003,
+            bank_init: [0; 8],
+            is_pal: false,
+            play_period: 16639,
+            expansion_chips: 0,
+            data,
+        };
+        let period = 16639 * CPU_CLOCK / 1_000_000;
+        let mut nes = NES::new_nsf_headless(&nsf);
+        for _ in 0..period * 21 / 2 * 12 {
+            nes.tick();
+        }
+        assert_eq!(1, nes.mmap.ram[0x10]);
+        assert_eq!(10, nes.mmap.ram[0x11]);
+
+        nes.select_track(2);
+        for _ in 0..period / 2 * 12 {
+            nes.tick();
+        }
+        assert_eq!(2, nes.mmap.ram[0x10]);
+        assert_eq!(0, nes.mmap.ram[0x11]);
+    }
+}
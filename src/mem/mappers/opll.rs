@@ -0,0 +1,383 @@
+// Yamaha YM2413 (OPLL) FM synthesis as found in the VRC7, which only has 6 channels and no rhythm mode
+
+use lazy_static::lazy_static;
+
+// VRC7 built-in instruments, instrument 0 is the custom one from registers 0x00..0x07
+const PATCHES: [[u8; 8]; 16] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x03, 0x21, 0x05, 0x06, 0xe8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0d, 0xd8, 0xf6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xfa, 0xb2, 0x20, 0x12],
+    [0x31, 0x61, 0x0c, 0x07, 0xa8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1e, 0x06, 0xe1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xa3, 0xe2, 0xf4, 0xf4],
+    [0x21, 0x61, 0x1d, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xa2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xb5, 0x01, 0x0f, 0x0f, 0xa8, 0xa5, 0x51, 0x02],
+    [0x17, 0xc1, 0x24, 0x07, 0xf8, 0xf8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xd3, 0x05, 0xc9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0c, 0x00, 0x94, 0xc0, 0x33, 0xf6],
+    [0x21, 0x72, 0x0d, 0x00, 0xc1, 0xd5, 0x56, 0x06],
+];
+
+// Frequency multipliers times two, so that 0 means 1/2
+const MULTIPLIERS: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+// Key scale level attenuation in the highest octave, indexed by the top 4 bits of the F-number
+const KEY_SCALE_LEVELS: [i32; 16] = [
+    0, 24, 32, 37, 40, 43, 45, 47, 48, 50, 51, 52, 53, 54, 55, 56,
+];
+
+// Envelope increments over 8 consecutive steps for the 4 fine rates
+const ENVELOPE_STEPS: [[u32; 8]; 4] = [
+    [0, 1, 0, 1, 0, 1, 0, 1],
+    [0, 1, 0, 1, 1, 1, 0, 1],
+    [0, 1, 1, 1, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 1],
+];
+
+const VIBRATO_STEPS: [i32; 8] = [0, 1, 2, 1, 0, -1, -2, -1];
+
+// Attenuation is counted in 0.375dB steps
+const MAX_ATTENUATION: u32 = 127;
+
+lazy_static! {
+    // Quarter of a sine wave as -log2(sin(x)) in 1/256 steps
+    static ref LOG_SIN: Vec<u32> = (0..256)
+        .map(|i| {
+            let x = (i as f64 + 0.5) * std::f64::consts::PI / 512.0;
+            (-x.sin().log2() * 256.0).round() as u32
+        })
+        .collect();
+    // Fractional part of the inverse, scaled to 12 bits
+    static ref EXP: Vec<u32> = (0..256)
+        .map(|i| (2f64.powf(-(i as f64) / 256.0) * 4095.0).round() as u32)
+        .collect();
+}
+
+// Output of one operator for a 10-bit phase and an attenuation
+fn operator_output(phase: i32, attenuation: u32, is_rectified: bool) -> i32 {
+    let phase = (phase & 0x3ff) as usize;
+    let is_negative = (phase & 0x200) != 0;
+    // Fully attenuated operators are muted, the rectified wave is silent during its second half
+    if attenuation >= MAX_ATTENUATION || (is_negative && is_rectified) {
+        return 0;
+    }
+    let quarter = if (phase & 0x100) == 0 {
+        phase & 0xff
+    } else {
+        0xff - (phase & 0xff)
+    };
+    let level = LOG_SIN[quarter] + (attenuation << 4);
+    let value = (EXP[(level & 0xff) as usize] >> (level >> 8)) as i32;
+    if is_negative {
+        -value
+    } else {
+        value
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Clone, Copy)]
+struct Operator {
+    // 18-bit phase, the top 10 bits index the sine wave
+    phase: u32,
+    envelope: u32,
+    state: EnvelopeState,
+    // Last two outputs, used for the modulator feedback
+    outputs: [i32; 2],
+}
+
+impl Operator {
+    fn new() -> Self {
+        Operator {
+            phase: 0,
+            envelope: MAX_ATTENUATION,
+            state: EnvelopeState::Release,
+            outputs: [0; 2],
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.state = EnvelopeState::Attack;
+        self.phase = 0;
+    }
+
+    fn key_off(&mut self) {
+        self.state = EnvelopeState::Release;
+    }
+
+    fn clock_phase(&mut self, flags: u8, fnum: u16, block: u8, vibrato: i32) {
+        let mut fnum = fnum as i32;
+        if (flags & 0x40) != 0 {
+            fnum += (fnum >> 7) * vibrato;
+        }
+        let increment = (((fnum as u32) << block) * MULTIPLIERS[(flags & 0x0f) as usize]) >> 2;
+        self.phase = (self.phase + increment) & 0x3ffff;
+    }
+
+    fn clock_envelope(&mut self, rate: u32, sustain_level: u32, counter: u32) {
+        let increment = envelope_increment(rate, counter);
+        match self.state {
+            // The attack is exponential and the highest rates are instantaneous
+            EnvelopeState::Attack if rate >= 60 => self.envelope = 0,
+            EnvelopeState::Attack => {
+                let step = (self.envelope * increment + 7) >> 3;
+                self.envelope -= step.min(self.envelope);
+            }
+            _ => self.envelope = (self.envelope + increment).min(MAX_ATTENUATION),
+        }
+        match self.state {
+            EnvelopeState::Attack if self.envelope == 0 => self.state = EnvelopeState::Decay,
+            EnvelopeState::Decay if self.envelope >= sustain_level => {
+                self.state = EnvelopeState::Sustain
+            }
+            _ => {}
+        }
+    }
+}
+
+// Envelope change for a 6-bit effective rate on the given envelope clock
+fn envelope_increment(rate: u32, counter: u32) -> u32 {
+    if rate < 4 {
+        return 0;
+    }
+    let steps = &ENVELOPE_STEPS[(rate & 0x03) as usize];
+    let coarse = rate >> 2;
+    if coarse < 13 {
+        let shift = 13 - coarse;
+        if (counter & ((1 << shift) - 1)) != 0 {
+            return 0;
+        }
+        steps[((counter >> shift) & 0x07) as usize]
+    } else {
+        steps[(counter & 0x07) as usize] << (coarse - 12)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Channel {
+    fnum: u16,
+    block: u8,
+    is_key_on: bool,
+    is_sustain_on: bool,
+    volume: u8,
+    instrument: u8,
+    // Modulator and carrier
+    operators: [Operator; 2],
+}
+
+impl Channel {
+    fn new() -> Self {
+        Channel {
+            fnum: 0,
+            block: 0,
+            is_key_on: false,
+            is_sustain_on: false,
+            volume: 0,
+            instrument: 0,
+            operators: [Operator::new(); 2],
+        }
+    }
+
+    fn set_key_on(&mut self, is_key_on: bool) {
+        if is_key_on && !self.is_key_on {
+            self.operators.iter_mut().for_each(Operator::key_on);
+        } else if !is_key_on && self.is_key_on {
+            self.operators.iter_mut().for_each(Operator::key_off);
+        }
+        self.is_key_on = is_key_on;
+    }
+
+    fn envelope_rate(&self, patch: &[u8; 8], op: usize) -> u32 {
+        let flags = patch[op];
+        let is_sustained = (flags & 0x20) != 0;
+        let release_rate = patch[6 + op] & 0x0f;
+        let rate = match self.operators[op].state {
+            EnvelopeState::Attack => patch[4 + op] >> 4,
+            EnvelopeState::Decay => patch[4 + op] & 0x0f,
+            // Sustained tones hold the level, percussive ones keep decaying
+            EnvelopeState::Sustain if is_sustained => 0,
+            EnvelopeState::Sustain => release_rate,
+            EnvelopeState::Release if self.is_sustain_on => 5,
+            EnvelopeState::Release if is_sustained => release_rate,
+            EnvelopeState::Release => 7,
+        } as u32;
+        if rate == 0 {
+            return 0;
+        }
+        // Higher notes have faster envelopes
+        let key_scale = ((self.block << 1) | (self.fnum >> 8) as u8) as u32;
+        let key_scale = if (flags & 0x10) != 0 {
+            key_scale
+        } else {
+            key_scale >> 2
+        };
+        (rate * 4 + key_scale).min(63)
+    }
+
+    fn attenuation(&self, patch: &[u8; 8], op: usize, tremolo: u32) -> u32 {
+        let total_level = if op == 0 {
+            (patch[2] & 0x3f) as u32 * 2
+        } else {
+            self.volume as u32 * 8
+        };
+        let key_scale = KEY_SCALE_LEVELS[(self.fnum >> 5) as usize] - (7 - self.block as i32) * 8;
+        let key_scale = match patch[2 + op] >> 6 {
+            0 => 0,
+            1 => key_scale.max(0) >> 1,
+            2 => key_scale.max(0),
+            _ => key_scale.max(0) << 1,
+        } as u32;
+        let tremolo = if (patch[op] & 0x80) != 0 { tremolo } else { 0 };
+        (self.operators[op].envelope + total_level + key_scale + tremolo).min(MAX_ATTENUATION)
+    }
+
+    fn clock(&mut self, patch: &[u8; 8], counter: u32, tremolo: u32, vibrato: i32) -> i32 {
+        for op in 0..2 {
+            let rate = self.envelope_rate(patch, op);
+            let sustain_level = ((patch[6 + op] >> 4) as u32) << 3;
+            let operator = &mut self.operators[op];
+            operator.clock_envelope(rate, sustain_level, counter);
+            operator.clock_phase(patch[op], self.fnum, self.block, vibrato);
+        }
+
+        let feedback = patch[3] & 0x07;
+        let modulator = &self.operators[0];
+        let feedback = if feedback == 0 {
+            0
+        } else {
+            (modulator.outputs[0] + modulator.outputs[1]) >> (9 - feedback)
+        };
+        let output = operator_output(
+            (modulator.phase >> 8) as i32 + feedback,
+            self.attenuation(patch, 0, tremolo),
+            (patch[3] & 0x08) != 0,
+        );
+        let modulator = &mut self.operators[0];
+        modulator.outputs = [modulator.outputs[1], output];
+
+        let carrier = &self.operators[1];
+        operator_output(
+            (carrier.phase >> 8) as i32 + output,
+            self.attenuation(patch, 1, tremolo),
+            (patch[3] & 0x10) != 0,
+        )
+    }
+}
+
+pub struct Opll {
+    address: u8,
+    custom_patch: [u8; 8],
+    channels: [Channel; 6],
+    // Sample counters driving the envelopes, the tremolo and the vibrato
+    counter: u32,
+}
+
+impl Opll {
+    pub fn new() -> Self {
+        Opll {
+            address: 0,
+            custom_patch: [0; 8],
+            channels: [Channel::new(); 6],
+            counter: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Opll::new();
+    }
+
+    pub fn write_address(&mut self, value: u8) {
+        self.address = value;
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        let channel = (self.address & 0x0f) as usize;
+        match self.address {
+            0x00..=0x07 => self.custom_patch[self.address as usize] = value,
+            0x10..=0x15 => {
+                let channel = &mut self.channels[channel];
+                channel.fnum = (channel.fnum & 0x100) | value as u16;
+            }
+            0x20..=0x25 => {
+                let channel = &mut self.channels[channel];
+                channel.fnum = (channel.fnum & 0xff) | ((value as u16 & 0x01) << 8);
+                channel.block = (value >> 1) & 0x07;
+                channel.is_sustain_on = (value & 0x20) != 0;
+                channel.set_key_on((value & 0x10) != 0);
+            }
+            0x30..=0x35 => {
+                let channel = &mut self.channels[channel];
+                channel.volume = value & 0x0f;
+                channel.instrument = value >> 4;
+            }
+            _ => {}
+        }
+    }
+
+    // Produces the next sample, the sum of all channels
+    pub fn clock(&mut self) -> i32 {
+        self.counter = self.counter.wrapping_add(1);
+        // 3.7Hz triangle of up to 4.8dB
+        let tremolo_step = (self.counter >> 6) % 210;
+        let tremolo = if tremolo_step < 105 {
+            tremolo_step
+        } else {
+            209 - tremolo_step
+        } >> 3;
+        // 6.1Hz vibrato
+        let vibrato = VIBRATO_STEPS[((self.counter >> 10) & 0x07) as usize];
+
+        let mut output = 0;
+        for channel in self.channels.iter_mut() {
+            let patch = if channel.instrument == 0 {
+                &self.custom_patch
+            } else {
+                &PATCHES[channel.instrument as usize]
+            };
+            output += channel.clock(patch, self.counter, tremolo, vibrato);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn max_output(opll: &mut Opll, samples: usize) -> i32 {
+        (0..samples).map(|_| opll.clock().abs()).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn test_key_on_off() {
+        let mut opll = Opll::new();
+        assert_eq!(0, max_output(&mut opll, 100));
+
+        // A4 on the flute, with a sustained envelope
+        let mut write = |address, value| {
+            opll.write_address(address);
+            opll.write_data(value);
+        };
+        write(0x10, 0x22);
+        write(0x30, 0x40);
+        write(0x20, 0x19);
+        assert!(max_output(&mut opll, 2000) > 1000);
+
+        // The release of a sustained tone fades it out
+        opll.write_address(0x20);
+        opll.write_data(0x09);
+        max_output(&mut opll, 100000);
+        assert_eq!(0, max_output(&mut opll, 100));
+    }
+}
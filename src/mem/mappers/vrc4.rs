@@ -0,0 +1,314 @@
+use crate::rom::nes::{Mirroring, NESHeader};
+
+use super::{vrc_irq::VrcIrq, Banks, ChrMemory, Mapper};
+
+// Boards differ in which CPU address lines are connected to the register select pins
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VRC4Board {
+    VRC2a,
+    VRC2b,
+    VRC2c,
+    VRC4a,
+    VRC4b,
+    VRC4c,
+    VRC4d,
+    VRC4e,
+    VRC4f,
+    // iNES headers don't tell the variants apart, both wirings are decoded at once
+    VRC4a_c,
+    VRC4b_d,
+    VRC4e_f,
+}
+
+impl VRC4Board {
+    pub fn detect(header: &NESHeader) -> Self {
+        match (header.mapper, header.submapper) {
+            (21, 1) => VRC4Board::VRC4a,
+            (21, 2) => VRC4Board::VRC4c,
+            (21, _) => VRC4Board::VRC4a_c,
+            (22, _) => VRC4Board::VRC2a,
+            (23, 1) => VRC4Board::VRC4f,
+            (23, 2) => VRC4Board::VRC4e,
+            (23, 3) => VRC4Board::VRC2b,
+            (23, _) => VRC4Board::VRC4e_f,
+            (25, 1) => VRC4Board::VRC4b,
+            (25, 2) => VRC4Board::VRC4d,
+            (25, 3) => VRC4Board::VRC2c,
+            (_, _) => VRC4Board::VRC4b_d,
+        }
+    }
+
+    pub fn is_vrc2(&self) -> bool {
+        matches!(self, VRC4Board::VRC2a | VRC4Board::VRC2b | VRC4Board::VRC2c)
+    }
+
+    // The iNES variants of mappers 23 and 25 may be a VRC2 as well
+    pub fn has_microwire_latch(&self) -> bool {
+        self.is_vrc2() || matches!(self, VRC4Board::VRC4b_d | VRC4Board::VRC4e_f)
+    }
+
+    // Address bits connected to the A0 and A1 register select pins
+    fn address_lines(&self) -> (u16, u16) {
+        match self {
+            VRC4Board::VRC2a => (0x02, 0x01),
+            VRC4Board::VRC2b => (0x01, 0x02),
+            VRC4Board::VRC2c => (0x02, 0x01),
+            VRC4Board::VRC4a => (0x02, 0x04),
+            VRC4Board::VRC4b => (0x02, 0x01),
+            VRC4Board::VRC4c => (0x40, 0x80),
+            VRC4Board::VRC4d => (0x08, 0x04),
+            VRC4Board::VRC4e => (0x04, 0x08),
+            VRC4Board::VRC4f => (0x01, 0x02),
+            VRC4Board::VRC4a_c => (0x42, 0x84),
+            VRC4Board::VRC4b_d => (0x0a, 0x05),
+            VRC4Board::VRC4e_f => (0x05, 0x0a),
+        }
+    }
+}
+
+// iNES mappers 21, 22, 23 and 25, VRC2 is a subset of VRC4
+#[allow(clippy::upper_case_acronyms)]
+pub struct VRC4 {
+    board: VRC4Board,
+    prg_rom: Banks,
+    prg_ram: Vec<u8>,
+    chr: Banks,
+
+    // Internal registers
+    reg_prg_banks: [u8; 2],
+    reg_chr_banks: [u16; 8],
+    reg_mirroring: u8,
+    reg_control: u8,
+    // VRC2 boards without PRG RAM have a one bit latch at 0x6000..0x6fff
+    microwire_latch: u8,
+
+    irq: VrcIrq,
+}
+
+impl VRC4 {
+    pub fn new(board: VRC4Board, prg_rom: Vec<u8>, prg_ram_size: usize, chr: ChrMemory) -> Self {
+        let mut mapper = VRC4 {
+            board,
+            prg_rom: Banks::new(prg_rom, 0x2000, 4),
+            prg_ram: vec![0x00; prg_ram_size],
+            chr: Banks::new_chr(chr, 0x0400, 8),
+            reg_prg_banks: [0, 0],
+            reg_chr_banks: [0; 8],
+            reg_mirroring: 0,
+            reg_control: 0,
+            microwire_latch: 0,
+            irq: VrcIrq::new(),
+        };
+        mapper.update_banks();
+        mapper
+    }
+
+    fn update_banks(&mut self) {
+        let second_last = self.prg_rom.bank_count().saturating_sub(2);
+        let r0 = (self.reg_prg_banks[0] & 0x1f) as usize;
+        if (self.reg_control & 0x02) == 0 {
+            self.prg_rom.set(0, r0);
+            self.prg_rom.set(2, second_last);
+        } else {
+            self.prg_rom.set(0, second_last);
+            self.prg_rom.set(2, r0);
+        }
+        self.prg_rom.set(1, (self.reg_prg_banks[1] & 0x1f) as usize);
+        self.prg_rom.set(3, second_last + 1);
+
+        for (window, bank) in self.reg_chr_banks.iter().enumerate() {
+            // VRC2a ignores the lowest bit of the bank number
+            let bank = if self.board == VRC4Board::VRC2a {
+                bank >> 1
+            } else {
+                *bank
+            };
+            self.chr.set(window, bank as usize);
+        }
+    }
+
+    // Decodes the register index (0..3) from the board specific address lines
+    fn register(&self, addr: u16) -> u16 {
+        let (a0, a1) = self.board.address_lines();
+        (((addr & a0) != 0) as u16) | ((((addr & a1) != 0) as u16) << 1)
+    }
+}
+
+impl Mapper for VRC4 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr >> 12 {
+            0x6 | 0x7 if !self.prg_ram.is_empty() => {
+                self.prg_ram[(addr as usize & 0x1fff) % self.prg_ram.len()]
+            }
+            // The rest of the bits are open bus
+            0x6 if self.board.has_microwire_latch() => {
+                ((addr >> 8) as u8 & 0xfe) | self.microwire_latch
+            }
+            // Open bus
+            0x4..=0x7 => (addr >> 8) as u8,
+            0x8..=0xf => self.prg_rom.read((addr & 0x7fff) as usize),
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        let reg = self.register(addr);
+        match (addr >> 12, reg) {
+            (0x6 | 0x7, _) if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize & 0x1fff) % len] = value;
+            }
+            (0x6, _) if self.board.has_microwire_latch() => self.microwire_latch = value & 0x01,
+            (0x4..=0x7, _) => {}
+            (0x8, _) => {
+                self.reg_prg_banks[0] = value;
+                self.update_banks();
+            }
+            (0x9, 0 | 1) => self.reg_mirroring = value,
+            (0x9, _) if self.board.is_vrc2() => self.reg_mirroring = value,
+            (0x9, 2) => {
+                self.reg_control = value;
+                self.update_banks();
+            }
+            (0x9, _) => {}
+            (0xa, _) => {
+                self.reg_prg_banks[1] = value;
+                self.update_banks();
+            }
+            (0xb..=0xe, _) => {
+                // Each bank register is written one nibble at a time
+                let window = (((addr >> 12) - 0xb) * 2 + (reg >> 1)) as usize;
+                let bank = &mut self.reg_chr_banks[window];
+                if (reg & 0x01) == 0 {
+                    *bank = (*bank & 0x1f0) | (value as u16 & 0x0f);
+                } else {
+                    *bank = (*bank & 0x00f) | ((value as u16 & 0x1f) << 4);
+                }
+                self.update_banks();
+            }
+            // VRC2 has no IRQ counter
+            (0xf, _) if self.board.is_vrc2() => {}
+            (0xf, 0) => self.irq.write_latch_lo(value),
+            (0xf, 1) => self.irq.write_latch_hi(value),
+            (0xf, 2) => self.irq.write_control(value),
+            (0xf, _) => self.irq.acknowledge(),
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // VRC2 only has the lower bit
+        let mask = if self.board.is_vrc2() { 0x01 } else { 0x03 };
+        match self.reg_mirroring & mask {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenA,
+            _ => Mirroring::SingleScreenB,
+        }
+    }
+
+    fn is_irq_asserted(&self) -> bool {
+        self.irq.is_pending()
+    }
+
+    fn tick(&mut self) {
+        self.irq.tick();
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::mappers::numbered_banks;
+
+    fn test_mapper(board: VRC4Board, prg_ram_size: usize) -> VRC4 {
+        let prg_rom = numbered_banks(0x40000, 0x2000);
+        let chr = numbered_banks(0x80000, 0x0400);
+        VRC4::new(board, prg_rom, prg_ram_size, ChrMemory::rom(chr))
+    }
+
+    #[test]
+    fn test_address_lines() {
+        let mut mapper = test_mapper(VRC4Board::VRC4c, 0x2000);
+        mapper.write_prg(0x8000, 3);
+        mapper.write_prg(0xa000, 4);
+        assert_eq!(3, mapper.read_prg(0x8000));
+        assert_eq!(30, mapper.read_prg(0xc000));
+        // PRG swap mode through 0x9080
+        mapper.write_prg(0x9080, 0x02);
+        assert_eq!(30, mapper.read_prg(0x8000));
+        assert_eq!(4, mapper.read_prg(0xa000));
+        assert_eq!(3, mapper.read_prg(0xc000));
+        assert_eq!(31, mapper.read_prg(0xe000));
+
+        mapper.write_prg(0xc080, 0x05);
+        mapper.write_prg(0xc0c0, 0x12);
+        assert_eq!(0x25, mapper.read_chr(0x0c00));
+        mapper.write_prg(0x9000, 0x03);
+        assert_eq!(Mirroring::SingleScreenB, mapper.mirroring());
+
+        // Both wirings work when the submapper is unknown
+        let mut mapper = test_mapper(VRC4Board::VRC4a_c, 0x2000);
+        mapper.write_prg(0xb004, 0x07);
+        mapper.write_prg(0xb040, 0x06);
+        assert_eq!(0x07, mapper.read_chr(0x0400));
+        assert_eq!(0x60, mapper.read_chr(0x0000));
+    }
+
+    #[test]
+    fn test_vrc2() {
+        let mut mapper = test_mapper(VRC4Board::VRC2a, 0);
+        mapper.write_prg(0xb000, 0x05);
+        assert_eq!(0x02, mapper.read_chr(0x0000));
+        mapper.write_prg(0x9000, 0x03);
+        assert_eq!(Mirroring::Horizontal, mapper.mirroring());
+
+        mapper.write_prg(0x6000, 0xff);
+        assert_eq!(0x61, mapper.read_prg(0x6000));
+        mapper.write_prg(0x6000, 0x00);
+        assert_eq!(0x60, mapper.read_prg(0x6000));
+
+        // Mapper 23 without a submapper could be a VRC2b
+        let mut mapper = test_mapper(VRC4Board::VRC4e_f, 0);
+        mapper.write_prg(0x6000, 0xff);
+        assert_eq!(0x61, mapper.read_prg(0x6000));
+        let mut mapper = test_mapper(VRC4Board::VRC4e_f, 0x2000);
+        mapper.write_prg(0x6000, 0xff);
+        assert_eq!(0xff, mapper.read_prg(0x6000));
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut mapper = test_mapper(VRC4Board::VRC4e, 0x2000);
+        mapper.write_prg(0xf000, 0x0e);
+        mapper.write_prg(0xf004, 0x0f);
+        mapper.write_prg(0xf008, 0x06);
+        mapper.tick();
+        assert!(!mapper.is_irq_asserted());
+        mapper.tick();
+        assert!(mapper.is_irq_asserted());
+        mapper.write_prg(0xf00c, 0);
+        assert!(!mapper.is_irq_asserted());
+    }
+}
@@ -0,0 +1,387 @@
+use crate::{
+    apu::{ExpansionAudio, ExpansionChip},
+    rom::nes::Mirroring,
+};
+
+use super::{vrc_irq::VrcIrq, Banks, ChrMemory, Mapper};
+
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    is_constant: bool,
+    is_enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Self {
+        Vrc6Pulse {
+            volume: 0,
+            duty: 0,
+            is_constant: false,
+            is_enabled: false,
+            period: 0,
+            timer: 0,
+            step: 0,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => {
+                self.is_constant = (value & 0x80) != 0;
+                self.duty = (value >> 4) & 0x07;
+                self.volume = value & 0x0f;
+            }
+            1 => self.period = (self.period & 0x0f00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00ff) | ((value as u16 & 0x0f) << 8);
+                self.is_enabled = (value & 0x80) != 0;
+                // Disabling the channel resets the duty cycle
+                if !self.is_enabled {
+                    self.step = 0;
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self, shift: u8) {
+        if !self.is_enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = (self.step + 1) & 0x0f;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.is_enabled && (self.is_constant || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+struct Vrc6Sawtooth {
+    rate: u8,
+    is_enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Sawtooth {
+    fn new() -> Self {
+        Vrc6Sawtooth {
+            rate: 0,
+            is_enabled: false,
+            period: 0,
+            timer: 0,
+            step: 0,
+            accumulator: 0,
+        }
+    }
+
+    fn write(&mut self, reg: u16, value: u8) {
+        match reg {
+            0 => self.rate = value & 0x3f,
+            1 => self.period = (self.period & 0x0f00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00ff) | ((value as u16 & 0x0f) << 8);
+                self.is_enabled = (value & 0x80) != 0;
+                if !self.is_enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self, shift: u8) {
+        if !self.is_enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        // The rate is added on every other step, the accumulator resets after 7 additions
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if (self.step & 0x01) == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+// iNES mappers 24 (VRC6a) and 26 (VRC6b)
+#[allow(clippy::upper_case_acronyms)]
+pub struct VRC6 {
+    prg_rom: Banks,
+    prg_ram: Vec<u8>,
+    chr: Banks,
+    // VRC6b has the A0 and A1 lines swapped
+    is_vrc6b: bool,
+
+    // Internal registers
+    reg_chr_banks: [u8; 8],
+    reg_ppu_control: u8,
+    reg_audio_control: u8,
+
+    irq: VrcIrq,
+
+    // Audio
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    sawtooth: Vrc6Sawtooth,
+}
+
+impl VRC6 {
+    pub fn new(prg_rom: Vec<u8>, chr: ChrMemory, is_vrc6b: bool) -> Self {
+        let mut prg_rom = Banks::new(prg_rom, 0x2000, 4);
+        prg_rom.set(3, prg_rom.bank_count() - 1);
+        let mut mapper = VRC6 {
+            prg_rom,
+            prg_ram: vec![0x00; 0x2000],
+            chr: Banks::new_chr(chr, 0x0400, 8),
+            is_vrc6b,
+            reg_chr_banks: [0; 8],
+            reg_ppu_control: 0,
+            reg_audio_control: 0,
+            irq: VrcIrq::new(),
+            pulse1: Vrc6Pulse::new(),
+            pulse2: Vrc6Pulse::new(),
+            sawtooth: Vrc6Sawtooth::new(),
+        };
+        mapper.update_chr_banks();
+        mapper
+    }
+
+    fn update_chr_banks(&mut self) {
+        let regs = self.reg_chr_banks;
+        // Without this bit the low bit of 2K banks comes from the PPU A10 line
+        let is_a10_from_reg = (self.reg_ppu_control & 0x20) != 0;
+        let bank_2k = |reg: u8, half: usize| {
+            if is_a10_from_reg {
+                reg as usize
+            } else {
+                (reg as usize & !0x01) | half
+            }
+        };
+        for window in 0..8 {
+            let bank = match (self.reg_ppu_control & 0x03, window) {
+                (0, _) => regs[window] as usize,
+                (1, _) => bank_2k(regs[window / 2], window & 0x01),
+                // Modes 2 and 3 mix 1K banks in the first half with 2K banks in the second
+                (_, 0..=3) => regs[window] as usize,
+                (_, _) => bank_2k(regs[4 + (window - 4) / 2], window & 0x01),
+            };
+            self.chr.set(window, bank);
+        }
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        (self.reg_ppu_control & 0x80) != 0
+    }
+
+    // Shift applied to the audio channel periods by the frequency scaling bits
+    fn audio_shift(&self) -> u8 {
+        if (self.reg_audio_control & 0x04) != 0 {
+            8
+        } else if (self.reg_audio_control & 0x02) != 0 {
+            4
+        } else {
+            0
+        }
+    }
+}
+
+impl Mapper for VRC6 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr >> 12 {
+            0x6 | 0x7 if self.is_prg_ram_enabled() => self.prg_ram[(addr & 0x1fff) as usize],
+            // Open bus
+            0x4..=0x7 => (addr >> 8) as u8,
+            0x8..=0xf => self.prg_rom.read((addr & 0x7fff) as usize),
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        let reg = if self.is_vrc6b {
+            ((addr & 0x01) << 1) | ((addr & 0x02) >> 1)
+        } else {
+            addr & 0x03
+        };
+        match (addr >> 12, reg) {
+            (0x6 | 0x7, _) if self.is_prg_ram_enabled() => {
+                self.prg_ram[(addr & 0x1fff) as usize] = value
+            }
+            (0x4..=0x7, _) => {}
+            (0x8, _) => {
+                let bank = (value & 0x0f) as usize * 2;
+                self.prg_rom.set(0, bank);
+                self.prg_rom.set(1, bank + 1);
+            }
+            (0x9, 3) => self.reg_audio_control = value,
+            (0x9, _) => self.pulse1.write(reg, value),
+            (0xa, 3) => {}
+            (0xa, _) => self.pulse2.write(reg, value),
+            (0xb, 3) => {
+                self.reg_ppu_control = value;
+                self.update_chr_banks();
+            }
+            (0xb, _) => self.sawtooth.write(reg, value),
+            (0xc, _) => self.prg_rom.set(2, (value & 0x1f) as usize),
+            (0xd, _) => {
+                self.reg_chr_banks[reg as usize] = value;
+                self.update_chr_banks();
+            }
+            (0xe, _) => {
+                self.reg_chr_banks[4 + reg as usize] = value;
+                self.update_chr_banks();
+            }
+            (0xf, 0) => self.irq.write_latch(value),
+            (0xf, 1) => self.irq.write_control(value),
+            (0xf, 2) => self.irq.acknowledge(),
+            (0xf, _) => {}
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    // Nametables from CHR ROM are not supported, no released game uses them
+    fn mirroring(&self) -> Mirroring {
+        match (self.reg_ppu_control >> 2) & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenA,
+            _ => Mirroring::SingleScreenB,
+        }
+    }
+
+    fn is_irq_asserted(&self) -> bool {
+        self.irq.is_pending()
+    }
+
+    fn tick(&mut self) {
+        self.irq.tick();
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(self)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+impl ExpansionAudio for VRC6 {
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::VRC6
+    }
+
+    fn tick_audio(&mut self) {
+        // The halt bit stops all channels
+        if (self.reg_audio_control & 0x01) == 0 {
+            let shift = self.audio_shift();
+            self.pulse1.tick(shift);
+            self.pulse2.tick(shift);
+            self.sawtooth.tick(shift);
+        }
+    }
+
+    fn audio_output(&self) -> i32 {
+        (self.pulse1.output() + self.pulse2.output() + self.sawtooth.output()) as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::mappers::numbered_banks;
+
+    fn test_mapper(is_vrc6b: bool) -> VRC6 {
+        let prg_rom = numbered_banks(0x40000, 0x2000);
+        let chr = numbered_banks(0x40000, 0x0400);
+        VRC6::new(prg_rom, ChrMemory::rom(chr), is_vrc6b)
+    }
+
+    #[test]
+    fn test_banking() {
+        for is_vrc6b in [false, true].iter().copied() {
+            let mut mapper = test_mapper(is_vrc6b);
+            mapper.write_prg(0x8000, 3);
+            mapper.write_prg(0xc000, 9);
+            assert_eq!(6, mapper.read_prg(0x8000));
+            assert_eq!(7, mapper.read_prg(0xa000));
+            assert_eq!(9, mapper.read_prg(0xc000));
+            assert_eq!(31, mapper.read_prg(0xe000));
+
+            // 0xd001 is register 2 on the VRC6b
+            mapper.write_prg(0xd001, 0x42);
+            let window = if is_vrc6b { 0x0800 } else { 0x0400 };
+            assert_eq!(0x42, mapper.read_chr(window));
+            mapper.write_prg(0xb003, 0x24);
+            assert_eq!(Mirroring::Horizontal, mapper.mirroring());
+        }
+    }
+
+    #[test]
+    fn test_audio() {
+        let max_output = |mapper: &mut VRC6| {
+            let mut max = 0;
+            for _ in 0..32 {
+                mapper.tick_audio();
+                max = max.max(mapper.audio_output());
+            }
+            max
+        };
+        let mut mapper = test_mapper(false);
+        mapper.write_prg(0x9000, 0x7f);
+        mapper.write_prg(0x9001, 0x00);
+        mapper.write_prg(0x9002, 0x80);
+        assert_eq!(15, max_output(&mut mapper));
+
+        // The sawtooth peaks after six additions of the rate
+        mapper.write_prg(0x9002, 0x00);
+        mapper.write_prg(0xb000, 42);
+        mapper.write_prg(0xb002, 0x80);
+        assert_eq!(31, max_output(&mut mapper));
+
+        // Halted channels keep their output
+        mapper.write_prg(0x9003, 0x01);
+        let output = mapper.audio_output();
+        assert_eq!(output, max_output(&mut mapper));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::{
+    apu::{ExpansionAudio, ExpansionChip},
+    rom::nes::Mirroring,
+};
+
+use super::{opll::Opll, vrc_irq::VrcIrq, Banks, ChrMemory, Mapper};
+
+// iNES mapper 85
+#[allow(clippy::upper_case_acronyms)]
+pub struct VRC7 {
+    prg_rom: Banks,
+    prg_ram: Vec<u8>,
+    chr: Banks,
+    // Address bits selecting the second register of each pair, A4 on the VRC7a and A3 on the VRC7b
+    register_lines: u16,
+
+    // Internal registers
+    reg_control: u8,
+
+    irq: VrcIrq,
+
+    // Audio, the FM core runs at its own rate and the last two samples are interpolated
+    opll: Opll,
+    sample_cycle: u32,
+    samples: [i32; 2],
+}
+
+impl VRC7 {
+    // CPU cycles per OPLL sample
+    const SAMPLE_CYCLES: u32 = 36;
+
+    pub fn new(prg_rom: Vec<u8>, chr: ChrMemory, register_lines: u16) -> Self {
+        let mut prg_rom = Banks::new(prg_rom, 0x2000, 4);
+        prg_rom.set(3, prg_rom.bank_count() - 1);
+        VRC7 {
+            prg_rom,
+            prg_ram: vec![0x00; 0x2000],
+            chr: Banks::new_chr(chr, 0x0400, 8),
+            register_lines,
+            reg_control: 0,
+            irq: VrcIrq::new(),
+            opll: Opll::new(),
+            sample_cycle: 0,
+            samples: [0; 2],
+        }
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        (self.reg_control & 0x80) != 0
+    }
+
+    // The OPLL is held in reset while this bit is set
+    fn is_audio_silenced(&self) -> bool {
+        (self.reg_control & 0x40) != 0
+    }
+}
+
+impl Mapper for VRC7 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        match addr >> 12 {
+            0x6 | 0x7 if self.is_prg_ram_enabled() => self.prg_ram[(addr & 0x1fff) as usize],
+            // Open bus
+            0x4..=0x7 => (addr >> 8) as u8,
+            0x8..=0xf => self.prg_rom.read((addr & 0x7fff) as usize),
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, value: u8) {
+        let reg = ((addr & self.register_lines) != 0) as usize;
+        match (addr >> 12, reg) {
+            (0x6 | 0x7, _) if self.is_prg_ram_enabled() => {
+                self.prg_ram[(addr & 0x1fff) as usize] = value
+            }
+            (0x4..=0x7, _) => {}
+            (0x8, _) => self.prg_rom.set(reg, (value & 0x3f) as usize),
+            // The audio ports decode A5 and A4 on both boards
+            (0x9, _) if (addr & 0x30) == 0x10 => self.opll.write_address(value),
+            (0x9, _) if (addr & 0x30) == 0x30 => self.opll.write_data(value),
+            (0x9, 0) => self.prg_rom.set(2, (value & 0x3f) as usize),
+            (0x9, _) => {}
+            (0xa..=0xd, _) => {
+                let window = ((addr >> 12) - 0xa) as usize * 2 + reg;
+                self.chr.set(window, value as usize);
+            }
+            (0xe, 0) => {
+                self.reg_control = value;
+                if self.is_audio_silenced() {
+                    self.opll.reset();
+                    self.samples = [0; 2];
+                }
+            }
+            (0xe, _) => self.irq.write_latch(value),
+            (0xf, 0) => self.irq.write_control(value),
+            (0xf, _) => self.irq.acknowledge(),
+            _ => panic!("Unmapped space access"),
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.chr.write(addr as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.reg_control & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenA,
+            _ => Mirroring::SingleScreenB,
+        }
+    }
+
+    fn is_irq_asserted(&self) -> bool {
+        self.irq.is_pending()
+    }
+
+    fn tick(&mut self) {
+        self.irq.tick();
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(self)
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+impl ExpansionAudio for VRC7 {
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::VRC7
+    }
+
+    fn tick_audio(&mut self) {
+        if self.is_audio_silenced() {
+            return;
+        }
+        self.sample_cycle += 1;
+        if self.sample_cycle == Self::SAMPLE_CYCLES {
+            self.sample_cycle = 0;
+            self.samples = [self.samples[1], self.opll.clock()];
+        }
+    }
+
+    // Linear interpolation from the FM rate to the CPU rate
+    fn audio_output(&self) -> i32 {
+        let [previous, next] = self.samples;
+        previous + (next - previous) * self.sample_cycle as i32 / Self::SAMPLE_CYCLES as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::mappers::numbered_banks;
+
+    fn test_mapper(register_lines: u16) -> VRC7 {
+        let prg_rom = numbered_banks(0x80000, 0x2000);
+        let chr = numbered_banks(0x40000, 0x0400);
+        VRC7::new(prg_rom, ChrMemory::rom(chr), register_lines)
+    }
+
+    #[test]
+    fn test_banking() {
+        for register_lines in [0x08, 0x10].iter().copied() {
+            let mut mapper = test_mapper(register_lines);
+            mapper.write_prg(0x8000, 3);
+            mapper.write_prg(0x8000 | register_lines, 4);
+            mapper.write_prg(0x9000, 5);
+            assert_eq!(3, mapper.read_prg(0x8000));
+            assert_eq!(4, mapper.read_prg(0xa000));
+            assert_eq!(5, mapper.read_prg(0xc000));
+            assert_eq!(63, mapper.read_prg(0xe000));
+
+            mapper.write_prg(0xd000 | register_lines, 0x42);
+            assert_eq!(0x42, mapper.read_chr(0x1c00));
+            mapper.write_prg(0xe000, 0x81);
+            assert_eq!(Mirroring::Horizontal, mapper.mirroring());
+            mapper.write_prg(0x6000, 0x55);
+            assert_eq!(0x55, mapper.read_prg(0x6000));
+        }
+    }
+
+    #[test]
+    fn test_audio() {
+        let mut mapper = test_mapper(0x10);
+        for (address, value) in [(0x10, 0x22), (0x30, 0x30), (0x20, 0x19)].iter().copied() {
+            mapper.write_prg(0x9010, address);
+            mapper.write_prg(0x9030, value);
+        }
+        let outputs: Vec<i32> = (0..10000)
+            .map(|_| {
+                mapper.tick_audio();
+                mapper.audio_output()
+            })
+            .collect();
+        assert!(outputs.iter().any(|&output| output > 1000));
+        assert!(outputs.iter().any(|&output| output < -1000));
+
+        // The reset bit silences the chip
+        mapper.write_prg(0xe000, 0x40);
+        mapper.tick_audio();
+        assert_eq!(0, mapper.audio_output());
+    }
+}
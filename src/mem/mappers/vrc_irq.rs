@@ -0,0 +1,120 @@
+// The IRQ counter shared by the Konami VRC4, VRC6 and VRC7
+
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    is_enabled: bool,
+    is_enabled_after_ack: bool,
+    is_cycle_mode: bool,
+    is_pending: bool,
+}
+
+impl VrcIrq {
+    // The prescaler divides the CPU clock by 113.667 to approximate scanlines
+    const PRESCALER_PERIOD: i16 = 341;
+    const PRESCALER_STEP: i16 = 3;
+
+    pub fn new() -> Self {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: Self::PRESCALER_PERIOD,
+            is_enabled: false,
+            is_enabled_after_ack: false,
+            is_cycle_mode: false,
+            is_pending: false,
+        }
+    }
+
+    pub fn write_latch(&mut self, value: u8) {
+        self.latch = value;
+    }
+
+    // VRC4 boards write the latch one nibble at a time
+    pub fn write_latch_lo(&mut self, value: u8) {
+        self.latch = (self.latch & 0xf0) | (value & 0x0f);
+    }
+
+    pub fn write_latch_hi(&mut self, value: u8) {
+        self.latch = (self.latch & 0x0f) | (value << 4);
+    }
+
+    pub fn write_control(&mut self, value: u8) {
+        self.is_enabled_after_ack = (value & 0x01) != 0;
+        self.is_enabled = (value & 0x02) != 0;
+        self.is_cycle_mode = (value & 0x04) != 0;
+        self.is_pending = false;
+        if self.is_enabled {
+            self.counter = self.latch;
+            self.prescaler = Self::PRESCALER_PERIOD;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.is_pending = false;
+        self.is_enabled = self.is_enabled_after_ack;
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.is_pending
+    }
+
+    // Called once per CPU cycle
+    pub fn tick(&mut self) {
+        if !self.is_enabled {
+            return;
+        }
+        if self.is_cycle_mode {
+            self.clock_counter();
+        } else {
+            self.prescaler -= Self::PRESCALER_STEP;
+            if self.prescaler <= 0 {
+                self.prescaler += Self::PRESCALER_PERIOD;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xff {
+            self.counter = self.latch;
+            self.is_pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_irq_modes() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xfe);
+        irq.write_control(0x07);
+        irq.tick();
+        assert!(!irq.is_pending());
+        irq.tick();
+        assert!(irq.is_pending());
+        irq.acknowledge();
+        assert!(!irq.is_pending());
+
+        // Two scanlines worth of CPU cycles
+        irq.write_control(0x02);
+        for _ in 0..227 {
+            irq.tick();
+        }
+        assert!(!irq.is_pending());
+        irq.tick();
+        assert!(irq.is_pending());
+        // Without the enable after acknowledgement bit the counter stops
+        irq.acknowledge();
+        for _ in 0..1000 {
+            irq.tick();
+        }
+        assert!(!irq.is_pending());
+    }
+}
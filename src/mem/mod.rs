@@ -1,3 +1,5 @@
+mod cartridge;
+pub use cartridge::*;
 pub mod mappers;
 #[allow(clippy::module_inception)]
 mod mem;
@@ -0,0 +1,113 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use super::NES;
+use crate::rom::patch;
+
+// Keeps the battery-backed cartridge memory in a .sav file next to the ROM
+pub struct BatterySave {
+    path: PathBuf,
+    last_saved: Option<Vec<u8>>,
+    // Disk images are saved as a diff against their original contents
+    original: Option<Vec<u8>>,
+}
+
+impl BatterySave {
+    pub fn new(rom_path: &Path) -> Self {
+        BatterySave {
+            path: rom_path.with_extension("sav"),
+            last_saved: None,
+            original: None,
+        }
+    }
+
+    // Saves the writes to a disk image as an IPS patch, the image itself is never modified
+    pub fn new_diff(rom_path: &Path, nes: &NES) -> Self {
+        BatterySave {
+            path: rom_path.with_extension("ips.sav"),
+            last_saved: None,
+            original: nes.battery_ram(),
+        }
+    }
+
+    pub fn load(&mut self, nes: &mut NES) -> io::Result<()> {
+        if nes.battery_ram().is_none() {
+            return Ok(());
+        }
+        match fs::read(&self.path) {
+            Ok(data) => {
+                let data = match &self.original {
+                    Some(original) => patch::apply_ips(original, &data)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+                    None => data,
+                };
+                nes.load_battery_ram(&data);
+                self.last_saved = nes.battery_ram();
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Only writes the file if the memory has changed since the last flush
+    pub fn flush(&mut self, nes: &NES) -> io::Result<()> {
+        let data = match nes.battery_ram() {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+        if self.last_saved.as_ref() == Some(&data) {
+            return Ok(());
+        }
+        // Write to a temporary file first so that a crash can't corrupt the save
+        let tmp_path = self.path.with_extension("sav.tmp");
+        match &self.original {
+            Some(original) => fs::write(&tmp_path, patch::encode_ips(original, &data))?,
+            None => fs::write(&tmp_path, &data)?,
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        self.last_saved = Some(data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mem::Memory, rom::nes::NESFile};
+
+    fn test_nes(has_battery: bool) -> NES {
+        let mut data = vec![0; 16 + 0x4000 + 0x2000];
+        data[0..4].copy_from_slice(b"NES\x1a");
+        data[4] = 0x01;
+        data[5] = 0x01;
+        data[6] = if has_battery { 0x02 } else { 0x00 };
+        NES::new_headless(NESFile::load(&data).expect("Failed to load the ROM"))
+            .expect("Failed to create the NES")
+    }
+
+    #[test]
+    fn test_save_round_trip() {
+        let rom_path = std::env::temp_dir().join(format!("nesness-{}.nes", std::process::id()));
+        let mut save = BatterySave::new(&rom_path);
+        let mut nes = test_nes(true);
+        nes.mmap.write_u8(0x6000, 0x42);
+        nes.mmap.write_u8(0x7fff, 0x24);
+        save.flush(&nes).expect("Failed to write the save");
+
+        let mut nes = test_nes(true);
+        let mut save = BatterySave::new(&rom_path);
+        save.load(&mut nes).expect("Failed to read the save");
+        fs::remove_file(rom_path.with_extension("sav")).unwrap();
+        assert_eq!(0x42, nes.mmap.read_u8(0x6000));
+        assert_eq!(0x24, nes.mmap.read_u8(0x7fff));
+
+        // Boards without a battery never touch the file system
+        let nes = test_nes(false);
+        assert_eq!(None, nes.battery_ram());
+        save.flush(&nes).expect("Failed to flush");
+        assert!(!rom_path.with_extension("sav").exists());
+    }
+}
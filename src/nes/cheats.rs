@@ -0,0 +1,351 @@
+// Game Genie and raw memory cheats, kept in an FCEUX compatible .cht file next to the ROM
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use super::NES;
+
+const GAME_GENIE_LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheatError {
+    InvalidCode(String),
+    InvalidLine(usize),
+}
+
+impl std::fmt::Display for CheatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheatError::InvalidCode(code) => write!(f, "invalid cheat code {:?}", code),
+            CheatError::InvalidLine(line) => write!(f, "invalid cheat on line {}", line),
+        }
+    }
+}
+
+impl std::error::Error for CheatError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    pub name: String,
+    pub addr: u16,
+    pub value: u8,
+    // The cheat only applies while the memory holds this value
+    pub compare: Option<u8>,
+    pub is_enabled: bool,
+}
+
+impl Cheat {
+    // Game Genie codes, ADDR:VAL and ADDR?CMP:VAL
+    pub fn parse(code: &str) -> Result<Self, CheatError> {
+        let code = code.trim();
+        let invalid = || CheatError::InvalidCode(code.to_string());
+        let (addr, value, compare) = match code.split_once(':') {
+            Some((addr, value)) => {
+                let (addr, compare) = match addr.split_once('?') {
+                    Some((addr, compare)) => (addr, Some(compare)),
+                    None => (addr, None),
+                };
+                let compare = match compare {
+                    Some(compare) => Some(u8::from_str_radix(compare, 16).map_err(|_| invalid())?),
+                    None => None,
+                };
+                (
+                    u16::from_str_radix(addr, 16).map_err(|_| invalid())?,
+                    u8::from_str_radix(value, 16).map_err(|_| invalid())?,
+                    compare,
+                )
+            }
+            None => decode_game_genie(code).ok_or_else(invalid)?,
+        };
+        if !is_cheatable(addr) {
+            return Err(invalid());
+        }
+        Ok(Cheat {
+            name: String::new(),
+            addr,
+            value,
+            compare,
+            is_enabled: true,
+        })
+    }
+
+    // Cheats on the program ROM replace what the CPU reads, the rest freeze memory every frame
+    pub fn is_substitution(&self) -> bool {
+        self.addr >= 0x8000
+    }
+
+    pub fn matches(&self, value: u8) -> bool {
+        !matches!(self.compare, Some(compare) if compare != value)
+    }
+}
+
+// Freezing only makes sense on RAM, writing to registers every frame would have side effects
+fn is_cheatable(addr: u16) -> bool {
+    matches!(addr, 0x0000..=0x1fff | 0x6000..=0xffff)
+}
+
+fn decode_game_genie(code: &str) -> Option<(u16, u8, Option<u8>)> {
+    let n = code
+        .bytes()
+        .map(|b| {
+            GAME_GENIE_LETTERS
+                .iter()
+                .position(|&letter| letter == b.to_ascii_uppercase())
+                .map(|n| n as u16)
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if !matches!(n.len(), 6 | 8) {
+        return None;
+    }
+    let addr = 0x8000
+        | ((n[3] & 7) << 12)
+        | ((n[5] & 7) << 8)
+        | ((n[4] & 8) << 8)
+        | ((n[2] & 7) << 4)
+        | ((n[1] & 8) << 4)
+        | (n[4] & 7)
+        | (n[3] & 8);
+    // The last letter holds the high bit of the value, or of the compare value for 8 letters
+    let last = if n.len() == 8 { n[7] } else { n[5] };
+    let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (last & 8);
+    let compare = if n.len() == 8 {
+        Some(((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8))
+    } else {
+        None
+    };
+    Some((addr, value as u8, compare.map(|compare| compare as u8)))
+}
+
+// FCEUX lines look like [S][C][:]ADDR:VAL[:CMP]:NAME, with S for substitutions,
+// C when there's a compare value and a colon in front for disabled cheats
+pub fn parse_cht(text: &str) -> Result<Vec<Cheat>, CheatError> {
+    let mut cheats = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || CheatError::InvalidLine(i + 1);
+        let line = line.strip_prefix('S').unwrap_or(line);
+        let (line, has_compare) = match line.strip_prefix('C') {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        let (line, is_enabled) = match line.strip_prefix(':') {
+            Some(line) => (line, false),
+            None => (line, true),
+        };
+        let fields = if has_compare { 4 } else { 3 };
+        let parts: Vec<&str> = line.splitn(fields, ':').collect();
+        if parts.len() != fields {
+            return Err(invalid());
+        }
+        let compare = if has_compare {
+            Some(u8::from_str_radix(parts[2], 16).map_err(|_| invalid())?)
+        } else {
+            None
+        };
+        let addr = u16::from_str_radix(parts[0], 16).map_err(|_| invalid())?;
+        if !is_cheatable(addr) {
+            return Err(invalid());
+        }
+        cheats.push(Cheat {
+            name: parts[fields - 1].to_string(),
+            addr,
+            value: u8::from_str_radix(parts[1], 16).map_err(|_| invalid())?,
+            compare,
+            is_enabled,
+        });
+    }
+    Ok(cheats)
+}
+
+pub fn encode_cht(cheats: &[Cheat]) -> String {
+    let mut text = String::new();
+    for cheat in cheats {
+        if cheat.is_substitution() {
+            text.push('S');
+        }
+        if cheat.compare.is_some() {
+            text.push('C');
+        }
+        if !cheat.is_enabled {
+            text.push(':');
+        }
+        text.push_str(&format!("{:04x}:{:02x}:", cheat.addr, cheat.value));
+        if let Some(compare) = cheat.compare {
+            text.push_str(&format!("{:02x}:", compare));
+        }
+        text.push_str(&cheat.name);
+        text.push('\n');
+    }
+    text
+}
+
+pub struct CheatManager {
+    path: PathBuf,
+    cheats: Vec<Cheat>,
+}
+
+impl CheatManager {
+    pub fn new(rom_path: &Path) -> Self {
+        CheatManager {
+            path: rom_path.with_extension("cht"),
+            cheats: vec![],
+        }
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn load(&mut self) -> io::Result<()> {
+        match fs::read_to_string(&self.path) {
+            Ok(text) => {
+                self.cheats = parse_cht(&text)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    // The file is removed once there are no cheats left
+    pub fn save(&self) -> io::Result<()> {
+        if self.cheats.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            };
+        }
+        fs::write(&self.path, encode_cht(&self.cheats))
+    }
+
+    // Adds the cheats of another FCEUX .cht file, returns how many were added
+    pub fn import(&mut self, path: &Path) -> io::Result<usize> {
+        let text = fs::read_to_string(path)?;
+        let cheats =
+            parse_cht(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(cheats
+            .into_iter()
+            .filter(|cheat| self.push(cheat.clone()))
+            .count())
+    }
+
+    // Returns false if the same cheat was already there
+    pub fn add(&mut self, code: &str, name: &str) -> Result<bool, CheatError> {
+        let mut cheat = Cheat::parse(code)?;
+        cheat.name = name.to_string();
+        Ok(self.push(cheat))
+    }
+
+    // Cheats are the same when they patch the same way, whatever their names
+    fn push(&mut self, cheat: Cheat) -> bool {
+        let is_duplicate = self.cheats.iter().any(|other| {
+            (other.addr, other.value, other.compare) == (cheat.addr, cheat.value, cheat.compare)
+        });
+        if !is_duplicate {
+            self.cheats.push(cheat);
+        }
+        !is_duplicate
+    }
+
+    pub fn set_enabled(&mut self, idx: usize, is_enabled: bool) {
+        self.cheats[idx].is_enabled = is_enabled;
+    }
+
+    // Needs to be called again after the cheats change
+    pub fn apply(&self, nes: &mut NES) {
+        nes.mmap.set_cheats(&self.cheats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mem::Memory, rom::nes::NESFile};
+
+    #[test]
+    fn test_parse() {
+        let cheat = |code| {
+            let cheat = Cheat::parse(code).expect("Failed to parse the code");
+            (cheat.addr, cheat.value, cheat.compare)
+        };
+        assert_eq!((0xd1dd, 0x14, None), cheat("GOSSIP"));
+        assert_eq!((0x94a7, 0x02, Some(0x03)), cheat("zexpygla"));
+        assert_eq!((0x0075, 0x09, None), cheat("0075:09"));
+        assert_eq!((0xc000, 0x60, Some(0xa9)), cheat("C000?A9:60"));
+        assert!(Cheat::parse("GOSSI").is_err());
+        assert!(Cheat::parse("GOSSIB").is_err());
+        assert!(Cheat::parse("10000:00").is_err());
+        assert_eq!((0x6000, 0x01, None), cheat("6000:01"));
+        assert!(Cheat::parse("2002:00").is_err());
+        assert!(Cheat::parse("4100?00:01").is_err());
+        assert!(Cheat::parse("5000:00").is_err());
+    }
+
+    #[test]
+    fn test_cht_round_trip() {
+        let text = "SC:c000:60:a9:Skip: the intro\n0075:09:Lives\n";
+        let cheats = parse_cht(text).expect("Failed to parse the cheats");
+        assert_eq!(2, cheats.len());
+        assert_eq!("Skip: the intro", cheats[0].name);
+        assert!(!cheats[0].is_enabled);
+        assert_eq!(Some(0xa9), cheats[0].compare);
+        assert!(cheats[1].is_enabled);
+        assert_eq!(text, encode_cht(&cheats));
+        assert_eq!(
+            Err(CheatError::InvalidLine(2)),
+            parse_cht("0075:09:Lives\nS8000:zz:Bad\n")
+        );
+        assert_eq!(
+            Err(CheatError::InvalidLine(1)),
+            parse_cht("4015:00:Silence\n")
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut data = vec![0; 16 + 0x4000 + 0x2000];
+        data[0..4].copy_from_slice(b"NES\x1a");
+        data[4] = 0x01;
+        data[5] = 0x01;
+        data[16] = 0xa9;
+        let mut nes = NES::new_headless(NESFile::load(&data).expect("Failed to load the ROM"))
+            .expect("Failed to create the NES");
+        let mut cheats = CheatManager::new(Path::new("game.nes"));
+        cheats.add("8000?A9:EA", "Compare").unwrap();
+        cheats.add("8001?FF:EA", "Mismatch").unwrap();
+        cheats.add("0010:42", "Freeze").unwrap();
+        cheats.apply(&mut nes);
+        assert_eq!(0xea, nes.mmap.read_u8(0x8000));
+        // Mirrors of the patched byte are left alone
+        assert_eq!(0xa9, nes.mmap.read_u8(0xc000));
+        assert_eq!(0x00, nes.mmap.read_u8(0x8001));
+        nes.mmap.freeze_cheats();
+        assert_eq!(0x42, nes.mmap.read_u8(0x0010));
+
+        cheats.set_enabled(0, false);
+        cheats.apply(&mut nes);
+        assert_eq!(0xa9, nes.mmap.read_u8(0x8000));
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let dir = std::env::temp_dir().join(format!("nesness_cheats_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut cheats = CheatManager::new(&dir.join("game.nes"));
+        assert_eq!(Ok(true), cheats.add("0075:09", "Lives"));
+        assert_eq!(Ok(false), cheats.add("0075:09", "More lives"));
+        assert_eq!(Ok(true), cheats.add("0075?01:09", "Lives once"));
+
+        let path = dir.join("import.cht");
+        fs::write(&path, "0075:09:Lives\n0076:01:Level\n0076:01:Level again\n").unwrap();
+        assert_eq!(1, cheats.import(&path).unwrap());
+        assert_eq!(3, cheats.cheats().len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,12 +1,12 @@
 use crate::{
     apu::APU,
-    mem::{
-        mappers::{self, Mapper},
-        Memory,
-    },
+    mem::{Cartridge, Memory},
     ppu::PPU,
+    rom::nes::Mirroring,
 };
 
+use super::cheats::Cheat;
+
 pub struct CpuMemoryMap {
     // Main RAM - 0x0000..0x1fff
     pub ram: [u8; 0x0800],
@@ -23,38 +23,139 @@ pub struct CpuMemoryMap {
     pub apu: APU,
 
     // Cartridge space - 0x4020..0xffff
-    mapper: Box<dyn Mapper>,
-    prg_rom: Vec<Vec<u8>>,
+    cartridge: Cartridge,
+
+    // Enabled cheats only
+    cheats: Vec<Cheat>,
 }
 
 pub struct PpuMemoryMap {
-    // PPU RAM (VRAM) - 0x2000..0x3fff
-    // TODO: mirror and split the attribute memory out
-    vram: [u8; 0x2000],
+    // Nametables - 0x2000..0x3eff
+    // The console only has 2K of VRAM (CIRAM), the cartridge decides
+    // how the four nametables are mapped onto it
+    ciram: [u8; 0x0800],
+    // Four-screen boards add 2K of their own VRAM for the other two nametables
+    four_screen_vram: [u8; 0x0800],
 
-    mapper: Box<dyn Mapper>,
-    chr_rom: Vec<Vec<u8>>,
+    // Palette RAM - 0x3f00..0x3fff
+    palette: [u8; 0x20],
+
+    // Pattern tables - 0x0000..0x1fff
+    cartridge: Cartridge,
 }
 
 impl CpuMemoryMap {
-    pub fn new(mapper: u8, prg_rom: Vec<Vec<u8>>, chr_rom: Vec<Vec<u8>>) -> Self {
+    pub fn new(cartridge: Cartridge, apu: APU) -> Self {
         CpuMemoryMap {
             ram: [0x00; 0x0800],
             ppu: PPU::new(),
-            ppu_mmap: PpuMemoryMap::new(mapper, chr_rom),
-            apu: APU::new(),
-            mapper: mappers::get_mapper(mapper),
-            prg_rom,
+            ppu_mmap: PpuMemoryMap::new(cartridge.clone()),
+            apu,
+            cartridge,
+            cheats: vec![],
+        }
+    }
+
+    pub fn is_irq_asserted(&self) -> bool {
+        self.cartridge.is_irq_asserted()
+    }
+
+    pub fn tick_cartridge(&mut self) {
+        self.cartridge.tick();
+    }
+
+    pub fn tick_apu(&mut self) {
+        let apu = &mut self.apu;
+        self.cartridge.with_expansion_audio(|audio| apu.tick(audio));
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    pub fn set_cheats(&mut self, cheats: &[Cheat]) {
+        self.cheats = cheats
+            .iter()
+            .filter(|cheat| cheat.is_enabled)
+            .cloned()
+            .collect();
+    }
+
+    // Writes the values of the cheats that aren't substitutions, once per frame
+    pub fn freeze_cheats(&mut self) {
+        let cheats = std::mem::take(&mut self.cheats);
+        for cheat in cheats.iter().filter(|cheat| !cheat.is_substitution()) {
+            if cheat.compare.is_none() || cheat.matches(self.read_u8(cheat.addr)) {
+                self.write_u8(cheat.addr, cheat.value);
+            }
+        }
+        self.cheats = cheats;
+    }
+
+    fn read_prg(&self, addr: u16) -> u8 {
+        let value = self.cartridge.read_prg(addr);
+        match self
+            .cheats
+            .iter()
+            .find(|cheat| cheat.addr == addr && cheat.is_substitution() && cheat.matches(value))
+        {
+            Some(cheat) => cheat.value,
+            None => value,
         }
     }
 }
 
 impl PpuMemoryMap {
-    pub fn new(mapper: u8, chr_rom: Vec<Vec<u8>>) -> Self {
+    pub fn new(cartridge: Cartridge) -> Self {
         PpuMemoryMap {
-            vram: [0x00; 0x2000],
-            mapper: mappers::get_mapper(mapper),
-            chr_rom,
+            ciram: [0x00; 0x0800],
+            four_screen_vram: [0x00; 0x0800],
+            palette: [0x00; 0x20],
+            cartridge,
+        }
+    }
+
+    // Returns the 1K page (0..3) and the offset within it
+    fn map_nametable(&self, addr: u16) -> (usize, usize) {
+        let table = ((addr >> 10) & 0x03) as usize;
+        let page = match self.cartridge.mirroring() {
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical => table & 0x01,
+            Mirroring::SingleScreenA => 0,
+            Mirroring::SingleScreenB => 1,
+            Mirroring::FourScreen => table,
+            Mirroring::Custom(pages) => pages[table] as usize,
+        };
+        (page, (addr & 0x03ff) as usize)
+    }
+
+    fn read_nametable(&self, addr: u16) -> u8 {
+        if let Some(value) = self.cartridge.read_nametable(addr) {
+            return value;
+        }
+        match self.map_nametable(addr) {
+            (page @ (0 | 1), offset) => self.ciram[(page << 10) | offset],
+            (page, offset) => self.four_screen_vram[((page & 0x01) << 10) | offset],
+        }
+    }
+
+    fn write_nametable(&mut self, addr: u16, value: u8) {
+        if self.cartridge.write_nametable(addr, value) {
+            return;
+        }
+        match self.map_nametable(addr) {
+            (page @ (0 | 1), offset) => self.ciram[(page << 10) | offset] = value,
+            (page, offset) => self.four_screen_vram[((page & 0x01) << 10) | offset] = value,
+        }
+    }
+
+    fn palette_index(addr: u16) -> usize {
+        let idx = (addr & 0x1f) as usize;
+        // Backdrop entries of the sprite palettes mirror the background ones
+        if (idx & 0x13) == 0x10 {
+            idx & 0x0f
+        } else {
+            idx
         }
     }
 }
@@ -75,7 +176,8 @@ impl Memory for CpuMemoryMap {
                 _ => unreachable!(),
             },
             4 if (addr & 0xfff == 0x14) => self.ppu.read_oamdma(),
-            4 if (addr as u8) < 0x20 => match addr & 0x1f {
+            // Only 0x4000..0x401f belongs to the APU, the rest of the page is cartridge space
+            4 if addr < 0x4020 => match addr & 0x1f {
                 0x00 => self.apu.read_pulse1_0(),
                 0x01 => self.apu.read_pulse1_1(),
                 0x02 => self.apu.read_pulse1_2(),
@@ -110,26 +212,30 @@ impl Memory for CpuMemoryMap {
                 0x1f => self.apu.read_dummy_x1f(),
                 _ => unreachable!(),
             },
-            _ => *self.mapper.prg_map(addr, &self.prg_rom),
+            _ => self.read_prg(addr),
         }
     }
 
     fn write_u8(&mut self, addr: u16, value: u8) {
         match addr >> 12 {
             0 | 1 => self.ram[(addr & 0x7ff) as usize] = value,
-            2 | 3 => match addr & 0x7 {
-                0 => self.ppu.write_ppuctrl(value),
-                1 => self.ppu.write_ppumask(value),
-                2 => self.ppu.write_ppustatus(value),
-                3 => self.ppu.write_oamaddr(value),
-                4 => self.ppu.write_oamdata(value),
-                5 => self.ppu.write_ppuscroll(value),
-                6 => self.ppu.write_ppuaddr(value),
-                7 => self.ppu.write_ppudata(&mut self.ppu_mmap, value),
-                _ => unreachable!(),
-            },
+            2 | 3 => {
+                // Some mappers watch the PPU configuration
+                self.cartridge.observe_cpu_write(addr, value);
+                match addr & 0x7 {
+                    0 => self.ppu.write_ppuctrl(value),
+                    1 => self.ppu.write_ppumask(value),
+                    2 => self.ppu.write_ppustatus(value),
+                    3 => self.ppu.write_oamaddr(value),
+                    4 => self.ppu.write_oamdata(value),
+                    5 => self.ppu.write_ppuscroll(value),
+                    6 => self.ppu.write_ppuaddr(value),
+                    7 => self.ppu.write_ppudata(&mut self.ppu_mmap, value),
+                    _ => unreachable!(),
+                }
+            }
             4 if (addr & 0xfff == 0x14) => self.ppu.write_oamdma(value),
-            4 if (addr as u8) < 0x20 => match addr & 0x1f {
+            4 if addr < 0x4020 => match addr & 0x1f {
                 0x00 => self.apu.write_pulse1_0(value),
                 0x01 => self.apu.write_pulse1_1(value),
                 0x02 => self.apu.write_pulse1_2(value),
@@ -164,7 +270,7 @@ impl Memory for CpuMemoryMap {
                 0x1f => self.apu.write_dummy_x1f(value),
                 _ => unreachable!(),
             },
-            _ => *self.mapper.prg_map_mut(addr, &mut self.prg_rom) = value,
+            _ => self.cartridge.write_prg(addr, value),
         }
     }
 }
@@ -172,37 +278,40 @@ impl Memory for CpuMemoryMap {
 impl Memory for PpuMemoryMap {
     fn read_u8(&self, mut addr: u16) -> u8 {
         addr &= 0x3fff;
-        match addr {
-            0x0000..=0x1fff => *self.mapper.chr_map(addr, &self.chr_rom),
-            0x2000..=0x2fff => self.vram[(addr - 0x2000) as usize],
-            0x3000..=0x3eff => self.vram[(addr - 0x3000) as usize],
-            0x3f00..=0x3fff => self.vram[0x1f00 | (addr as usize & 0x1f)],
+        let value = match addr {
+            0x0000..=0x1fff => self.cartridge.read_chr(addr),
+            0x2000..=0x3eff => self.read_nametable(addr),
+            0x3f00..=0x3fff => return self.palette[Self::palette_index(addr)],
             _ => unreachable!(),
-        }
+        };
+        self.cartridge.observe_ppu_addr(addr);
+        value
     }
 
     fn write_u8(&mut self, mut addr: u16, value: u8) {
         addr &= 0x3fff;
         match addr {
-            0x0000..=0x1fff => *self.mapper.chr_map_mut(addr, &mut self.chr_rom) = value,
-            0x2000..=0x2fff => self.vram[(addr - 0x2000) as usize] = value,
-            0x3000..=0x3eff => self.vram[(addr - 0x3000) as usize] = value,
-            0x3f00..=0x3fff => self.vram[0x1f00 | (addr as usize & 0x1f)] = value,
+            0x0000..=0x1fff => self.cartridge.write_chr(addr, value),
+            0x2000..=0x3eff => self.write_nametable(addr, value),
+            0x3f00..=0x3fff => return self.palette[Self::palette_index(addr)] = value,
             _ => unreachable!(),
         }
+        self.cartridge.observe_ppu_addr(addr);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mem::mappers::{ChrMemory, Namco163, NROM};
     use std::ops::Range;
 
     #[test]
     fn test_ram_mirroring() {
         let test = |range: Range<u16>| {
             let prg_rom = vec![];
-            let mut mmap = CpuMemoryMap::new(0, prg_rom, vec![]);
+            let mapper = NROM::new(prg_rom, ChrMemory::ram(0x2000), Mirroring::Horizontal);
+            let mut mmap = CpuMemoryMap::new(Cartridge::new(Box::new(mapper)), APU::new_headless());
             for i in range {
                 mmap.write_u8(i, i as u8);
             }
@@ -215,4 +324,36 @@ mod tests {
         test(0x1000..0x1800);
         test(0x1800..0x2000);
     }
+
+    #[test]
+    fn test_cartridge_registers_in_apu_page() {
+        let mapper = Namco163::new(vec![0; 0x8000], 0x2000, ChrMemory::ram(0x2000));
+        let mut mmap = CpuMemoryMap::new(Cartridge::new(Box::new(mapper)), APU::new_headless());
+        // Sound RAM through the data port at 0x4800, with auto-increment
+        mmap.write_u8(0xf800, 0x80);
+        mmap.write_u8(0x4800, 0x12);
+        mmap.write_u8(0x4800, 0x34);
+        mmap.write_u8(0xf800, 0x80);
+        assert_eq!(0x12, mmap.read_u8(0x4800));
+        assert_eq!(0x34, mmap.read_u8(0x4800));
+    }
+
+    #[test]
+    fn test_nametable_mirroring() {
+        let test = |mirroring: Mirroring, pages: [u8; 4]| {
+            let mapper = NROM::new(vec![], ChrMemory::ram(0x2000), mirroring);
+            let mut mmap = PpuMemoryMap::new(Cartridge::new(Box::new(mapper)));
+            for (i, page) in pages.iter().enumerate() {
+                mmap.write_u8(0x2000 + i as u16 * 0x400, *page);
+            }
+            for (i, page) in pages.iter().enumerate() {
+                assert_eq!(*page, mmap.read_u8(0x2000 + i as u16 * 0x400));
+                assert_eq!(*page, mmap.read_u8(0x3000 + i as u16 * 0x400));
+            }
+        };
+        test(Mirroring::Horizontal, [1, 1, 3, 3]);
+        test(Mirroring::Vertical, [2, 3, 2, 3]);
+        test(Mirroring::SingleScreenA, [3, 3, 3, 3]);
+        test(Mirroring::FourScreen, [0, 1, 2, 3]);
+    }
 }
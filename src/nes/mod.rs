@@ -1,3 +1,5 @@
+pub mod battery;
+pub mod cheats;
 pub mod mmap;
 #[allow(clippy::module_inception)]
 mod nes;
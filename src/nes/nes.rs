@@ -1,4 +1,17 @@
-use crate::{cpu::CPU, mem::Memory, rom::nes::NESFile, util::ClockDivider};
+use crate::{
+    apu::APU,
+    cpu::CPU,
+    mem::{
+        mappers::{self, Mapper, FDS, NSF},
+        Cartridge, Memory,
+    },
+    rom::{
+        fds::FDSFile,
+        nes::{LoadError, NESFile},
+        nsf::NSFFile,
+    },
+    util::ClockDivider,
+};
 
 use super::{mmap::CpuMemoryMap, trace::ExecutionTrace};
 
@@ -10,16 +23,57 @@ pub struct NES {
     total_ticks: u64, // Enough for ~25k years
     cpu_clock_divider: ClockDivider<12>,
     ppu_clock_divider: ClockDivider<4>,
+    cartridge_clock_divider: ClockDivider<12>,
+
+    has_battery: bool,
 }
 
 impl NES {
-    pub fn new(rom: NESFile) -> Self {
+    pub fn new(rom: NESFile) -> Result<Self, LoadError> {
+        let has_battery = rom.header.has_persistent_memory;
+        let mapper = mappers::get_mapper(rom)?;
+        Ok(Self::with_mapper(mapper, has_battery, APU::new()))
+    }
+
+    // Doesn't need an audio device, for tests
+    #[cfg(test)]
+    pub fn new_headless(rom: NESFile) -> Result<Self, LoadError> {
+        let has_battery = rom.header.has_persistent_memory;
+        let mapper = mappers::get_mapper(rom)?;
+        Ok(Self::with_mapper(mapper, has_battery, APU::new_headless()))
+    }
+
+    // Famicom Disk System running the BIOS ROM, with the first disk side inserted.
+    // Disk writes are reported as battery RAM.
+    pub fn new_disk_system(disk: FDSFile, bios: Vec<u8>) -> Self {
+        Self::with_mapper(Box::new(FDS::new(bios, disk.sides)), true, APU::new())
+    }
+
+    // NSF player, starting with the default track of the file
+    pub fn new_nsf(nsf: &NSFFile) -> Self {
+        Self::with_nsf(nsf, APU::new())
+    }
+
+    // NSF player that doesn't need an audio device, for rendering the tracks to files
+    pub fn new_nsf_headless(nsf: &NSFFile) -> Self {
+        Self::with_nsf(nsf, APU::new_headless())
+    }
+
+    fn with_nsf(nsf: &NSFFile, apu: APU) -> Self {
+        let mut nes = Self::with_mapper(Box::new(NSF::new(nsf)), false, apu);
+        nes.select_track(nsf.starting_track);
+        nes
+    }
+
+    fn with_mapper(mapper: Box<dyn Mapper>, has_battery: bool, apu: APU) -> Self {
         let mut nes = NES {
             cpu: CPU::new(),
-            mmap: CpuMemoryMap::new(rom.header.mapper, rom.prg_rom, rom.chr_rom),
+            mmap: CpuMemoryMap::new(Cartridge::new(mapper), apu),
             total_ticks: 0,
             cpu_clock_divider: ClockDivider::new(),
             ppu_clock_divider: ClockDivider::new(),
+            cartridge_clock_divider: ClockDivider::new(),
+            has_battery,
         };
         nes.reset();
         nes.mmap.apu.play(); // Should probably be done elsewhere?
@@ -30,6 +84,43 @@ impl NES {
         self.total_ticks / 12
     }
 
+    // Contents of the battery-backed cartridge memory, None if there's no battery
+    pub fn battery_ram(&self) -> Option<Vec<u8>> {
+        if !self.has_battery {
+            return None;
+        }
+        self.mmap.cartridge().battery_ram()
+    }
+
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        self.mmap.cartridge().load_battery_ram(data);
+    }
+
+    // Swipes a card through the barcode reader of the cartridge, if it has one
+    pub fn scan_barcode(&mut self, barcode: &str) -> bool {
+        self.mmap.cartridge().scan_barcode(barcode)
+    }
+
+    pub fn disk_side_count(&self) -> usize {
+        self.mmap.cartridge().disk_side_count()
+    }
+
+    pub fn insert_disk(&mut self, side: Option<usize>) {
+        self.mmap.cartridge().insert_disk(side);
+    }
+
+    // Restarts the NSF player with another track, the way the NSF spec initializes the console
+    pub fn select_track(&mut self, track: usize) {
+        self.mmap.cartridge().select_track(track);
+        self.mmap.ram = [0x00; 0x0800];
+        for addr in 0x4000..=0x4013 {
+            self.mmap.write_u8(addr, 0x00);
+        }
+        self.mmap.write_u8(0x4015, 0x0f);
+        self.mmap.write_u8(0x4017, 0x40);
+        self.reset();
+    }
+
     pub fn reset(&mut self) {
         self.cpu.reset(&self.mmap);
         self.total_ticks = 7 * 12; // CPU reset takes 7 cycles
@@ -39,6 +130,7 @@ impl NES {
         }
         self.cpu_clock_divider.reset();
         self.ppu_clock_divider.reset();
+        self.cartridge_clock_divider.reset();
     }
 
     #[inline]
@@ -48,6 +140,8 @@ impl NES {
             let cycles = if self.mmap.ppu.is_cpu_interrupt_requested {
                 self.mmap.ppu.is_cpu_interrupt_requested = false;
                 self.cpu.execute_interrupt(&mut self.mmap)
+            } else if self.mmap.is_irq_asserted() && !self.cpu.flag_interrupt_disable {
+                self.cpu.execute_irq(&mut self.mmap)
             } else {
                 self.cpu.run_one(&mut self.mmap)
             };
@@ -65,11 +159,19 @@ impl NES {
         }
         if self.ppu_clock_divider.is_triggered() {
             self.mmap.ppu.run_one(&self.mmap.ppu_mmap);
+            // Frozen values are written back at the start of vblank
+            if self.mmap.ppu.current_scanline == 241 && self.mmap.ppu.current_cycle == 2 {
+                self.mmap.freeze_cheats();
+            }
+        }
+        if self.cartridge_clock_divider.is_triggered() {
+            self.mmap.tick_cartridge();
         }
         self.total_ticks += 1;
         self.cpu_clock_divider.tick();
         self.ppu_clock_divider.tick();
-        self.mmap.apu.tick();
+        self.cartridge_clock_divider.tick();
+        self.mmap.tick_apu();
     }
 
     pub fn run_with_trace<T: ExecutionTrace>(&mut self, mut trace: T) {
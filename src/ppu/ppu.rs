@@ -146,7 +146,6 @@ impl PPU {
             // Data fetches and address increments
             if self.current_scanline < 240 || self.current_scanline == 261 {
                 // Tile fetch
-                // NOTE: unused nametable fetches are not implemented
                 let c = self.current_cycle;
                 if (1..=256).contains(&c) || (321..=336).contains(&c) {
                     match c % 8 {
@@ -205,6 +204,9 @@ impl PPU {
                         }
                         _ => unreachable!(),
                     }
+                } else if c == 337 || c == 339 {
+                    // Unused nametable fetches, MMC5 uses them to detect scanlines
+                    mem.read_u8(0x2000 | (self.reg_v.get() & 0x0fff));
                 }
 
                 // Coordinate increment
@@ -264,10 +266,11 @@ impl PPU {
                     if self.current_scanline != 261 {
                         // Not cycle accurate
                         let mut idx_free = 0;
+                        let height = self.sprite_height();
                         for i in 0..64 {
                             let y = self.oam_data[i * 4];
                             self.oam_evaluated[idx_free].y = y;
-                            if (y..y.wrapping_add(8)).contains(&next_y) {
+                            if (y..y.wrapping_add(height)).contains(&next_y) {
                                 self.oam_evaluated[idx_free].tile_index = self.oam_data[i * 4 + 1];
                                 self.oam_evaluated[idx_free].attributes = self.oam_data[i * 4 + 2];
                                 self.oam_evaluated[idx_free].x = self.oam_data[i * 4 + 3];
@@ -284,15 +287,21 @@ impl PPU {
                 } else if c == 257 {
                     // Not cycle accurate
                     for i in 0..self.oam_evaluated.len() {
-                        if !self.oam_evaluated[i].is_valid {
-                            break;
+                        // Empty slots still fetch tile 0xff, mappers watching
+                        // the pattern table addresses depend on these fetches
+                        let sprite = self.oam_evaluated[i];
+                        let row = if sprite.is_valid {
+                            next_y.wrapping_sub(sprite.y)
+                        } else {
+                            0
+                        };
+                        let addr = self.sprite_pattern_addr(sprite.tile_index, row);
+                        let lo = mem.read_u8(addr);
+                        let hi = mem.read_u8(addr + 8);
+                        if sprite.is_valid {
+                            self.oam_evaluated[i].tile_lo.load(lo);
+                            self.oam_evaluated[i].tile_hi.load(hi);
                         }
-                        let idx = self.oam_evaluated[i].tile_index;
-                        let addr = self.sprite_pattern_table_addr
-                            + idx as u16 * 8
-                            + (next_y - self.oam_evaluated[i].y) as u16;
-                        self.oam_evaluated[i].tile_lo.load(mem.read_u8(addr));
-                        self.oam_evaluated[i].tile_hi.load(mem.read_u8(addr + 8));
                     }
                 }
             }
@@ -408,6 +417,27 @@ impl PPU {
         self.is_odd_frame = !self.is_odd_frame;
     }
 
+    fn sprite_height(&self) -> u8 {
+        match self.sprite_size {
+            SpriteSize::_8x8 => 8,
+            SpriteSize::_8x16 => 16,
+        }
+    }
+
+    fn sprite_pattern_addr(&self, tile_index: u8, row: u8) -> u16 {
+        match self.sprite_size {
+            SpriteSize::_8x8 => {
+                self.sprite_pattern_table_addr + tile_index as u16 * 16 + row as u16
+            }
+            SpriteSize::_8x16 => {
+                // The pattern table is selected by bit 0 of the tile index
+                let table_addr = (tile_index as u16 & 0x01) << 12;
+                let tile = (tile_index & 0xfe) as u16 + (row as u16 >> 3);
+                table_addr + tile * 16 + (row as u16 & 0x07)
+            }
+        }
+    }
+
     pub fn read_ppuctrl(&self) -> u8 {
         // PPUCTRL is write-only
         self.latch
@@ -609,12 +639,28 @@ impl PPU {
 
 #[cfg(test)]
 mod tests {
-    use crate::{cpu::rp2a03::opcodes::*, cpu::CPU, nes::mmap::CpuMemoryMap};
+    use crate::{
+        apu::APU,
+        cpu::rp2a03::opcodes::*,
+        cpu::CPU,
+        mem::{
+            mappers::{ChrMemory, NROM},
+            Cartridge,
+        },
+        nes::mmap::CpuMemoryMap,
+        rom::nes::Mirroring,
+    };
 
     #[test]
     fn test_ppu_vram_access() {
         let mut cpu = CPU::new();
-        let mut mmap = CpuMemoryMap::new(0, vec![vec![0; 0x10000]], vec![vec![0; 0x2000]]);
+        let mapper = NROM::new(
+            vec![0; 0x10000],
+            ChrMemory::ram(0x2000),
+            Mirroring::Horizontal,
+        );
+        let cartridge = Cartridge::new(Box::new(mapper));
+        let mut mmap = CpuMemoryMap::new(cartridge, APU::new_headless());
         cpu.reset(&mut mmap);
 
         for addr in 0..0x4000u16 {
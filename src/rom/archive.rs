@@ -0,0 +1,104 @@
+// Zip and gzip compressed ROMs, unpacked before they reach the loaders
+
+use std::io::{self, Cursor, Read};
+
+const ROM_EXTENSIONS: [&str; 4] = [".nes", ".fds", ".nsf", ".unf"];
+
+// Larger than any cartridge, bigger files are most likely decompression bombs
+const MAX_ROM_SIZE: u64 = 0x100_0000;
+
+fn is_rom_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    ROM_EXTENSIONS
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+// Returns the data as is if it's not compressed
+pub fn extract(data: Vec<u8>, entry_name: Option<&str>) -> io::Result<Vec<u8>> {
+    if data.starts_with(b"PK\x03\x04") {
+        extract_zip(data, entry_name)
+    } else if data.starts_with(b"\x1f\x8b") {
+        read_limited(flate2::read::GzDecoder::new(data.as_slice()))
+    } else {
+        Ok(data)
+    }
+}
+
+// Picks the entry by name, or the first one that looks like a ROM
+fn extract_zip(data: Vec<u8>, entry_name: Option<&str>) -> io::Result<Vec<u8>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+    let name = match entry_name {
+        Some(name) => name.to_string(),
+        None => (0..archive.len())
+            .filter_map(|i| archive.name_for_index(i))
+            .find(|name| is_rom_name(name))
+            .map(|name| name.to_string())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no ROM found in the zip archive")
+            })?,
+    };
+    let file = archive.by_name(&name)?;
+    // The size in the header can't be trusted for allocating
+    read_limited(file)
+}
+
+fn read_limited(reader: impl Read) -> io::Result<Vec<u8>> {
+    let mut extracted = vec![];
+    reader.take(MAX_ROM_SIZE + 1).read_to_end(&mut extracted)?;
+    if extracted.len() as u64 > MAX_ROM_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the compressed file is too large",
+        ));
+    }
+    Ok(extracted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_zip() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(vec![]));
+        let options = zip::write::SimpleFileOptions::default();
+        writer.start_file("readme.txt", options).unwrap();
+        writer.write_all(b"Not a ROM").unwrap();
+        writer.start_file("Game (U).NES", options).unwrap();
+        writer.write_all(b"NES\x1a").unwrap();
+        writer.start_file("Game (E).nes", options).unwrap();
+        writer.write_all(b"NES\x1b").unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        assert_eq!(b"NES\x1a".to_vec(), extract(data.clone(), None).unwrap());
+        assert_eq!(
+            b"NES\x1b".to_vec(),
+            extract(data.clone(), Some("Game (E).nes")).unwrap()
+        );
+        assert!(extract(data, Some("Game (J).nes")).is_err());
+    }
+
+    #[test]
+    fn test_gzip() {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(b"NES\x1a").unwrap();
+        let data = encoder.finish().unwrap();
+        assert_eq!(b"NES\x1a".to_vec(), extract(data, None).unwrap());
+        assert_eq!(
+            b"NES\x1a".to_vec(),
+            extract(b"NES\x1a".to_vec(), None).unwrap()
+        );
+
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder
+            .write_all(&vec![0; MAX_ROM_SIZE as usize + 1])
+            .unwrap();
+        let data = encoder.finish().unwrap();
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            extract(data, None).unwrap_err().kind()
+        );
+    }
+}
@@ -0,0 +1,73 @@
+// Famicom Disk System images, with or without the fwNES header
+
+use super::nes::LoadError;
+
+// Block data of a single disk side, without the gaps and CRCs
+pub const DISK_SIDE_SIZE: usize = 65500;
+
+pub struct FDSFile {
+    pub sides: Vec<Vec<u8>>,
+}
+
+impl FDSFile {
+    pub fn is_disk_image(data: &[u8]) -> bool {
+        data.starts_with(b"FDS\x1a") || Self::is_disk_side(data)
+    }
+
+    // Every side starts with the disk info block
+    fn is_disk_side(data: &[u8]) -> bool {
+        data.starts_with(b"\x01*NINTENDO-HVC*")
+    }
+
+    pub fn load(data: &[u8]) -> Result<Self, LoadError> {
+        let (data, side_count) = if data.starts_with(b"FDS\x1a") {
+            let side_count = *data.get(4).ok_or(LoadError::Truncated)? as usize;
+            (data.get(16..).ok_or(LoadError::Truncated)?, side_count)
+        } else {
+            (data, data.len() / DISK_SIDE_SIZE)
+        };
+        if !Self::is_disk_side(data) {
+            return Err(LoadError::BadMagic);
+        }
+        if side_count == 0 || data.len() < side_count * DISK_SIDE_SIZE {
+            return Err(LoadError::Truncated);
+        }
+        let sides = data
+            .chunks_exact(DISK_SIDE_SIZE)
+            .take(side_count)
+            .map(|side| side.to_vec())
+            .collect();
+        Ok(FDSFile { sides })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let mut data = vec![0; DISK_SIDE_SIZE * 2];
+        data[..15].copy_from_slice(b"\x01*NINTENDO-HVC*");
+        data[DISK_SIDE_SIZE] = 0x01;
+        let disk = FDSFile::load(&data).expect("Failed to load a headerless image");
+        assert_eq!(2, disk.sides.len());
+        assert_eq!(0x01, disk.sides[1][0]);
+
+        let mut headered = b"FDS\x1a\x01".to_vec();
+        headered.resize(16, 0);
+        headered.extend_from_slice(&data);
+        assert!(FDSFile::is_disk_image(&headered));
+        let disk = FDSFile::load(&headered).expect("Failed to load an fwNES image");
+        assert_eq!(1, disk.sides.len());
+
+        assert!(matches!(
+            FDSFile::load(&headered[..0x100]),
+            Err(LoadError::Truncated)
+        ));
+        assert!(matches!(
+            FDSFile::load(&data[1..]),
+            Err(LoadError::BadMagic)
+        ));
+    }
+}
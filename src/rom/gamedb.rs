@@ -0,0 +1,185 @@
+// Database of known cartridges, overrides the headers of bad iNES dumps
+
+use super::{
+    crc32::crc32,
+    nes::{Mirroring, NESHeader, TVSystem},
+    sha1::sha1,
+};
+
+const GAME_DATABASE: &str = include_str!("gamedb.txt");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameEntry {
+    pub title: String,
+    crc32: u32,
+    sha1: [u8; 20],
+    mapper: u16,
+    submapper: u8,
+    mirroring: Mirroring,
+    is_four_screen: bool,
+    prg_ram_size: usize,
+    prg_nvram_size: usize,
+    chr_ram_size: usize,
+    chr_nvram_size: usize,
+    tv_system: TVSystem,
+    default_expansion_device: u8,
+}
+
+impl GameEntry {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let mut next = || fields.next();
+        let crc32 = u32::from_str_radix(next()?, 16).ok()?;
+        let sha1_hex = next()?;
+        if sha1_hex.len() != 40 {
+            return None;
+        }
+        let mut sha1 = [0; 20];
+        for (i, byte) in sha1.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(sha1_hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+        }
+        let mapper = next()?.parse().ok()?;
+        let submapper = next()?.parse().ok()?;
+        let (mirroring, is_four_screen) = match next()? {
+            "H" => (Mirroring::Horizontal, false),
+            "V" => (Mirroring::Vertical, false),
+            "4" => (Mirroring::Horizontal, true),
+            _ => return None,
+        };
+        let mut size = || usize::from_str_radix(next()?, 16).ok();
+        let prg_ram_size = size()?;
+        let prg_nvram_size = size()?;
+        let chr_ram_size = size()?;
+        let chr_nvram_size = size()?;
+        let tv_system = match next()? {
+            "NTSC" => TVSystem::NTSC,
+            "PAL" => TVSystem::PAL,
+            "Dual" => TVSystem::DualCompatible,
+            "Dendy" => TVSystem::Dendy,
+            _ => return None,
+        };
+        let default_expansion_device = u8::from_str_radix(next()?, 16).ok()?;
+        let title = fields.collect::<Vec<_>>().join(" ");
+        Some(GameEntry {
+            title,
+            crc32,
+            sha1,
+            mapper,
+            submapper,
+            mirroring,
+            is_four_screen,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size,
+            chr_nvram_size,
+            tv_system,
+            default_expansion_device,
+        })
+    }
+
+    // Turns the header into the NES 2.0 equivalent of the entry, returns false if nothing changed
+    pub fn apply(&self, header: &mut NESHeader) -> bool {
+        let has_persistent_memory = self.prg_nvram_size + self.chr_nvram_size > 0;
+        let is_changed = header.mapper != self.mapper
+            || header.submapper != self.submapper
+            || header.mirroring != self.mirroring
+            || header.ignore_mirroring != self.is_four_screen
+            || header.total_prg_ram_size() != self.prg_ram_size + self.prg_nvram_size
+            || header.total_chr_ram_size() != self.chr_ram_size + self.chr_nvram_size
+            || header.has_persistent_memory != has_persistent_memory
+            || header.tv_system != self.tv_system
+            || header.default_expansion_device != self.default_expansion_device;
+        header.is_nes2_format = true;
+        header.mapper = self.mapper;
+        header.submapper = self.submapper;
+        header.mirroring = self.mirroring;
+        header.ignore_mirroring = self.is_four_screen;
+        header.prg_ram_size = self.prg_ram_size;
+        header.prg_nvram_size = self.prg_nvram_size;
+        header.chr_ram_size = self.chr_ram_size;
+        header.chr_nvram_size = self.chr_nvram_size;
+        header.has_persistent_memory = has_persistent_memory;
+        header.has_prg_ram = self.prg_ram_size + self.prg_nvram_size > 0;
+        header.tv_system = self.tv_system;
+        header.default_expansion_device = self.default_expansion_device;
+        is_changed
+    }
+}
+
+fn find(database: &str, prg_rom: &[u8], chr_rom: &[u8]) -> Option<GameEntry> {
+    let data = [prg_rom, chr_rom].concat();
+    let crc = crc32(&data);
+    // The CRC32 narrows it down, the SHA-1 confirms the match
+    let mut digest = None;
+    database
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(GameEntry::parse)
+        .filter(|entry| entry.crc32 == crc)
+        .find(|entry| *digest.get_or_insert_with(|| sha1(&data)) == entry.sha1)
+}
+
+pub fn lookup(prg_rom: &[u8], chr_rom: &[u8]) -> Option<GameEntry> {
+    find(GAME_DATABASE, prg_rom, chr_rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let prg_rom = vec![0x01; 0x8000];
+        let chr_rom = vec![0x02; 0x2000];
+        let data = [prg_rom.as_slice(), chr_rom.as_slice()].concat();
+        let sha1_hex: String = sha1(&data).iter().map(|b| format!("{:02x}", b)).collect();
+        let database = format!(
+            "# Comment\n\
+             {:08x} {} 1 0 H 0 2000 0 0 PAL 1 Some Game (E)\n",
+            crc32(&data),
+            sha1_hex
+        );
+        let entry = find(&database, &prg_rom, &chr_rom).expect("Failed to find the entry");
+        assert_eq!("Some Game (E)", entry.title);
+        assert_eq!(None, find(&database, &prg_rom, &prg_rom));
+
+        let mut header = crate::rom::nes::NESFile::load(
+            &[b"NES\x1a\x02\x01\x01\x00".as_ref(), &[0; 8], &data].concat(),
+        )
+        .expect("Failed to load the ROM")
+        .header;
+        assert!(entry.apply(&mut header));
+        assert_eq!(1, header.mapper);
+        assert_eq!(Mirroring::Horizontal, header.mirroring);
+        assert_eq!(0x2000, header.prg_nvram_size);
+        assert!(header.has_persistent_memory);
+        assert_eq!(TVSystem::PAL, header.tv_system);
+        assert!(!entry.apply(&mut header));
+    }
+
+    #[test]
+    fn test_override() {
+        let mut data = include_bytes!("../cpu/tests/rom/nestest/nestest.nes").to_vec();
+        // A CNROM header with vertical mirroring
+        data[6] = 0x31;
+        let database =
+            "158b0388 4131307f0f69f2a5c54b7d438328c5b2a5ed0820 0 0 H 0 0 0 0 NTSC 0 nestest\n";
+        let mut rom = crate::rom::nes::NESFile::load(&data).expect("Failed to load the ROM");
+        assert_eq!(3, rom.header.mapper);
+        let entry = find(database, &rom.prg_rom, &rom.chr_rom).expect("Failed to find nestest");
+        assert!(entry.apply(&mut rom.header));
+        assert_eq!(0, rom.header.mapper);
+        assert_eq!(Mirroring::Horizontal, rom.header.mirroring);
+        assert_eq!(TVSystem::NTSC, rom.header.tv_system);
+    }
+
+    #[test]
+    fn test_database() {
+        // Every line of the embedded database must parse
+        for line in GAME_DATABASE.lines() {
+            if !line.starts_with('#') && !line.trim().is_empty() {
+                assert!(GameEntry::parse(line).is_some(), "Bad entry: {}", line);
+            }
+        }
+    }
+}
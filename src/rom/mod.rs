@@ -1 +1,9 @@
+pub mod archive;
+pub mod crc32;
+pub mod fds;
+pub mod gamedb;
 pub mod nes;
+pub mod nsf;
+pub mod patch;
+pub mod sha1;
+pub mod unif;
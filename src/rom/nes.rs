@@ -1,17 +1,105 @@
 // iNES/NES 2.0 format ROM
 
-#[derive(Debug)]
+use super::gamedb;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mirroring {
     Horizontal,
     Vertical,
+    SingleScreenA,
+    SingleScreenB,
+    FourScreen,
+    // CIRAM page for each of the four nametables, set up by the mapper
+    Custom([u8; 4]),
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TVSystem {
     NTSC,
     PAL,
     DualCompatible,
+    Dendy,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleType {
+    NES,
+    VsSystem {
+        ppu: VsPpuType,
+        hardware: VsHardwareType,
+    },
+    PlayChoice10,
+    // NES 2.0 extended console type, stored in the byte 13
+    Extended(u8),
+}
+
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VsPpuType {
+    RP2C03B,
+    RP2C03G,
+    RP2C04_0001,
+    RP2C04_0002,
+    RP2C04_0003,
+    RP2C04_0004,
+    RC2C03B,
+    RC2C03C,
+    RC2C05_01,
+    RC2C05_02,
+    RC2C05_03,
+    RC2C05_04,
+    RC2C05_05,
+    Unknown(u8),
+}
+
+impl VsPpuType {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0x0 => VsPpuType::RP2C03B,
+            0x1 => VsPpuType::RP2C03G,
+            0x2 => VsPpuType::RP2C04_0001,
+            0x3 => VsPpuType::RP2C04_0002,
+            0x4 => VsPpuType::RP2C04_0003,
+            0x5 => VsPpuType::RP2C04_0004,
+            0x6 => VsPpuType::RC2C03B,
+            0x7 => VsPpuType::RC2C03C,
+            0x8 => VsPpuType::RC2C05_01,
+            0x9 => VsPpuType::RC2C05_02,
+            0xa => VsPpuType::RC2C05_03,
+            0xb => VsPpuType::RC2C05_04,
+            0xc => VsPpuType::RC2C05_05,
+            _ => VsPpuType::Unknown(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VsHardwareType {
+    Unisystem,
+    UnisystemRbiBaseball,
+    UnisystemTkoBoxing,
+    UnisystemSuperXevious,
+    UnisystemIceClimberJapan,
+    DualSystem,
+    DualSystemRaidOnBungelingBay,
+    Unknown(u8),
+}
+
+impl VsHardwareType {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0x0 => VsHardwareType::Unisystem,
+            0x1 => VsHardwareType::UnisystemRbiBaseball,
+            0x2 => VsHardwareType::UnisystemTkoBoxing,
+            0x3 => VsHardwareType::UnisystemSuperXevious,
+            0x4 => VsHardwareType::UnisystemIceClimberJapan,
+            0x5 => VsHardwareType::DualSystem,
+            0x6 => VsHardwareType::DualSystemRaidOnBungelingBay,
+            _ => VsHardwareType::Unknown(value),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -19,80 +107,235 @@ pub enum TVSystem {
 pub struct NESHeader {
     pub is_nes2_format: bool,
     pub tv_system: TVSystem,
-    pub prg_rom_banks: u8,
-    pub chr_rom_banks: u8,
-    pub prg_ram_banks: u8,
-    pub is_vs_unisystem: bool,
-    pub is_playchoice_10: bool,
-    pub mapper: u8,
+    pub console_type: ConsoleType,
+    // All sizes are in bytes
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub mapper: u16,
+    pub submapper: u8,
     pub mirroring: Mirroring,
     pub ignore_mirroring: bool,
     pub has_persistent_memory: bool,
     pub has_trainer: bool,
     pub has_prg_ram: bool,
     pub has_bus_conflicts: bool,
+    pub misc_rom_count: u8,
+    // Index in the NES 2.0 expansion device list, 0 when unspecified
+    pub default_expansion_device: u8,
+}
+
+impl NESHeader {
+    pub fn nametable_mirroring(&self) -> Mirroring {
+        // Four-screen boards provide their own VRAM for the other two nametables
+        if self.ignore_mirroring {
+            Mirroring::FourScreen
+        } else {
+            self.mirroring
+        }
+    }
+
+    pub fn total_prg_ram_size(&self) -> usize {
+        self.prg_ram_size + self.prg_nvram_size
+    }
+
+    pub fn total_chr_ram_size(&self) -> usize {
+        self.chr_ram_size + self.chr_nvram_size
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    BadMagic,
+    Truncated,
+    // The sizes in the header add up to more than can be addressed
+    RomTooLarge,
+    MissingPrgRom,
+    UnsupportedMapper(u16),
+    // UNIF file without a MAPR chunk
+    MissingBoard,
+    // UNIF board name without a matching mapper
+    UnsupportedBoard(String),
+    // NSFe chunk that has to be understood to play the file
+    UnsupportedChunk(String),
+    SizeMismatch { expected: usize, actual: usize },
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::BadMagic => write!(f, "unknown file format"),
+            LoadError::Truncated => write!(f, "the file is truncated"),
+            LoadError::RomTooLarge => write!(f, "the ROM sizes in the header are too large"),
+            LoadError::MissingPrgRom => write!(f, "the file has no PRG ROM"),
+            LoadError::UnsupportedMapper(mapper) => write!(f, "mapper {} is not supported", mapper),
+            LoadError::MissingBoard => write!(f, "the file doesn't name its board"),
+            LoadError::UnsupportedBoard(board) => write!(f, "board {} is not supported", board),
+            LoadError::UnsupportedChunk(id) => write!(f, "chunk {} is not supported", id),
+            LoadError::SizeMismatch { expected, actual } => write!(
+                f,
+                "expected {} bytes of ROM data, found {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadWarning {
+    // Bytes 12..15 of an iNES header are not zeroes, usually a ripper signature
+    DirtyHeader,
+    TrailingData(usize),
+    MissingChrData(usize),
+    // The header was replaced with the game database entry of the given title
+    HeaderOverride(String),
+}
+
+impl std::fmt::Display for LoadWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadWarning::DirtyHeader => {
+                write!(f, "garbage in the header, ignoring the upper mapper bits")
+            }
+            LoadWarning::TrailingData(size) => {
+                write!(f, "ignoring {} bytes of trailing data", size)
+            }
+            LoadWarning::MissingChrData(size) => {
+                write!(
+                    f,
+                    "{} bytes of CHR ROM are missing, padding with zeroes",
+                    size
+                )
+            }
+            LoadWarning::HeaderOverride(title) => {
+                write!(
+                    f,
+                    "fixed the header from the game database entry for {}",
+                    title
+                )
+            }
+        }
+    }
 }
 
 pub struct NESFile {
     pub header: NESHeader,
-    pub prg_rom: Vec<Vec<u8>>,
-    pub chr_rom: Vec<Vec<u8>>,
+    // Loaded into 0x7000..0x71ff
+    pub trainer: Option<Vec<u8>>,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    #[allow(dead_code)]
+    pub misc_rom: Vec<u8>,
+    pub warnings: Vec<LoadWarning>,
 }
 
 impl NESFile {
-    pub fn load(data: &[u8]) -> Option<Self> {
-        let header = Self::load_header(data)?;
-        if header.mapper != 0 {
-            panic!("Mapper {} is not implemented", header.mapper);
+    pub fn load(data: &[u8]) -> Result<Self, LoadError> {
+        let mut warnings = vec![];
+        let mut header = Self::load_header(data, &mut warnings)?;
+        if header.prg_rom_size == 0 {
+            return Err(LoadError::MissingPrgRom);
         }
         let mut idx = 16;
-        if header.has_trainer {
-            todo!()
-        }
-        const PRG_ROM_BANK_SIZE: usize = 0x4000;
-        const CHR_ROM_BANK_SIZE: usize = 0x2000;
-        if data.len()
-            != idx
-                + header.prg_rom_banks as usize * PRG_ROM_BANK_SIZE
-                + header.chr_rom_banks as usize * CHR_ROM_BANK_SIZE
-        {
-            return None;
-        }
-        let mut prg_rom = vec![];
-        for _ in 0..header.prg_rom_banks {
-            prg_rom.push(data[idx..idx + PRG_ROM_BANK_SIZE].to_vec());
-            idx += PRG_ROM_BANK_SIZE;
-        }
-        let mut chr_rom = vec![];
-        for _ in 0..header.chr_rom_banks {
-            chr_rom.push(data[idx..idx + CHR_ROM_BANK_SIZE].to_vec());
-            idx += CHR_ROM_BANK_SIZE;
-        }
-        Some(NESFile {
+        let trainer = if header.has_trainer {
+            let trainer = data.get(idx..idx + 0x200).ok_or(LoadError::Truncated)?;
+            idx += 0x200;
+            Some(trainer.to_vec())
+        } else {
+            None
+        };
+        // NES 2.0 sizes can be large enough to overflow
+        let rom_size = header
+            .prg_rom_size
+            .checked_add(header.chr_rom_size)
+            .ok_or(LoadError::RomTooLarge)?;
+        let actual_size = data.len() - idx;
+        if actual_size < rom_size {
+            // Some dumps are missing the last CHR bank, other emulators run them anyway
+            let missing = rom_size - actual_size;
+            if missing > 0x2000 || missing > header.chr_rom_size {
+                return Err(LoadError::SizeMismatch {
+                    expected: rom_size,
+                    actual: actual_size,
+                });
+            }
+            warnings.push(LoadWarning::MissingChrData(missing));
+        } else if actual_size > rom_size && header.misc_rom_count == 0 {
+            // Miscellaneous ROMs take up the rest of the file, otherwise it's garbage
+            warnings.push(LoadWarning::TrailingData(actual_size - rom_size));
+        }
+        let prg_end = idx
+            .checked_add(header.prg_rom_size)
+            .ok_or(LoadError::RomTooLarge)?;
+        let prg_rom = data.get(idx..prg_end).ok_or(LoadError::Truncated)?.to_vec();
+        idx = prg_end;
+        let chr_end = idx
+            .checked_add(header.chr_rom_size)
+            .ok_or(LoadError::RomTooLarge)?
+            .min(data.len());
+        let mut chr_rom = data[idx..chr_end].to_vec();
+        chr_rom.resize(header.chr_rom_size, 0x00);
+        idx = chr_end;
+        let misc_rom = if header.misc_rom_count > 0 {
+            data[idx..].to_vec()
+        } else {
+            vec![]
+        };
+        // Known dumps take their header from the database, the mapper could be fixed too
+        if let Some(entry) = gamedb::lookup(&prg_rom, &chr_rom) {
+            if entry.apply(&mut header) {
+                warnings.push(LoadWarning::HeaderOverride(entry.title));
+            }
+        }
+        Ok(NESFile {
             header,
+            trainer,
             prg_rom,
             chr_rom,
+            misc_rom,
+            warnings,
         })
     }
 
-    fn load_header(data: &[u8]) -> Option<NESHeader> {
-        if data.len() < 16 {
-            return None;
+    fn load_header(data: &[u8], warnings: &mut Vec<LoadWarning>) -> Result<NESHeader, LoadError> {
+        if data.len() < 4 || &data[0..4] != b"NES\x1a" {
+            return Err(LoadError::BadMagic);
         }
-        if &data[0..4] != b"NES\x1a" {
-            return None;
+        if data.len() < 16 {
+            return Err(LoadError::Truncated);
         }
-        let mut mapper = data[6] >> 4;
-        let is_nes2_format = (data[7] & 0xc0) == 0x80;
-        if is_nes2_format {
-            unimplemented!()
+        let is_nes2_format = (data[7] & 0x0c) == 0x08;
+        let mut mapper = ((data[7] & 0xf0) | (data[6] >> 4)) as u16;
+        let mirroring = if (data[6] & 0x01) != 0 {
+            Mirroring::Vertical
         } else {
-            // Ensure bytes 12..15 are zeroes
-            if &data[12..=15] != b"\x00\x00\x00\x00" {
-                return None;
-            }
+            Mirroring::Horizontal
+        };
+        let ignore_mirroring = (data[6] & 0x08) != 0;
+        let has_persistent_memory = (data[6] & 0x02) != 0;
+        let has_trainer = (data[6] & 0x04) != 0;
+        if is_nes2_format {
+            return Self::load_nes2_header(
+                data,
+                mapper,
+                mirroring,
+                ignore_mirroring,
+                has_persistent_memory,
+                has_trainer,
+            );
+        }
+
+        // Bytes 12..15 should be zeroes, otherwise byte 7 can't be trusted either
+        let is_dirty = &data[12..=15] != b"\x00\x00\x00\x00";
+        if is_dirty {
+            warnings.push(LoadWarning::DirtyHeader);
+            mapper &= 0x0f;
         }
-        mapper |= data[7] & 0xf0;
         let mut prg_ram_size_in_units = data[8];
         if prg_ram_size_in_units == 0 {
             prg_ram_size_in_units = 1;
@@ -109,25 +352,230 @@ impl NESFile {
             2 => tv_system = TVSystem::PAL,
             _ => unreachable!(),
         }
-        Some(NESHeader {
+        let console_type = if is_dirty {
+            ConsoleType::NES
+        } else if (data[7] & 0x01) != 0 {
+            // iNES doesn't specify the exact hardware
+            ConsoleType::VsSystem {
+                ppu: VsPpuType::RP2C03B,
+                hardware: VsHardwareType::Unisystem,
+            }
+        } else if (data[7] & 0x02) != 0 {
+            ConsoleType::PlayChoice10
+        } else {
+            ConsoleType::NES
+        };
+        Ok(NESHeader {
             is_nes2_format,
             tv_system,
-            prg_rom_banks: data[4],
-            chr_rom_banks: data[5],
-            prg_ram_banks: prg_ram_size_in_units,
-            is_vs_unisystem: (data[7] & 0x01) != 0,
-            is_playchoice_10: (data[7] & 0x02) != 0,
+            console_type,
+            prg_rom_size: data[4] as usize * 0x4000,
+            chr_rom_size: data[5] as usize * 0x2000,
+            prg_ram_size: prg_ram_size_in_units as usize * 0x2000,
+            prg_nvram_size: 0,
+            // iNES boards without CHR ROM always have 8K of CHR RAM
+            chr_ram_size: if data[5] == 0 { 0x2000 } else { 0 },
+            chr_nvram_size: 0,
             mapper,
-            mirroring: if (data[6] & 0x01) != 0 {
-                Mirroring::Vertical
-            } else {
-                Mirroring::Horizontal
-            },
-            ignore_mirroring: (data[6] & 0x08) != 0,
-            has_persistent_memory: (data[6] & 0x02) != 0,
-            has_trainer: (data[6] & 0x04) != 0,
+            submapper: 0,
+            mirroring,
+            ignore_mirroring,
+            has_persistent_memory,
+            has_trainer,
             has_prg_ram: (data[10] & 0x10) == 0,
             has_bus_conflicts: (data[10] & 0x20) != 0,
+            misc_rom_count: 0,
+            default_expansion_device: 0,
+        })
+    }
+
+    fn load_nes2_header(
+        data: &[u8],
+        mapper: u16,
+        mirroring: Mirroring,
+        ignore_mirroring: bool,
+        has_persistent_memory: bool,
+        has_trainer: bool,
+    ) -> Result<NESHeader, LoadError> {
+        let console_type = match data[7] & 0x03 {
+            0 => ConsoleType::NES,
+            1 => ConsoleType::VsSystem {
+                ppu: VsPpuType::from_u8(data[13] & 0x0f),
+                hardware: VsHardwareType::from_u8(data[13] >> 4),
+            },
+            2 => ConsoleType::PlayChoice10,
+            3 => ConsoleType::Extended(data[13] & 0x0f),
+            _ => unreachable!(),
+        };
+        let tv_system = match data[12] & 0x03 {
+            0 => TVSystem::NTSC,
+            1 => TVSystem::PAL,
+            2 => TVSystem::DualCompatible,
+            3 => TVSystem::Dendy,
+            _ => unreachable!(),
+        };
+        let prg_ram_size = Self::nes2_ram_size(data[10] & 0x0f);
+        let prg_nvram_size = Self::nes2_ram_size(data[10] >> 4);
+        Ok(NESHeader {
+            is_nes2_format: true,
+            tv_system,
+            console_type,
+            prg_rom_size: Self::nes2_rom_size(data[4], data[9] & 0x0f, 0x4000)?,
+            chr_rom_size: Self::nes2_rom_size(data[5], data[9] >> 4, 0x2000)?,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size: Self::nes2_ram_size(data[11] & 0x0f),
+            chr_nvram_size: Self::nes2_ram_size(data[11] >> 4),
+            mapper: mapper | ((data[8] as u16 & 0x0f) << 8),
+            submapper: data[8] >> 4,
+            mirroring,
+            ignore_mirroring,
+            has_persistent_memory,
+            has_trainer,
+            has_prg_ram: prg_ram_size + prg_nvram_size > 0,
+            // Bus conflicts are specified through submappers instead
+            has_bus_conflicts: false,
+            misc_rom_count: data[14] & 0x03,
+            default_expansion_device: data[15] & 0x3f,
         })
     }
+
+    // Sizes that don't fit in memory can't be in the file either
+    fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> Result<usize, LoadError> {
+        if msb == 0x0f {
+            // Exponent-multiplier notation: 2^E * (M * 2 + 1)
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = (lsb & 0x03) as usize * 2 + 1;
+            1usize
+                .checked_shl(exponent)
+                .and_then(|size| size.checked_mul(multiplier))
+                .ok_or(LoadError::RomTooLarge)
+        } else {
+            Ok((((msb as usize) << 8) | lsb as usize) * unit)
+        }
+    }
+
+    fn nes2_ram_size(shift: u8) -> usize {
+        if shift == 0 {
+            0
+        } else {
+            64 << shift
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::mappers;
+
+    #[test]
+    fn test_nes2_header() {
+        let mut data = vec![0; 16];
+        data[0..4].copy_from_slice(b"NES\x1a");
+        data[4] = 0x02; // 2 * 16K
+        data[5] = 0x09; // 2^2 * 3 bytes with the exponent-multiplier notation
+        data[6] = 0x43;
+        data[7] = 0x19; // NES 2.0, Vs. System
+        data[8] = 0x31; // Submapper 3, mapper bits 8..11 are 1
+        data[9] = 0xf0;
+        data[10] = 0x70; // 8K of PRG NVRAM
+        data[11] = 0x07; // 8K of CHR RAM
+        data[12] = 0x03;
+        data[13] = 0x52;
+        data[14] = 0x01;
+        data[15] = 0x05;
+        let header = NESFile::load_header(&data, &mut vec![]).expect("Failed to parse the header");
+        assert!(header.is_nes2_format);
+        assert_eq!(0x114, header.mapper);
+        assert_eq!(3, header.submapper);
+        assert_eq!(0x8000, header.prg_rom_size);
+        assert_eq!(12, header.chr_rom_size);
+        assert_eq!(0, header.prg_ram_size);
+        assert_eq!(0x2000, header.prg_nvram_size);
+        assert_eq!(0x2000, header.chr_ram_size);
+        assert_eq!(0, header.chr_nvram_size);
+        assert_eq!(Mirroring::Vertical, header.mirroring);
+        assert!(header.has_persistent_memory);
+        assert_eq!(TVSystem::Dendy, header.tv_system);
+        assert_eq!(
+            ConsoleType::VsSystem {
+                ppu: VsPpuType::RP2C04_0001,
+                hardware: VsHardwareType::DualSystem,
+            },
+            header.console_type
+        );
+        assert_eq!(1, header.misc_rom_count);
+        assert_eq!(5, header.default_expansion_device);
+    }
+
+    #[test]
+    fn test_malformed_files() {
+        let mut data = vec![0; 16 + 0x200 + 0x4000 + 0x2000];
+        data[0..4].copy_from_slice(b"NES\x1a");
+        data[4] = 0x01;
+        data[5] = 0x02;
+        data[6] = 0x04; // Trainer
+        data[16] = 0x42;
+        let rom = NESFile::load(&data).expect("Failed to load a ROM without the last CHR bank");
+        assert_eq!(Some(0x42), rom.trainer.as_ref().map(|trainer| trainer[0]));
+        assert_eq!(0x4000, rom.chr_rom.len());
+        assert_eq!(vec![LoadWarning::MissingChrData(0x2000)], rom.warnings);
+        let mapper = mappers::get_mapper(rom).expect("Failed to create the mapper");
+        assert_eq!(0x42, mapper.read_prg(0x7000));
+
+        data.resize(16 + 0x200 + 0x4000 + 0x4000 + 0x10, 0);
+        let rom = NESFile::load(&data).expect("Failed to load a ROM with trailing data");
+        assert_eq!(vec![LoadWarning::TrailingData(0x10)], rom.warnings);
+
+        assert_eq!(
+            Err(LoadError::SizeMismatch {
+                expected: 0x8000,
+                actual: 0x2000
+            }),
+            NESFile::load(&data[..16 + 0x200 + 0x2000]).map(|_| ())
+        );
+        assert_eq!(
+            Err(LoadError::Truncated),
+            NESFile::load(&data[..0x100]).map(|_| ())
+        );
+        assert_eq!(
+            Err(LoadError::BadMagic),
+            NESFile::load(&data[1..]).map(|_| ())
+        );
+        data[6] = 0xf0;
+        let rom = NESFile::load(&data).expect("Failed to load a ROM with an unknown mapper");
+        assert_eq!(
+            Err(LoadError::UnsupportedMapper(0x0f)),
+            mappers::get_mapper(rom).map(|_| ())
+        );
+        data[4] = 0x00;
+        assert_eq!(
+            Err(LoadError::MissingPrgRom),
+            NESFile::load(&data).map(|_| ())
+        );
+
+        // 2^63 * 3 bytes of PRG ROM
+        let mut data = vec![0; 16];
+        data[0..4].copy_from_slice(b"NES\x1a");
+        data[4] = 0xfd;
+        data[7] = 0x08;
+        data[9] = 0x0f;
+        assert_eq!(
+            Err(LoadError::RomTooLarge),
+            NESFile::load(&data).map(|_| ())
+        );
+
+        // 2^63 bytes of both PRG and CHR ROM
+        let mut data = vec![0; 16];
+        data[0..4].copy_from_slice(b"NES\x1a");
+        data[4] = 0xfc;
+        data[5] = 0xfc;
+        data[7] = 0x08;
+        data[9] = 0xff;
+        assert_eq!(
+            Err(LoadError::RomTooLarge),
+            NESFile::load(&data).map(|_| ())
+        );
+    }
 }
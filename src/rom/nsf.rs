@@ -0,0 +1,284 @@
+// NSF and NSFe music rips, played through a small driver instead of a game
+
+use super::nes::LoadError;
+
+// Expansion chip flags of the header
+pub const EXPANSION_VRC6: u8 = 0x01;
+pub const EXPANSION_VRC7: u8 = 0x02;
+pub const EXPANSION_FDS: u8 = 0x04;
+pub const EXPANSION_MMC5: u8 = 0x08;
+pub const EXPANSION_N163: u8 = 0x10;
+pub const EXPANSION_SUNSOFT5B: u8 = 0x20;
+
+// Play routine periods in microseconds when the header leaves them out
+const NTSC_PLAY_PERIOD: u16 = 16639;
+const PAL_PLAY_PERIOD: u16 = 19997;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NSFTrack {
+    pub title: Option<String>,
+    // In milliseconds, only known for NSFe files
+    pub length: Option<u32>,
+    pub fade: Option<u32>,
+}
+
+pub struct NSFFile {
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub tracks: Vec<NSFTrack>,
+    // 0-based
+    pub starting_track: usize,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    // All zeroes when the tune doesn't use bankswitching
+    pub bank_init: [u8; 8],
+    pub is_pal: bool,
+    // Play routine period in microseconds for the selected region
+    pub play_period: u16,
+    pub expansion_chips: u8,
+    pub data: Vec<u8>,
+}
+
+// Strings are padded or terminated with zeroes
+fn read_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+fn read_u16(data: &[u8], idx: usize) -> Result<u16, LoadError> {
+    let bytes = data.get(idx..idx + 2).ok_or(LoadError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn play_period(is_pal: bool, ntsc_period: u16, pal_period: u16) -> u16 {
+    match (is_pal, ntsc_period, pal_period) {
+        (false, 0, _) => NTSC_PLAY_PERIOD,
+        (false, period, _) => period,
+        (true, _, 0) => PAL_PLAY_PERIOD,
+        (true, _, period) => period,
+    }
+}
+
+impl NSFFile {
+    pub fn is_nsf(data: &[u8]) -> bool {
+        data.starts_with(b"NESM\x1a") || data.starts_with(b"NSFE")
+    }
+
+    pub fn load(data: &[u8]) -> Result<Self, LoadError> {
+        if data.starts_with(b"NESM\x1a") {
+            Self::load_nsf(data)
+        } else if data.starts_with(b"NSFE") {
+            Self::load_nsfe(data)
+        } else {
+            Err(LoadError::BadMagic)
+        }
+    }
+
+    fn load_nsf(data: &[u8]) -> Result<Self, LoadError> {
+        let header = data.get(..0x80).ok_or(LoadError::Truncated)?;
+        let track_count = header[0x06].max(1) as usize;
+        // Dual region tunes are played at the NTSC rate
+        let is_pal = (header[0x7a] & 0x03) == 0x01;
+        // NSF2 can give the size of the program data, followed by metadata
+        let data_size = u32::from_le_bytes([header[0x7d], header[0x7e], header[0x7f], 0]) as usize;
+        let data = match data_size {
+            0 => &data[0x80..],
+            size => data.get(0x80..0x80 + size).ok_or(LoadError::Truncated)?,
+        };
+        let mut bank_init = [0; 8];
+        bank_init.copy_from_slice(&header[0x70..0x78]);
+        Ok(NSFFile {
+            title: read_string(&header[0x0e..0x2e]),
+            artist: read_string(&header[0x2e..0x4e]),
+            copyright: read_string(&header[0x4e..0x6e]),
+            tracks: vec![NSFTrack::default(); track_count],
+            starting_track: (header[0x07].max(1) as usize - 1).min(track_count - 1),
+            load_addr: read_u16(header, 0x08)?,
+            init_addr: read_u16(header, 0x0a)?,
+            play_addr: read_u16(header, 0x0c)?,
+            bank_init,
+            is_pal,
+            play_period: play_period(is_pal, read_u16(header, 0x6e)?, read_u16(header, 0x78)?),
+            expansion_chips: header[0x7b],
+            data: data.to_vec(),
+        })
+    }
+
+    fn load_nsfe(data: &[u8]) -> Result<Self, LoadError> {
+        let mut nsf = NSFFile {
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            tracks: vec![],
+            starting_track: 0,
+            load_addr: 0,
+            init_addr: 0,
+            play_addr: 0,
+            bank_init: [0; 8],
+            is_pal: false,
+            play_period: 0,
+            expansion_chips: 0,
+            data: vec![],
+        };
+        let mut has_info = false;
+        let mut rates = (0, 0);
+        let mut titles = vec![];
+        let mut lengths = vec![];
+        let mut fades = vec![];
+        let mut idx = 4;
+        loop {
+            let size = data.get(idx..idx + 4).ok_or(LoadError::Truncated)?;
+            let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize;
+            let id = data.get(idx + 4..idx + 8).ok_or(LoadError::Truncated)?;
+            let chunk = data
+                .get(idx + 8..idx + 8 + size)
+                .ok_or(LoadError::Truncated)?;
+            idx += 8 + size;
+            // Millisecond lists, negative values mean the default
+            let read_times = |chunk: &[u8]| -> Vec<Option<u32>> {
+                chunk
+                    .chunks_exact(4)
+                    .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .map(|time| if time < 0 { None } else { Some(time as u32) })
+                    .collect()
+            };
+            match id {
+                b"INFO" => {
+                    if chunk.len() < 8 {
+                        return Err(LoadError::Truncated);
+                    }
+                    has_info = true;
+                    nsf.load_addr = read_u16(chunk, 0)?;
+                    nsf.init_addr = read_u16(chunk, 2)?;
+                    nsf.play_addr = read_u16(chunk, 4)?;
+                    nsf.is_pal = (chunk[6] & 0x03) == 0x01;
+                    nsf.expansion_chips = chunk[7];
+                    let track_count = chunk.get(8).copied().unwrap_or(1).max(1) as usize;
+                    nsf.tracks = vec![NSFTrack::default(); track_count];
+                    nsf.starting_track =
+                        (chunk.get(9).copied().unwrap_or(0) as usize).min(track_count - 1);
+                }
+                b"DATA" => nsf.data = chunk.to_vec(),
+                b"BANK" => {
+                    let size = chunk.len().min(8);
+                    nsf.bank_init[..size].copy_from_slice(&chunk[..size]);
+                }
+                b"RATE" => rates = (read_u16(chunk, 0)?, read_u16(chunk, 2).unwrap_or(0)),
+                b"auth" => {
+                    let mut strings = chunk.split(|&b| b == 0).map(read_string);
+                    nsf.title = strings.next().unwrap_or_default();
+                    nsf.artist = strings.next().unwrap_or_default();
+                    nsf.copyright = strings.next().unwrap_or_default();
+                }
+                b"tlbl" => titles = chunk.split(|&b| b == 0).map(read_string).collect(),
+                b"time" => lengths = read_times(chunk),
+                b"fade" => fades = read_times(chunk),
+                b"NEND" => break,
+                // Chunks starting with an upper case letter are required to play the file
+                _ if id[0].is_ascii_uppercase() => {
+                    return Err(LoadError::UnsupportedChunk(read_string(id)));
+                }
+                _ => {}
+            }
+        }
+        if !has_info || nsf.data.is_empty() {
+            return Err(LoadError::Truncated);
+        }
+        nsf.play_period = play_period(nsf.is_pal, rates.0, rates.1);
+        for (i, track) in nsf.tracks.iter_mut().enumerate() {
+            track.title = titles.get(i).cloned().filter(|title| !title.is_empty());
+            track.length = lengths.get(i).copied().flatten();
+            track.fade = fades.get(i).copied().flatten();
+        }
+        Ok(nsf)
+    }
+
+    pub fn uses_bankswitching(&self) -> bool {
+        self.bank_init.iter().any(|&bank| bank != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_nsf() {
+        let mut data = vec![0; 0x80];
+        data[..5].copy_from_slice(b"NESM\x1a");
+        data[0x06] = 3;
+        data[0x07] = 2;
+        data[0x08..0x0e].copy_from_slice(&[0x00, 0x80, 0x03, 0x80, 0x06, 0x80]);
+        data[0x0e..0x13].copy_from_slice(b"Title");
+        data[0x72] = 0x01;
+        data[0x7a] = 0x01;
+        data[0x7b] = EXPANSION_VRC6 | EXPANSION_FDS;
+        data.extend_from_slice(&[0x60; 0x10]);
+        let nsf = NSFFile::load(&data).expect("Failed to load the NSF");
+        assert_eq!("Title", nsf.title);
+        assert_eq!(3, nsf.tracks.len());
+        assert_eq!(1, nsf.starting_track);
+        assert_eq!(
+            (0x8000, 0x8003, 0x8006),
+            (nsf.load_addr, nsf.init_addr, nsf.play_addr)
+        );
+        assert!(nsf.uses_bankswitching());
+        assert!(nsf.is_pal);
+        assert_eq!(PAL_PLAY_PERIOD, nsf.play_period);
+        assert_eq!(EXPANSION_VRC6 | EXPANSION_FDS, nsf.expansion_chips);
+        assert_eq!(0x10, nsf.data.len());
+        assert!(matches!(
+            NSFFile::load(&data[..0x40]),
+            Err(LoadError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn test_load_nsfe() {
+        fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+            let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+            chunk.extend_from_slice(id);
+            chunk.extend_from_slice(data);
+            chunk
+        }
+        let mut data = b"NSFE".to_vec();
+        data.extend(chunk(
+            b"INFO",
+            &[0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 0x00, 0x00, 0x02, 0x01],
+        ));
+        data.extend(chunk(b"DATA", &[0x60; 0x10]));
+        data.extend(chunk(b"RATE", &[0x0a, 0x1a]));
+        data.extend(chunk(b"auth", b"Game\0Composer\0\0Ripper\0"));
+        data.extend(chunk(b"tlbl", b"Intro\0Stage 1\0"));
+        data.extend(chunk(b"time", &[0x10, 0x27, 0, 0, 0xff, 0xff, 0xff, 0xff]));
+        data.extend(chunk(b"fade", &[0xe8, 0x03, 0, 0]));
+        data.extend(chunk(b"text", b"Ignored"));
+        data.extend(chunk(b"NEND", &[]));
+        let nsf = NSFFile::load(&data).expect("Failed to load the NSFe");
+        assert_eq!(
+            ("Game", "Composer"),
+            (nsf.title.as_str(), nsf.artist.as_str())
+        );
+        assert_eq!(1, nsf.starting_track);
+        assert_eq!(0x1a0a, nsf.play_period);
+        assert_eq!(
+            NSFTrack {
+                title: Some("Intro".to_string()),
+                length: Some(10000),
+                fade: Some(1000),
+            },
+            nsf.tracks[0]
+        );
+        assert_eq!(None, nsf.tracks[1].length);
+
+        let mut unsupported = b"NSFE".to_vec();
+        unsupported.extend(chunk(b"INFO", &[0; 8]));
+        unsupported.extend(chunk(b"VRC9", &[]));
+        assert!(matches!(
+            NSFFile::load(&unsupported),
+            Err(LoadError::UnsupportedChunk(_))
+        ));
+    }
+}
@@ -0,0 +1,407 @@
+// IPS, UPS and BPS soft patches, applied to the raw image before it's parsed
+
+use std::convert::TryFrom;
+
+use super::crc32::crc32;
+
+// Larger than any cartridge, bigger sizes only come from corrupt patches
+const MAX_TARGET_SIZE: usize = 0x100_0000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    UnknownFormat,
+    Malformed,
+    // The patch was made for a different ROM
+    SourceMismatch { expected: u32, actual: u32 },
+    TargetMismatch { expected: u32, actual: u32 },
+    PatchChecksum,
+}
+
+impl std::fmt::Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "unknown patch format"),
+            PatchError::Malformed => write!(f, "the patch is malformed"),
+            PatchError::SourceMismatch { expected, actual } => write!(
+                f,
+                "the patch is for a ROM with CRC32 {:08x}, this one has {:08x}",
+                expected, actual
+            ),
+            PatchError::TargetMismatch { expected, actual } => write!(
+                f,
+                "the patched ROM should have CRC32 {:08x}, got {:08x}",
+                expected, actual
+            ),
+            PatchError::PatchChecksum => write!(f, "the patch file is corrupted"),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+pub fn apply(data: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(data, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(data, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(data, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+// Builds an IPS patch that turns the original contents into the new ones
+pub fn encode_ips(original: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut patch = b"PATCH".to_vec();
+    let mut idx = 0;
+    while idx < data.len() {
+        if original.get(idx) == Some(&data[idx]) {
+            idx += 1;
+            continue;
+        }
+        // An offset that reads as "EOF" would end the patch early
+        let start = if idx == 0x454f46 { idx - 1 } else { idx };
+        let mut end = idx;
+        while end < data.len() && end - start < 0xffff && original.get(end) != Some(&data[end]) {
+            end += 1;
+        }
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&data[start..end]);
+        idx = end;
+    }
+    patch.extend_from_slice(b"EOF");
+    patch
+}
+
+pub fn apply_ips(original: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(b"PATCH") {
+        return Err(PatchError::UnknownFormat);
+    }
+    let mut data = original.to_vec();
+    let mut idx = 5;
+    loop {
+        let record = patch.get(idx..idx + 3).ok_or(PatchError::Malformed)?;
+        if record == b"EOF" {
+            break;
+        }
+        let offset = u32::from_be_bytes([0, record[0], record[1], record[2]]) as usize;
+        let size = patch.get(idx + 3..idx + 5).ok_or(PatchError::Malformed)?;
+        let size = u16::from_be_bytes([size[0], size[1]]) as usize;
+        idx += 5;
+        // Size 0 is a run of a single value
+        let chunk = if size == 0 {
+            let run = patch.get(idx..idx + 3).ok_or(PatchError::Malformed)?;
+            idx += 3;
+            vec![run[2]; u16::from_be_bytes([run[0], run[1]]) as usize]
+        } else {
+            let chunk = patch.get(idx..idx + size).ok_or(PatchError::Malformed)?;
+            idx += size;
+            chunk.to_vec()
+        };
+        if data.len() < offset + chunk.len() {
+            data.resize(offset + chunk.len(), 0x00);
+        }
+        data[offset..offset + chunk.len()].copy_from_slice(&chunk);
+    }
+    // Extension: the size to truncate the file to follows the end marker
+    if let Some(size) = patch.get(idx + 3..idx + 6) {
+        data.truncate(u32::from_be_bytes([0, size[0], size[1], size[2]]) as usize);
+    }
+    Ok(data)
+}
+
+// Variable-length integers shared by UPS and BPS, with an implicit +1 on every continuation
+fn read_number(patch: &[u8], idx: &mut usize) -> Result<usize, PatchError> {
+    let mut number = 0usize;
+    let mut shift = 1usize;
+    loop {
+        let byte = *patch.get(*idx).ok_or(PatchError::Malformed)?;
+        *idx += 1;
+        number = ((byte & 0x7f) as usize)
+            .checked_mul(shift)
+            .and_then(|value| number.checked_add(value))
+            .ok_or(PatchError::Malformed)?;
+        if (byte & 0x80) != 0 {
+            return Ok(number);
+        }
+        // Stops once the shift doesn't fit anymore
+        shift = shift.checked_mul(0x80).ok_or(PatchError::Malformed)?;
+        number = number.checked_add(shift).ok_or(PatchError::Malformed)?;
+    }
+}
+
+fn read_target_size(patch: &[u8], idx: &mut usize) -> Result<usize, PatchError> {
+    match read_number(patch, idx)? {
+        size if size > MAX_TARGET_SIZE => Err(PatchError::Malformed),
+        size => Ok(size),
+    }
+}
+
+fn checked_range(start: usize, length: usize) -> Result<std::ops::Range<usize>, PatchError> {
+    let end = start.checked_add(length).ok_or(PatchError::Malformed)?;
+    Ok(start..end)
+}
+
+// Checks the patch and source CRCs at the end, returns where they start and the target CRC
+fn check_footer(data: &[u8], patch: &[u8]) -> Result<(usize, u32), PatchError> {
+    if patch.len() < 4 + 12 {
+        return Err(PatchError::Malformed);
+    }
+    let footer = patch.len() - 12;
+    let read_crc = |idx: usize| {
+        u32::from_le_bytes([patch[idx], patch[idx + 1], patch[idx + 2], patch[idx + 3]])
+    };
+    if crc32(&patch[..footer + 8]) != read_crc(footer + 8) {
+        return Err(PatchError::PatchChecksum);
+    }
+    let expected = read_crc(footer);
+    let actual = crc32(data);
+    if expected != actual {
+        return Err(PatchError::SourceMismatch { expected, actual });
+    }
+    Ok((footer, read_crc(footer + 4)))
+}
+
+fn check_target(target: Vec<u8>, expected: u32) -> Result<Vec<u8>, PatchError> {
+    let actual = crc32(&target);
+    if expected != actual {
+        return Err(PatchError::TargetMismatch { expected, actual });
+    }
+    Ok(target)
+}
+
+pub fn apply_ups(data: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (footer, target_crc) = check_footer(data, patch)?;
+    let mut idx = 4;
+    let source_size = read_number(patch, &mut idx)?;
+    let target_size = read_target_size(patch, &mut idx)?;
+    if source_size != data.len() {
+        return Err(PatchError::Malformed);
+    }
+    let mut target = data.to_vec();
+    target.resize(target_size, 0x00);
+    let mut offset = 0usize;
+    // Each hunk skips ahead, then XORs bytes up to and including a zero
+    while idx < footer {
+        offset = offset
+            .checked_add(read_number(patch, &mut idx)?)
+            .ok_or(PatchError::Malformed)?;
+        loop {
+            let byte = *patch[..footer].get(idx).ok_or(PatchError::Malformed)?;
+            idx += 1;
+            if let Some(value) = target.get_mut(offset) {
+                *value ^= byte;
+            }
+            offset = offset.checked_add(1).ok_or(PatchError::Malformed)?;
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+    check_target(target, target_crc)
+}
+
+pub fn apply_bps(data: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (footer, target_crc) = check_footer(data, patch)?;
+    let mut idx = 4;
+    let _source_size = read_number(patch, &mut idx)?;
+    let target_size = read_target_size(patch, &mut idx)?;
+    let metadata_size = read_number(patch, &mut idx)?;
+    idx = idx
+        .checked_add(metadata_size)
+        .ok_or(PatchError::Malformed)?;
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset = 0isize;
+    let mut target_offset = 0isize;
+    // Copies are relative to the previous one, with the sign in the lowest bit
+    let read_relative = |idx: &mut usize| {
+        let number = read_number(patch, idx)?;
+        let offset = (number >> 1) as isize;
+        Ok(if (number & 0x01) != 0 {
+            -offset
+        } else {
+            offset
+        })
+    };
+    while idx < footer {
+        let action = read_number(patch, &mut idx)?;
+        let length = (action >> 2) + 1;
+        // Nothing can be written past the target size
+        if target.len() + length > target_size {
+            return Err(PatchError::Malformed);
+        }
+        match action & 0x03 {
+            // Source read
+            0 => {
+                let chunk = data
+                    .get(checked_range(target.len(), length)?)
+                    .ok_or(PatchError::Malformed)?;
+                target.extend_from_slice(chunk);
+            }
+            // Target read
+            1 => {
+                let chunk = patch[..footer]
+                    .get(checked_range(idx, length)?)
+                    .ok_or(PatchError::Malformed)?;
+                target.extend_from_slice(chunk);
+                idx += length;
+            }
+            // Source copy
+            2 => {
+                source_offset = source_offset
+                    .checked_add(read_relative(&mut idx)?)
+                    .ok_or(PatchError::Malformed)?;
+                let start = usize::try_from(source_offset).map_err(|_| PatchError::Malformed)?;
+                let chunk = data
+                    .get(checked_range(start, length)?)
+                    .ok_or(PatchError::Malformed)?;
+                target.extend_from_slice(chunk);
+                source_offset += length as isize;
+            }
+            // Target copy, byte by byte since the ranges can overlap
+            _ => {
+                target_offset = target_offset
+                    .checked_add(read_relative(&mut idx)?)
+                    .ok_or(PatchError::Malformed)?;
+                let start = usize::try_from(target_offset).map_err(|_| PatchError::Malformed)?;
+                for i in checked_range(start, length)? {
+                    let value = *target.get(i).ok_or(PatchError::Malformed)?;
+                    target.push(value);
+                }
+                target_offset += length as isize;
+            }
+        }
+    }
+    if target.len() != target_size {
+        return Err(PatchError::Malformed);
+    }
+    check_target(target, target_crc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_number(patch: &mut Vec<u8>, mut number: usize) {
+        loop {
+            let byte = (number & 0x7f) as u8;
+            number >>= 7;
+            if number == 0 {
+                patch.push(byte | 0x80);
+                return;
+            }
+            patch.push(byte);
+            number -= 1;
+        }
+    }
+
+    fn write_footer(patch: &mut Vec<u8>, source: &[u8], target: &[u8]) {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(patch).to_le_bytes());
+    }
+
+    #[test]
+    fn test_ips() {
+        let original = vec![0; 0x20000];
+        let mut data = original.clone();
+        data[0x10] = 0x01;
+        data[0x11] = 0x02;
+        // Longer runs are split into several records
+        data[0x10000..].fill(0x03);
+        let patch = encode_ips(&original, &data);
+        assert_eq!(b"PATCH\x00\x00\x10\x00\x02\x01\x02", &patch[..12]);
+        assert_eq!(Ok(data), apply(&original, &patch));
+        assert!(apply_ips(&original, &patch[..patch.len() - 1]).is_err());
+
+        // An RLE record followed by the truncate extension
+        let patch = b"PATCH\x00\x00\x04\x00\x00\x00\x03\x42EOF\x00\x00\x06";
+        assert_eq!(
+            Ok(vec![0, 0, 0, 0, 0x42, 0x42]),
+            apply(&original[..0x10], patch)
+        );
+    }
+
+    #[test]
+    fn test_ups() {
+        let source = b"Hello, world".to_vec();
+        let target = b"Hello, NES world!".to_vec();
+        let mut patch = b"UPS1".to_vec();
+        write_number(&mut patch, source.len());
+        write_number(&mut patch, target.len());
+        // Skip "Hello, " then XOR everything after it
+        write_number(&mut patch, 7);
+        for (i, value) in target.iter().enumerate().skip(7) {
+            patch.push(source.get(i).unwrap_or(&0) ^ value);
+        }
+        patch.push(0);
+        write_footer(&mut patch, &source, &target);
+        assert_eq!(Ok(target), apply(&source, &patch));
+
+        assert!(matches!(
+            apply(b"Goodbye, world", &patch),
+            Err(PatchError::SourceMismatch { .. })
+        ));
+        let last = patch.len() - 1;
+        patch[last] ^= 0xff;
+        assert_eq!(Err(PatchError::PatchChecksum), apply(&source, &patch));
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = b"abcdefgh".to_vec();
+        let target = b"abcdXYXYXYefgh".to_vec();
+        let mut patch = b"BPS1".to_vec();
+        write_number(&mut patch, source.len());
+        write_number(&mut patch, target.len());
+        write_number(&mut patch, 0);
+        // Source read of "abcd"
+        write_number(&mut patch, 3 << 2);
+        // Target read of "XY"
+        write_number(&mut patch, (1 << 2) | 1);
+        patch.extend_from_slice(b"XY");
+        // Target copy of "XYXY" from offset 4, overlapping its own output
+        write_number(&mut patch, (3 << 2) | 3);
+        write_number(&mut patch, 4 << 1);
+        // Source copy of "efgh" from offset 4
+        write_number(&mut patch, (3 << 2) | 2);
+        write_number(&mut patch, 4 << 1);
+        write_footer(&mut patch, &source, &target);
+        assert_eq!(Ok(target.clone()), apply(&source, &patch));
+
+        let mut footer = patch.clone();
+        let idx = footer.len() - 8;
+        footer[idx] ^= 0xff;
+        let checksum = crc32(&footer[..footer.len() - 4]).to_le_bytes();
+        let idx = footer.len() - 4;
+        footer[idx..].copy_from_slice(&checksum);
+        assert!(matches!(
+            apply(&source, &footer),
+            Err(PatchError::TargetMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_malformed_numbers() {
+        let mut patch = vec![];
+        write_number(&mut patch, usize::MAX >> 1);
+        assert_eq!(Ok(usize::MAX >> 1), read_number(&patch, &mut 0));
+        // Continuation bytes that would shift past the size of usize
+        assert_eq!(Err(PatchError::Malformed), read_number(&[0x7f; 12], &mut 0));
+
+        // Target sizes larger than any ROM are rejected before anything is allocated
+        let source = b"Hello, world".to_vec();
+        let mut patch = b"UPS1".to_vec();
+        write_number(&mut patch, source.len());
+        write_number(&mut patch, usize::MAX >> 1);
+        write_footer(&mut patch, &source, &source);
+        assert_eq!(Err(PatchError::Malformed), apply(&source, &patch));
+        patch[..4].copy_from_slice(b"BPS1");
+        let footer = patch.len() - 12;
+        patch.truncate(footer);
+        write_footer(&mut patch, &source, &source);
+        assert_eq!(Err(PatchError::Malformed), apply(&source, &patch));
+    }
+}
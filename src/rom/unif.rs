@@ -0,0 +1,234 @@
+// UNIF format ROM, a list of chunks with the board given by name
+
+use super::nes::{ConsoleType, LoadError, Mirroring, NESFile, NESHeader, TVSystem};
+
+struct Board {
+    mapper: u16,
+    submapper: u8,
+    prg_ram_size: usize,
+}
+
+impl Board {
+    fn new(mapper: u16, submapper: u8, prg_ram_size: usize) -> Self {
+        Board {
+            mapper,
+            submapper,
+            prg_ram_size,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        // The manufacturer prefix doesn't change the hardware
+        let name = ["NES-", "HVC-", "UNL-", "BTL-", "BMC-"]
+            .iter()
+            .find_map(|prefix| name.strip_prefix(prefix))
+            .unwrap_or(name);
+        let board = match name {
+            "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => Board::new(0, 0, 0),
+            "SBROM" | "SCROM" | "SEROM" | "SGROM" | "SLROM" | "SL1ROM" => Board::new(1, 0, 0),
+            "SAROM" | "SKROM" | "SNROM" | "SUROM" => Board::new(1, 0, 0x2000),
+            "SOROM" => Board::new(1, 0, 0x4000),
+            "SXROM" => Board::new(1, 0, 0x8000),
+            "UNROM" | "UOROM" => Board::new(2, 2, 0),
+            "CNROM" => Board::new(3, 2, 0),
+            "TBROM" | "TEROM" | "TFROM" | "TGROM" | "TLROM" | "TR1ROM" => Board::new(4, 0, 0),
+            "TKROM" | "TNROM" | "TSROM" => Board::new(4, 0, 0x2000),
+            "HKROM" => Board::new(4, 1, 0x400),
+            "ELROM" => Board::new(5, 0, 0),
+            "EKROM" => Board::new(5, 0, 0x2000),
+            "ETROM" => Board::new(5, 0, 0x4000),
+            "EWROM" => Board::new(5, 0, 0x8000),
+            "ANROM" | "AN1ROM" => Board::new(7, 1, 0),
+            "AMROM" => Board::new(7, 2, 0),
+            "AOROM" => Board::new(7, 0, 0),
+            "PNROM" | "PEEOROM" => Board::new(9, 0, 0),
+            "FJROM" | "FKROM" => Board::new(10, 0, 0x2000),
+            "BNROM" => Board::new(34, 2, 0),
+            "GNROM" | "MHROM" => Board::new(66, 2, 0),
+            _ => return None,
+        };
+        Some(board)
+    }
+}
+
+pub fn is_unif(data: &[u8]) -> bool {
+    data.starts_with(b"UNIF")
+}
+
+// NES 2.0 default expansion device for the CTRL chunk bits, the most specific device wins
+fn expansion_device(controllers: u8) -> u8 {
+    if (controllers & 0x02) != 0 {
+        0x08 // Zapper
+    } else if (controllers & 0x04) != 0 {
+        0x1f // R.O.B.
+    } else if (controllers & 0x08) != 0 {
+        0x0f // Arkanoid controller
+    } else if (controllers & 0x10) != 0 {
+        0x0b // Power Pad
+    } else if (controllers & 0x20) != 0 {
+        0x02 // Four Score
+    } else if (controllers & 0x01) != 0 {
+        0x01
+    } else {
+        0
+    }
+}
+
+// Fills in the same structure as an NES 2.0 header so the mappers don't need to know about UNIF
+pub fn load(data: &[u8]) -> Result<NESFile, LoadError> {
+    if !is_unif(data) {
+        return Err(LoadError::BadMagic);
+    }
+    let mut chunks = data.get(32..).ok_or(LoadError::Truncated)?;
+    let mut board_name = None;
+    let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut mirroring = (Mirroring::Horizontal, false);
+    let mut has_battery = false;
+    let mut tv_system = TVSystem::NTSC;
+    let mut controllers = 0;
+    while !chunks.is_empty() {
+        let header = chunks.get(..8).ok_or(LoadError::Truncated)?;
+        let id = &header[..4];
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let chunk = chunks.get(8..8 + size).ok_or(LoadError::Truncated)?;
+        chunks = &chunks[8 + size..];
+        let index = (id[3] as char).to_digit(16).unwrap_or(0) as usize;
+        match id {
+            b"MAPR" => {
+                // Null terminated, but some files pad the name with garbage instead
+                let end = chunk.iter().position(|&b| b == 0).unwrap_or(chunk.len());
+                board_name = Some(String::from_utf8_lossy(&chunk[..end]).trim().to_string());
+            }
+            [b'P', b'R', b'G', _] if id[3].is_ascii_hexdigit() => prg_chunks[index] = Some(chunk),
+            [b'C', b'H', b'R', _] if id[3].is_ascii_hexdigit() => chr_chunks[index] = Some(chunk),
+            b"MIRR" => {
+                mirroring = match chunk.first() {
+                    Some(0) => (Mirroring::Horizontal, false),
+                    Some(1) => (Mirroring::Vertical, false),
+                    Some(2) => (Mirroring::SingleScreenA, false),
+                    Some(3) => (Mirroring::SingleScreenB, false),
+                    Some(4) => (Mirroring::Horizontal, true),
+                    // Controlled by the mapper
+                    _ => (Mirroring::Horizontal, false),
+                }
+            }
+            b"BATR" => has_battery = chunk.first() != Some(&0),
+            b"TVCI" => {
+                tv_system = match chunk.first() {
+                    Some(1) => TVSystem::PAL,
+                    Some(2) => TVSystem::DualCompatible,
+                    _ => TVSystem::NTSC,
+                }
+            }
+            b"CTRL" => controllers = chunk.first().copied().unwrap_or(0),
+            // Checksums, titles and dumper info
+            _ => {}
+        }
+    }
+
+    let board_name = board_name.ok_or(LoadError::MissingBoard)?;
+    let board = Board::from_name(&board_name).ok_or(LoadError::UnsupportedBoard(board_name))?;
+    let prg_rom: Vec<u8> = prg_chunks
+        .iter()
+        .flatten()
+        .flat_map(|c| c.to_vec())
+        .collect();
+    let chr_rom: Vec<u8> = chr_chunks
+        .iter()
+        .flatten()
+        .flat_map(|c| c.to_vec())
+        .collect();
+    if prg_rom.is_empty() {
+        return Err(LoadError::MissingPrgRom);
+    }
+    let (prg_ram_size, prg_nvram_size) = if has_battery {
+        (0, board.prg_ram_size)
+    } else {
+        (board.prg_ram_size, 0)
+    };
+    let header = NESHeader {
+        is_nes2_format: true,
+        tv_system,
+        console_type: ConsoleType::NES,
+        prg_rom_size: prg_rom.len(),
+        chr_rom_size: chr_rom.len(),
+        prg_ram_size,
+        prg_nvram_size,
+        chr_ram_size: if chr_rom.is_empty() { 0x2000 } else { 0 },
+        chr_nvram_size: 0,
+        mapper: board.mapper,
+        submapper: board.submapper,
+        mirroring: mirroring.0,
+        ignore_mirroring: mirroring.1,
+        has_persistent_memory: has_battery,
+        has_trainer: false,
+        has_prg_ram: prg_ram_size + prg_nvram_size > 0,
+        has_bus_conflicts: false,
+        misc_rom_count: 0,
+        default_expansion_device: expansion_device(controllers),
+    };
+    Ok(NESFile {
+        header,
+        trainer: None,
+        prg_rom,
+        chr_rom,
+        misc_rom: vec![],
+        warnings: vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    #[test]
+    fn test_load() {
+        let mut data = b"UNIF".to_vec();
+        data.extend_from_slice(&7u32.to_le_bytes());
+        data.resize(32, 0);
+        data.extend(chunk(b"MAPR", b"NES-SKROM\0"));
+        // Chunks are ordered by their number, not by their position in the file
+        data.extend(chunk(b"PRG1", &[0x02; 0x4000]));
+        data.extend(chunk(b"PRG0", &[0x01; 0x4000]));
+        data.extend(chunk(b"CHR0", &[0x03; 0x2000]));
+        data.extend(chunk(b"MIRR", &[1]));
+        data.extend(chunk(b"BATR", &[1]));
+        data.extend(chunk(b"CTRL", &[0x03]));
+        data.extend(chunk(b"READ", b"Dumped by someone\0"));
+        assert!(is_unif(&data));
+        let rom = load(&data).expect("Failed to load the UNIF file");
+        assert_eq!(1, rom.header.mapper);
+        assert_eq!(0x8000, rom.prg_rom.len());
+        assert_eq!([0x01, 0x02], [rom.prg_rom[0], rom.prg_rom[0x4000]]);
+        assert_eq!(0x2000, rom.chr_rom.len());
+        assert_eq!(Mirroring::Vertical, rom.header.mirroring);
+        assert!(rom.header.has_persistent_memory);
+        assert_eq!(0x2000, rom.header.total_prg_ram_size());
+        assert_eq!(0x08, rom.header.default_expansion_device);
+
+        assert_eq!(
+            Err(LoadError::Truncated),
+            load(&data[..data.len() - 4]).map(|_| ())
+        );
+        let mut unnamed = data[..32].to_vec();
+        unnamed.extend(chunk(b"PRG0", &[0x01; 0x4000]));
+        assert_eq!(Err(LoadError::MissingBoard), load(&unnamed).map(|_| ()));
+        let mut empty = data[..32].to_vec();
+        empty.extend(chunk(b"MAPR", b"NES-SKROM\0"));
+        assert_eq!(Err(LoadError::MissingPrgRom), load(&empty).map(|_| ()));
+        let mut unknown = data[..32].to_vec();
+        unknown.extend(chunk(b"MAPR", b"UNL-SOMETHING\0"));
+        assert_eq!(
+            Err(LoadError::UnsupportedBoard("UNL-SOMETHING".to_string())),
+            load(&unknown).map(|_| ())
+        );
+    }
+}
@@ -1,2 +1,4 @@
 mod clock_divider;
 pub use clock_divider::*;
+mod wav;
+pub use wav::*;
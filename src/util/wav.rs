@@ -0,0 +1,38 @@
+use std::io::{self, Write};
+
+// 16-bit mono PCM
+pub fn write_wav<W: Write>(mut writer: W, samples: &[f32], sample_rate: u32) -> io::Result<()> {
+    let data_size = samples.len() as u32 * 2;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?; // PCM
+    writer.write_all(&1u16.to_le_bytes())?; // Mono
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * 2).to_le_bytes())?;
+    writer.write_all(&2u16.to_le_bytes())?; // Bytes per frame
+    writer.write_all(&16u16.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+    for &sample in samples {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        writer.write_all(&sample.to_le_bytes())?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_wav() {
+        let mut data = vec![];
+        write_wav(&mut data, &[0.0, 1.0, -2.0], 44100).unwrap();
+        assert_eq!(44 + 6, data.len());
+        assert_eq!(b"RIFF", &data[0..4]);
+        assert_eq!(&(44100u32).to_le_bytes(), &data[24..28]);
+        assert_eq!(&[0x00, 0x00, 0xff, 0x7f, 0x01, 0x80], &data[44..]);
+    }
+}